@@ -0,0 +1,236 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::error::Result;
+use crate::octopus::OctopusApi;
+use crate::usage::Fuel;
+
+/// Account details as returned by `/accounts/{number}/`.
+#[derive(Debug, Deserialize)]
+pub struct Account {
+    #[serde(default)]
+    pub properties: Vec<Property>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Property {
+    #[serde(default)]
+    pub electricity_meter_points: Vec<ElectricityMeterPoint>,
+    #[serde(default)]
+    pub gas_meter_points: Vec<GasMeterPoint>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ElectricityMeterPoint {
+    pub mpan: String,
+    #[serde(default)]
+    pub is_export: bool,
+    #[serde(default)]
+    pub meters: Vec<InstalledMeter>,
+    #[serde(default)]
+    pub agreements: Vec<Agreement>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GasMeterPoint {
+    pub mprn: String,
+    #[serde(default)]
+    pub meters: Vec<InstalledMeter>,
+    #[serde(default)]
+    pub agreements: Vec<Agreement>,
+}
+
+/// A physical meter on a meter point; replaced meters stay listed before the new one.
+#[derive(Debug, Deserialize)]
+pub struct InstalledMeter {
+    pub serial_number: String,
+}
+
+/// A meter point of either fuel, as found on the account.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredMeterPoint {
+    pub fuel: Fuel,
+    pub meter_point: String,
+    /// Whether the meter point records generation exported to the grid.
+    pub export: bool,
+    /// Index of the property on the account the meter point supplies.
+    pub property: usize,
+    pub serial_numbers: Vec<String>,
+    pub tariff_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Agreement {
+    pub tariff_code: String,
+    pub valid_from: Option<String>,
+    pub valid_to: Option<String>,
+}
+
+impl Agreement {
+    /// Whether the agreement covers `at`; open-ended bounds always match.
+    pub fn is_active(&self, at: DateTime<Utc>) -> bool {
+        let started = match &self.valid_from {
+            Some(from) => DateTime::parse_from_rfc3339(from).is_ok_and(|from| from <= at),
+            None => true,
+        };
+        let ended = match &self.valid_to {
+            Some(to) => DateTime::parse_from_rfc3339(to).is_ok_and(|to| to <= at),
+            None => false,
+        };
+        started && !ended
+    }
+}
+
+impl Account {
+    /// Every meter point of `fuel`, with the tariff of the agreement active at `at`.
+    pub fn meter_points(&self, fuel: Fuel, at: DateTime<Utc>) -> Vec<DiscoveredMeterPoint> {
+        let discovered = |property: usize, meter_point: &str, export: bool, meters: &[InstalledMeter], agreements: &[Agreement]| {
+            DiscoveredMeterPoint {
+                fuel,
+                meter_point: meter_point.to_string(),
+                export,
+                property,
+                serial_numbers: meters
+                    .iter()
+                    .map(|meter| meter.serial_number.clone())
+                    .filter(|serial| !serial.is_empty())
+                    .collect(),
+                tariff_code: agreements
+                    .iter()
+                    .find(|agreement| agreement.is_active(at))
+                    .map(|agreement| agreement.tariff_code.clone()),
+            }
+        };
+
+        self.properties
+            .iter()
+            .enumerate()
+            .flat_map(|(i, property)| match fuel {
+                Fuel::Electricity => property
+                    .electricity_meter_points
+                    .iter()
+                    .map(|point| discovered(i, &point.mpan, point.is_export, &point.meters, &point.agreements))
+                    .collect::<Vec<_>>(),
+                Fuel::Gas => property
+                    .gas_meter_points
+                    .iter()
+                    .map(|point| discovered(i, &point.mprn, false, &point.meters, &point.agreements))
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+pub async fn fetch_account(api: &OctopusApi, number: &str) -> Result<Account> {
+    api.get(&format!("accounts/{number}/"), &[]).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octopus::mock_server;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn account_json() -> serde_json::Value {
+        serde_json::json!({
+            "number": "A-1234ABCD",
+            "properties": [{
+                "electricity_meter_points": [{
+                    "mpan": "1200000000001",
+                    "is_export": false,
+                    "meters": [{"serial_number": "19L0000001"}, {"serial_number": "21E0000001"}],
+                    "agreements": [
+                        {"tariff_code": "E-1R-VAR-22-11-01-C", "valid_from": "2023-01-01T00:00:00Z", "valid_to": "2024-06-01T00:00:00+01:00"},
+                        {"tariff_code": "E-1R-AGILE-24-10-01-C", "valid_from": "2024-06-01T00:00:00+01:00", "valid_to": null}
+                    ]
+                }, {
+                    "mpan": "1400000000009",
+                    "is_export": true,
+                    "meters": [{"serial_number": "21E0000001"}],
+                    "agreements": [
+                        {"tariff_code": "E-1R-OUTGOING-FIX-12M-19-05-13-C", "valid_from": "2024-06-01T00:00:00+01:00", "valid_to": null}
+                    ]
+                }],
+                "gas_meter_points": [{
+                    "mprn": "3000000001",
+                    "meters": [{"serial_number": "G4P0000001"}],
+                    "agreements": [
+                        {"tariff_code": "G-1R-VAR-22-11-01-C", "valid_from": "2023-01-01T00:00:00Z", "valid_to": null}
+                    ]
+                }]
+            }]
+        })
+    }
+
+    fn tariff_code(account: &Account, fuel: Fuel, meter_point: &str, at: DateTime<Utc>) -> Option<String> {
+        account
+            .meter_points(fuel, at)
+            .into_iter()
+            .find(|point| point.meter_point == meter_point)?
+            .tariff_code
+    }
+
+    #[test]
+    fn test_tariff_code_follows_agreements() {
+        let account: Account = serde_json::from_value(account_json()).unwrap();
+        let account = &account;
+
+        assert_eq!(
+            tariff_code(account, Fuel::Electricity, "1200000000001", at("2024-01-01T00:00:00Z")).as_deref(),
+            Some("E-1R-VAR-22-11-01-C"),
+        );
+        assert_eq!(
+            tariff_code(account, Fuel::Electricity, "1200000000001", at("2024-05-31T23:00:00Z")).as_deref(),
+            Some("E-1R-AGILE-24-10-01-C"),
+        );
+        assert_eq!(
+            tariff_code(account, Fuel::Gas, "3000000001", at("2025-01-01T00:00:00Z")).as_deref(),
+            Some("G-1R-VAR-22-11-01-C"),
+        );
+        assert_eq!(tariff_code(account, Fuel::Gas, "9999999999", at("2025-01-01T00:00:00Z")), None);
+        assert_eq!(tariff_code(account, Fuel::Electricity, "1200000000001", at("2020-01-01T00:00:00Z")), None);
+    }
+
+    #[test]
+    fn test_meter_points_flag_exports() {
+        let account: Account = serde_json::from_value(account_json()).unwrap();
+
+        let electricity = account.meter_points(Fuel::Electricity, at("2025-01-01T00:00:00Z"));
+        assert_eq!(electricity, vec![
+            DiscoveredMeterPoint {
+                fuel: Fuel::Electricity,
+                meter_point: "1200000000001".to_string(),
+                export: false,
+                property: 0,
+                serial_numbers: vec!["19L0000001".to_string(), "21E0000001".to_string()],
+                tariff_code: Some("E-1R-AGILE-24-10-01-C".to_string()),
+            },
+            DiscoveredMeterPoint {
+                fuel: Fuel::Electricity,
+                meter_point: "1400000000009".to_string(),
+                export: true,
+                property: 0,
+                serial_numbers: vec!["21E0000001".to_string()],
+                tariff_code: Some("E-1R-OUTGOING-FIX-12M-19-05-13-C".to_string()),
+            },
+        ]);
+
+        let gas = account.meter_points(Fuel::Gas, at("2025-01-01T00:00:00Z"));
+        assert_eq!(gas.len(), 1);
+        assert_eq!(gas[0].serial_numbers, ["G4P0000001"]);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_account_from_mock_server() {
+        let base_url = mock_server(vec![("/v1/accounts/A-1234ABCD/", account_json())]);
+        let api = OctopusApi::new("key").with_base_url(base_url);
+
+        let account = fetch_account(&api, "A-1234ABCD").await.unwrap();
+        assert_eq!(account.properties[0].gas_meter_points[0].mprn, "3000000001");
+
+        assert!(fetch_account(&api, "A-0000").await.is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use std::{collections::BTreeMap, fs, io, path::{Path, PathBuf}, sync::Arc};
+
+use chrono::{DateTime, Duration, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::error;
+use crate::meter::Meter;
+use crate::store::Store;
+use crate::usage::Reading;
+
+/// Hours before the last cached slot fetched again each poll, for readings
+/// that arrive late or get corrected.
+pub const DEFAULT_RECHECK_HOURS: u64 = 48;
+
+/// Bumped when the file layout changes, so old files are refetched rather than misread.
+const VERSION: u32 = 1;
+
+/// Where the reading cache lives and how far back each poll checks again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheSettings {
+    pub location: CacheLocation,
+    pub recheck_hours: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheLocation {
+    /// A JSON file per meter in this directory.
+    Dir(PathBuf),
+    /// The SQLite database `backfill` fills, which keeps carbon intensity
+    /// and tariff rates as well.
+    Database(PathBuf),
+}
+
+/// Half-hourly readings of every meter, kept on disk between polls and
+/// restarts so each poll only fetches what's new.
+#[derive(Clone)]
+pub struct ReadingCache {
+    backend: Backend,
+    recheck: Duration,
+}
+
+#[derive(Clone)]
+enum Backend {
+    Dir(PathBuf),
+    Database(Arc<Store>),
+}
+
+/// The span a series of readings, intensities or rates has been fetched
+/// over. Nothing is missing after `from` other than slots the API had
+/// nothing for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coverage {
+    pub from: DateTime<Utc>,
+    /// End of the last slot that came back.
+    pub complete_to: Option<DateTime<Utc>>,
+}
+
+impl Coverage {
+    /// Where to fetch from for everything from `from` onwards: `recheck`
+    /// before the last complete slot when the rest is already held.
+    pub fn fetch_from(&self, from: DateTime<Utc>, recheck: Duration) -> DateTime<Utc> {
+        match self.complete_to {
+            Some(complete_to) if self.from <= from => {
+                complete_to.checked_sub_signed(recheck).map_or(from, |recheck| recheck.max(from))
+            }
+            _ => from,
+        }
+    }
+}
+
+/// One meter's file. Times are Unix timestamps.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Stored {
+    version: u32,
+    from: i64,
+    complete_to: Option<i64>,
+    readings: Vec<(i64, f64)>,
+}
+
+/// The readings held for a meter, as fetched and before any gas conversion.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MeterReadings {
+    coverage: Option<Coverage>,
+    readings: BTreeMap<DateTime<Utc>, f64>,
+    /// The span the last `merge` replaced, which is all the database needs written.
+    merged: Option<(DateTime<Utc>, DateTime<Utc>)>,
+}
+
+impl ReadingCache {
+    /// Opens the cache, creating the database if that's where it lives.
+    pub fn open(settings: &CacheSettings) -> error::Result<Self> {
+        let hours = i64::try_from(settings.recheck_hours).unwrap_or(i64::MAX);
+        let backend = match &settings.location {
+            CacheLocation::Dir(dir) => Backend::Dir(dir.clone()),
+            CacheLocation::Database(path) => Backend::Database(Arc::new(Store::open(path)?)),
+        };
+        Ok(ReadingCache { backend, recheck: Duration::try_hours(hours).unwrap_or(Duration::MAX) })
+    }
+
+    /// How far before the last complete slot each poll fetches again.
+    pub fn recheck(&self) -> Duration {
+        self.recheck
+    }
+
+    /// The database, when the cache lives in one.
+    pub fn store(&self) -> Option<&Store> {
+        match &self.backend {
+            Backend::Dir(_) => None,
+            Backend::Database(store) => Some(store),
+        }
+    }
+
+    /// Where a meter's readings are kept, e.g. `electricity-1200000000001-S0000001.json`.
+    fn path(dir: &Path, meter: &Meter) -> PathBuf {
+        let name = format!("{}-{}-{}", meter.fuel.as_str(), meter.meter_point, meter.serial_number);
+        let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+        dir.join(format!("{name}.json"))
+    }
+
+    /// The readings held for `meter` from `from` onwards, or none when
+    /// there's nothing usable.
+    pub fn load(&self, meter: &Meter, from: DateTime<Utc>) -> MeterReadings {
+        let dir = match &self.backend {
+            Backend::Dir(dir) => dir,
+            Backend::Database(store) => {
+                let loaded = store.coverage(&Store::meter_series(meter)).and_then(|coverage| {
+                    let readings = store.readings(meter, from, DateTime::<Utc>::MAX_UTC)?;
+                    Ok(MeterReadings {
+                        coverage,
+                        readings: readings.into_iter().map(|reading| (reading.start, reading.kwh)).collect(),
+                        merged: None,
+                    })
+                });
+                return loaded.unwrap_or_else(|e| {
+                    warn!("Can't read cached readings of meter point {}, fetching them again: {e}", meter.meter_point);
+                    MeterReadings::default()
+                });
+            }
+        };
+
+        let path = Self::path(dir, meter);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return MeterReadings::default(),
+            Err(e) => {
+                warn!("Can't read cached readings {}, fetching them again: {e}", path.display());
+                return MeterReadings::default();
+            }
+        };
+        match serde_json::from_str::<Stored>(&text) {
+            Ok(stored) if stored.version == VERSION => MeterReadings::from_stored(stored),
+            Ok(_) => MeterReadings::default(),
+            Err(e) => {
+                warn!("Can't parse cached readings {}, fetching them again: {e}", path.display());
+                MeterReadings::default()
+            }
+        }
+    }
+
+    /// Writes `readings` for `meter`: in files, replacing the meter's file
+    /// in one step so a crash can't leave it half written; in the database,
+    /// only the span last merged. A failure only costs the next poll a
+    /// longer fetch, so it's logged rather than returned.
+    pub fn save(&self, meter: &Meter, readings: &MeterReadings) {
+        let dir = match &self.backend {
+            Backend::Dir(dir) => dir,
+            Backend::Database(store) => {
+                let Some((from, to)) = readings.merged else {
+                    return;
+                };
+                if let Err(e) = store.save_readings(meter, from, to, &readings.readings(from, to)) {
+                    warn!("Can't write cached readings of meter point {}: {e}", meter.meter_point);
+                }
+                return;
+            }
+        };
+
+        let path = Self::path(dir, meter);
+        let write = || -> io::Result<()> {
+            fs::create_dir_all(dir)?;
+            let text = serde_json::to_string(&readings.to_stored())?;
+            let tmp = path.with_extension("json.tmp");
+            fs::write(&tmp, text)?;
+            fs::rename(&tmp, &path)
+        };
+        if let Err(e) = write() {
+            warn!("Can't write cached readings {}: {e}", path.display());
+        }
+    }
+
+    /// Forgets what every meter's readings cover, so the next poll fetches
+    /// them all again. The database keeps its rows, which that poll replaces.
+    pub fn clear(&self) -> error::Result<()> {
+        match &self.backend {
+            Backend::Dir(dir) => clear_dir(dir).map_err(|e| error::Error::Config(format!("can't clear {}: {e}", dir.display()))),
+            Backend::Database(store) => store.forget_coverage(),
+        }
+    }
+
+    /// Where to fetch from for readings from `from` onwards, see `Coverage::fetch_from`.
+    pub fn fetch_from(&self, cached: &MeterReadings, from: DateTime<Utc>) -> DateTime<Utc> {
+        cached.coverage.map_or(from, |coverage| coverage.fetch_from(from, self.recheck))
+    }
+}
+
+fn clear_dir(dir: &Path) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        if name.ends_with(".json") || name.ends_with(".json.tmp") {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+impl MeterReadings {
+    fn from_stored(stored: Stored) -> Self {
+        let time = |secs: i64| DateTime::from_timestamp(secs, 0);
+        MeterReadings {
+            coverage: time(stored.from).map(|from| Coverage { from, complete_to: stored.complete_to.and_then(time) }),
+            readings: stored.readings.into_iter().filter_map(|(start, kwh)| Some((time(start)?, kwh))).collect(),
+            merged: None,
+        }
+    }
+
+    fn to_stored(&self) -> Stored {
+        Stored {
+            version: VERSION,
+            from: self.coverage.map_or(0, |coverage| coverage.from.timestamp()),
+            complete_to: self.coverage.and_then(|coverage| coverage.complete_to).map(|to| to.timestamp()),
+            readings: self.readings.iter().map(|(start, kwh)| (start.timestamp(), *kwh)).collect(),
+        }
+    }
+
+    /// Takes in `fetched`, every reading from `fetched_from` up to `to`,
+    /// replacing what was held for that span, and drops readings from
+    /// before `from` that no window needs any more.
+    pub fn merge(&mut self, from: DateTime<Utc>, fetched_from: DateTime<Utc>, to: DateTime<Utc>, fetched: &[Reading]) {
+        self.readings.retain(|start, _| *start >= from && !(fetched_from..to).contains(start));
+        self.readings.extend(fetched.iter().map(|reading| (reading.start, reading.kwh)));
+        let complete_to = self.readings.keys().next_back().map(|last| *last + Duration::minutes(30));
+        self.coverage = Some(Coverage { from, complete_to: complete_to.or(self.coverage.and_then(|c| c.complete_to)) });
+        self.merged = Some((fetched_from, to));
+    }
+
+    /// The readings from `from` up to `to`, in order.
+    pub fn readings(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<Reading> {
+        self.readings.range(from..to).map(|(start, kwh)| Reading { start: *start, kwh: *kwh }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meter::test_meter;
+    use crate::usage::Fuel;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn reading(start: &str, kwh: f64) -> Reading {
+        Reading { start: at(start), kwh }
+    }
+
+    #[test]
+    fn test_polls_fetch_only_the_recheck_margin() {
+        let dir = std::env::temp_dir().join(format!("octopus-cache-{}", std::process::id()));
+        let cache = ReadingCache::open(&CacheSettings { location: CacheLocation::Dir(dir.clone()), recheck_hours: 2 }).unwrap();
+        let meter = test_meter(Fuel::Electricity, "1200000000001");
+        let (from, to) = (at("2025-08-01T00:00:00Z"), at("2025-08-02T00:00:00Z"));
+
+        // Nothing cached, so the whole span is fetched
+        let mut cached = cache.load(&meter, from);
+        assert_eq!(cache.fetch_from(&cached, from), from);
+        cached.merge(from, from, to, &[
+            reading("2025-08-01T09:00:00Z", 0.5),
+            reading("2025-08-01T11:00:00Z", 0.25),
+            reading("2025-08-01T11:30:00Z", 0.25),
+        ]);
+        cache.save(&meter, &cached);
+
+        // After a restart, only from two hours before the last complete slot
+        let mut cached = cache.load(&meter, from);
+        let (from, to) = (at("2025-08-01T01:00:00Z"), at("2025-08-02T01:00:00Z"));
+        let fetched_from = cache.fetch_from(&cached, from);
+        assert_eq!(fetched_from, at("2025-08-01T10:00:00Z"));
+        // A late correction, a new slot, and a reading that's gone
+        cached.merge(from, fetched_from, to, &[reading("2025-08-01T11:00:00Z", 0.75), reading("2025-08-01T12:00:00Z", 1.0)]);
+        assert_eq!(
+            cached.readings(from, to),
+            [reading("2025-08-01T09:00:00Z", 0.5), reading("2025-08-01T11:00:00Z", 0.75), reading("2025-08-01T12:00:00Z", 1.0)],
+        );
+
+        // A window reaching further back than the cache needs a full fetch
+        assert_eq!(cache.fetch_from(&cached, at("2025-07-01T00:00:00Z")), at("2025-07-01T00:00:00Z"));
+
+        cache.clear().unwrap();
+        assert_eq!(cache.load(&meter, from), MeterReadings::default());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,45 +1,453 @@
-use std::error::Error;
-
-use carbonintensity::{get_intensities};
-use chrono::{DateTime, Utc};
-
-// Example function to fetch usage and compute carbon
-pub async fn get_carbon_intensity(
-    usage_kwh: f64,
-    region: carbonintensity::Region,
-    period_from: &str,
-    period_to:  Option<&str>,
-) -> Result<f64, Box<dyn Error>> {
-    let date = DateTime::parse_from_rfc3339(period_from)
-        .unwrap()
-        .with_timezone(&Utc);
-    let pt = period_to.unwrap();
-    
-    let date1 = DateTime::parse_from_rfc3339(pt)
-        .unwrap()
-        .with_timezone(&Utc);
-
-    let format_period_from = date.format("%Y-%m-%dT%H:%MZ").to_string();
-    let format_period_to = date1.format("%Y-%m-%dT%H:%MZ").to_string();
-    let result = get_intensities(
-        &carbonintensity::Target::Region(region),
-        &format_period_from,
-        &Some(&format_period_to),
-    ).await?;
-
-    let avg_intensity: f64 = result
-    .iter()
-    .map(|(_dt, val)| *val as f64)
-    .sum::<f64>() / result.len() as f64;
-
-    let carbon_grams = usage_kwh * avg_intensity;
-
-    Ok(carbon_grams)
+use std::{collections::BTreeMap, fmt, str::FromStr, sync::Arc};
+
+use carbonintensity::Region;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use log::debug;
+use reqwest::Client as HttpClient;
+use serde::{Deserialize, Deserializer, de::DeserializeOwned};
+
+use crate::error::{self, Error};
+use crate::retry::{self, Api, Retrier};
+use crate::usage::Reading;
+
+pub const DEFAULT_BASE_URL: &str = "https://api.carbonintensity.org.uk/";
+
+/// Lowest-carbon windows looked for in the forecast unless configured otherwise.
+pub const DEFAULT_LOWEST_WINDOWS: &[&str] = &["1h", "3h"];
+
+/// Intensity bands the API grades slots in, from greenest to dirtiest.
+pub const INDEXES: &[&str] = &["very low", "low", "moderate", "high", "very high"];
+
+/// Days of regional intensity fetched per request, inside the API's limit of 14.
+const HISTORY_CHUNK_DAYS: i64 = 13;
+
+/// How the API writes and expects times, e.g. `2025-08-01T12:30Z`.
+const API_TIME_FORMAT: &str = "%Y-%m-%dT%H:%MZ";
+
+/// Regional carbon intensity in gCO2/kWh, keyed by the start of each half-hour slot.
+pub type Intensities = BTreeMap<DateTime<Utc>, f64>;
+
+/// Share of generation in percent by fuel type, such as `wind` or `imports`.
+pub type GenerationMix = BTreeMap<String, f64>;
+
+/// Regional generation mix, keyed by the start of each half-hour slot.
+pub type GenerationMixes = BTreeMap<DateTime<Utc>, GenerationMix>;
+
+/// Where carbon intensity is read for: a region by name, or the region
+/// covering an outward postcode such as `SW1A`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    Region(Region),
+    Postcode(String),
+}
+
+/// The region a `Location` resolved to, as the API names it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedRegion {
+    pub region: Region,
+    pub name: String,
+    pub postcode: Option<String>,
+}
+
+impl ResolvedRegion {
+    /// The API's numeric id of the region.
+    pub fn id(&self) -> u8 {
+        self.region as u8
+    }
+}
+
+/// Minimal Carbon Intensity API client for what `carbonintensity` doesn't
+/// expose, such as the region a postcode falls in.
+#[derive(Clone)]
+pub struct CarbonIntensityApi {
+    http: HttpClient,
+    base_url: String,
+    retrier: Arc<Retrier>,
+}
+
+impl CarbonIntensityApi {
+    pub fn new() -> Self {
+        CarbonIntensityApi {
+            http: HttpClient::new(),
+            base_url: DEFAULT_BASE_URL.to_string(),
+            retrier: Arc::new(Retrier::default()),
+        }
+    }
+
+    /// Paces and retries requests with `retrier`, shared with the Octopus clients.
+    pub fn with_retrier(mut self, retrier: Arc<Retrier>) -> Self {
+        self.retrier = retrier;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_base_url<S: Into<String>>(mut self, base_url: S) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    /// GETs `path` relative to the base URL and parses the JSON body.
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> error::Result<T> {
+        let url = format!("{}/{}", self.base_url.trim_end_matches('/'), path.trim_start_matches('/'));
+        debug!("GET {url}");
+
+        let body = self.retrier.run(Api::CarbonIntensity, || self.send(&url)).await?;
+        serde_json::from_slice(&body).map_err(|e| Error::Parse(format!("JSON from {url}: {e}")))
+    }
+
+    /// Sends one GET and returns the body of a successful response.
+    async fn send(&self, url: &str) -> error::Result<Vec<u8>> {
+        let resp = self.http.get(url).send().await.map_err(Error::carbon_intensity)?;
+        let status = resp.status();
+        let retry_after = retry::retry_after(resp.headers(), Utc::now());
+        let body = resp.bytes().await.map_err(Error::carbon_intensity)?;
+        if !status.is_success() {
+            return Err(Error::CarbonIntensity {
+                status: Some(status.as_u16()),
+                retry_after,
+                message: format!("error status {status}: {}", String::from_utf8_lossy(&body)),
+            });
+        }
+        Ok(body.to_vec())
+    }
+
+    /// The region carbon intensity is read for at `location`. Only postcodes need a lookup.
+    pub async fn resolve(&self, location: &Location) -> error::Result<ResolvedRegion> {
+        let postcode = match location {
+            Location::Region(region) => {
+                return Ok(ResolvedRegion { region: *region, name: region.to_string(), postcode: None });
+            }
+            Location::Postcode(postcode) => postcode,
+        };
+
+        #[derive(Deserialize)]
+        struct Response {
+            data: Vec<RegionData>,
+        }
+        #[derive(Deserialize)]
+        struct RegionData {
+            regionid: u8,
+            shortname: String,
+        }
+
+        let response: Response = self.get(&format!("regional/postcode/{postcode}")).await?;
+        let data = response
+            .data
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::carbon_intensity(format!("no region found for postcode {postcode}")))?;
+        let region = data
+            .regionid
+            .to_string()
+            .parse::<Region>()
+            .map_err(|e| Error::Parse(format!("postcode {postcode} is in unknown region {}: {e}", data.regionid)))?;
+        Ok(ResolvedRegion { region, name: data.shortname, postcode: Some(postcode.clone()) })
+    }
+}
+
+/// A half hour of regional intensity, as forecast by the API.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct IntensitySlot {
+    #[serde(deserialize_with = "api_time")]
+    pub from: DateTime<Utc>,
+    #[serde(deserialize_with = "api_time")]
+    pub to: DateTime<Utc>,
+    pub intensity: SlotIntensity,
+    #[serde(rename = "generationmix", default)]
+    pub generation_mix: Vec<GenerationShare>,
+}
+
+impl IntensitySlot {
+    /// The slot's generation mix by fuel type.
+    pub fn mix(&self) -> GenerationMix {
+        self.generation_mix.iter().map(|share| (share.fuel.clone(), share.percent)).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SlotIntensity {
+    /// gCO2/kWh
+    pub forecast: f64,
+    /// One of `INDEXES`.
+    pub index: String,
+}
+
+/// One fuel type's share of a slot's generation.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GenerationShare {
+    pub fuel: String,
+    #[serde(rename = "perc")]
+    pub percent: f64,
+}
+
+/// Slots of a single region, as the regional intensity endpoints return them.
+#[derive(Deserialize)]
+struct RegionResponse {
+    data: RegionSlots,
+}
+
+#[derive(Deserialize)]
+struct RegionSlots {
+    data: Vec<IntensitySlot>,
+}
+
+/// Times in the API's `2025-08-01T12:30Z` form.
+fn api_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    chrono::NaiveDateTime::parse_from_str(&s, API_TIME_FORMAT)
+        .map(|time| time.and_utc())
+        .map_err(serde::de::Error::custom)
+}
+
+impl CarbonIntensityApi {
+    /// The half-hourly forecast for `region` over the 48 hours from the slot containing `from`.
+    pub async fn forecast(&self, region: Region, from: DateTime<Utc>) -> error::Result<Vec<IntensitySlot>> {
+        let from = from.format(API_TIME_FORMAT);
+        let response: RegionResponse = self.get(&format!("regional/intensity/{from}/fw48h/regionid/{}", region as u8)).await?;
+        Ok(response.data.data)
+    }
+
+    /// Half-hourly intensity and generation mix of `region` between `from`
+    /// and `to`, fetched `HISTORY_CHUNK_DAYS` at a time. The regional
+    /// endpoints only publish the forecast, which stands in for the actual.
+    pub async fn history(
+        &self,
+        region: Region,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> error::Result<Vec<IntensitySlot>> {
+        let mut slots: Vec<IntensitySlot> = Vec::new();
+        let mut start = from;
+        while start < to {
+            let end = (start + TimeDelta::days(HISTORY_CHUNK_DAYS)).min(to);
+            let path = format!(
+                "regional/intensity/{}/{}/regionid/{}",
+                start.format(API_TIME_FORMAT),
+                end.format(API_TIME_FORMAT),
+                region as u8,
+            );
+            let response: RegionResponse = self.get(&path).await?;
+            slots.extend(response.data.data);
+            start = end;
+        }
+
+        // Neighbouring chunks can both return the slot at their boundary
+        slots.sort_by_key(|slot| slot.from);
+        slots.dedup_by_key(|slot| slot.from);
+        Ok(slots)
+    }
+}
+
+/// The slot of `slots` covering `at`.
+pub fn slot_at(slots: &[IntensitySlot], at: DateTime<Utc>) -> Option<&IntensitySlot> {
+    slots.iter().find(|slot| slot.from <= at && at < slot.to)
+}
+
+/// A length of time to find the greenest stretch of the forecast for, such as
+/// `3h` to run a dishwasher or charge a car. A whole number of half hours.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct LowestWindow {
+    slots: usize,
+}
+
+impl LowestWindow {
+    /// Label used for the `duration` metric label.
+    pub fn label(&self) -> String {
+        if self.slots.is_multiple_of(2) {
+            format!("{}h", self.slots / 2)
+        } else {
+            format!("{}min", self.slots * 30)
+        }
+    }
+}
+
+impl fmt::Display for LowestWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.label())
+    }
+}
+
+impl FromStr for LowestWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        // Minutes are `min`, as `m` is months in reporting windows
+        let minutes = if let Some(hours) = s.strip_suffix('h') {
+            hours.parse::<usize>().ok().and_then(|hours| hours.checked_mul(60))
+        } else {
+            s.strip_suffix("min").and_then(|minutes| minutes.parse::<usize>().ok())
+        };
+        match minutes {
+            Some(minutes) if minutes > 0 && minutes.is_multiple_of(30) && minutes <= 24 * 60 => Ok(LowestWindow { slots: minutes / 30 }),
+            _ => Err(format!("invalid lowest-carbon window '{s}', expected half hours up to a day such as 30min, 1h or 3h")),
+        }
+    }
+}
+
+impl TryFrom<String> for LowestWindow {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+pub fn default_lowest_windows() -> Vec<LowestWindow> {
+    DEFAULT_LOWEST_WINDOWS.iter().map(|w| w.parse().expect("default lowest-carbon windows are valid")).collect()
+}
+
+/// The greenest unbroken run of `window` slots in the forecast that hasn't
+/// ended by `now`, as its start and average intensity. Ties go to the earliest.
+pub fn lowest_window(slots: &[IntensitySlot], window: LowestWindow, now: DateTime<Utc>) -> Option<(DateTime<Utc>, f64)> {
+    let upcoming: Vec<&IntensitySlot> = slots.iter().filter(|slot| slot.to > now).collect();
+    let mut best: Option<(DateTime<Utc>, f64)> = None;
+
+    for run in upcoming.windows(window.slots) {
+        // A gap in the forecast would make the run longer than asked for
+        if run.windows(2).any(|pair| pair[0].to != pair[1].from) {
+            continue;
+        }
+        let average = run.iter().map(|slot| slot.intensity.forecast).sum::<f64>() / run.len() as f64;
+        if best.is_none_or(|(_, lowest)| average < lowest) {
+            best = Some((run[0].from, average));
+        }
+    }
+    best
+}
+
+/// Carbon Intensity API region for a region name, such as `North Scotland`.
+pub fn region_by_name(name: &str) -> Option<Region> {
+    let region = match name {
+        "North Scotland" => Region::NorthScotland,
+        "South Scotland" => Region::SouthScotland,
+        "North West England" => Region::NorthWestEngland,
+        "North East England" => Region::NorthEastEngland,
+        "South Yorkshire" => Region::SouthYorkshire,
+        "North Wales, Merseyside and Cheshire" => Region::NorthWalesMerseysideAndCheshire,
+        "South Wales" => Region::SouthWales,
+        "West Midlands" => Region::WestMidlands,
+        "East Midlands" => Region::EastMidlands,
+        "East England" => Region::EastEngland,
+        "South West England" => Region::SouthWestEngland,
+        "South England" => Region::SouthEngland,
+        "London" => Region::London,
+        "South East England" => Region::SouthEastEngland,
+        "England" => Region::England,
+        "Wales" => Region::Wales,
+        "Scotland" => Region::Scotland,
+        _ => return None,
+    };
+    Some(region)
+}
+
+/// The outward code of a UK postcode, e.g. `SW1A` from `sw1a 1aa`, which is
+/// all the API takes.
+pub fn outward_code(postcode: &str) -> Result<String, String> {
+    let upper = postcode.trim().to_ascii_uppercase();
+    let mut parts = upper.split_whitespace();
+    let outward = match (parts.next(), parts.next()) {
+        (Some(outward), Some(_)) => outward,
+        // A full postcode written without its space ends in a three character inward code
+        (Some(code), None) if code.is_ascii() && code.len() >= 5 => &code[..code.len() - 3],
+        (Some(code), None) => code,
+        (None, _) => "",
+    };
+
+    let valid = (2..=4).contains(&outward.len())
+        && outward.starts_with(|c: char| c.is_ascii_alphabetic())
+        && outward.chars().all(|c| c.is_ascii_alphanumeric());
+    if !valid {
+        return Err(format!("invalid postcode '{postcode}', expected an outward code such as SW1A"));
+    }
+    Ok(outward.to_string())
+}
+
+/// Intensity of each of `slots`, keyed by its start.
+pub fn intensities(slots: &[IntensitySlot]) -> Intensities {
+    slots.iter().map(|slot| (slot.from, slot.intensity.forecast)).collect()
+}
+
+/// Generation mix of each of `slots` that has one, keyed by its start.
+pub fn generation_mixes(slots: &[IntensitySlot]) -> GenerationMixes {
+    slots
+        .iter()
+        .filter(|slot| !slot.generation_mix.is_empty())
+        .map(|slot| (slot.from, slot.mix()))
+        .collect()
+}
+
+/// Average intensity over the slots in `intensities`, `None` when there are none.
+pub fn average_intensity(intensities: &Intensities) -> Option<f64> {
+    if intensities.is_empty() {
+        return None;
+    }
+    Some(intensities.values().sum::<f64>() / intensities.len() as f64)
+}
+
+/// Average share of each fuel type over the slots in `mixes`, `None` when there are none.
+pub fn average_mix(mixes: &GenerationMixes) -> Option<GenerationMix> {
+    if mixes.is_empty() {
+        return None;
+    }
+    let mut average = GenerationMix::new();
+    for mix in mixes.values() {
+        for (fuel, percent) in mix {
+            *average.entry(fuel.clone()).or_default() += percent / mixes.len() as f64;
+        }
+    }
+    Some(average)
+}
+
+/// kWh of half-hourly `readings` by the fuel type that generated them, each
+/// slot split by its own mix. Slots are matched and filled in as in
+/// `slot_carbon`. `None` when there's no mix to use at all.
+pub fn mix_usage(readings: &[Reading], mixes: &GenerationMixes) -> Option<GenerationMix> {
+    let fallback = average_mix(mixes)?;
+    let slot = TimeDelta::minutes(30);
+
+    let mut usage = GenerationMix::new();
+    for reading in readings {
+        let start = reading.start.duration_trunc(slot).unwrap_or(reading.start);
+        let mix = mixes.get(&start).unwrap_or(&fallback);
+        for (fuel, percent) in mix {
+            *usage.entry(fuel.clone()).or_default() += reading.kwh * percent / 100.0;
+        }
+    }
+    Some(usage)
+}
+
+/// Emissions in grams of half-hourly `readings`, each slot at its own
+/// intensity. A reading starting off the half hour counts towards the slot it
+/// starts in, and slots with no intensity use the average of those that have
+/// one. `None` when there's no intensity to use at all.
+pub fn slot_carbon(readings: &[Reading], intensities: &Intensities) -> Option<f64> {
+    let fallback = average_intensity(intensities)?;
+    let slot = TimeDelta::minutes(30);
+
+    let mut missing = 0;
+    let grams = readings
+        .iter()
+        .map(|reading| {
+            let start = reading.start.duration_trunc(slot).unwrap_or(reading.start);
+            let intensity = intensities.get(&start).copied().unwrap_or_else(|| {
+                missing += 1;
+                fallback
+            });
+            reading.kwh * intensity
+        })
+        .sum();
+    if missing > 0 {
+        debug!("No carbon intensity for {missing} of {} slots, used the average of {fallback:.1} g/kWh", readings.len());
+    }
+    Some(grams)
 }
 
 #[cfg(test)]
 mod tests {
-    use carbonintensity::Region;
+    use super::*;
+    use crate::octopus::mock_server;
 
     #[test]
     fn test_region_enum_values() {
@@ -81,7 +489,7 @@ mod tests {
                 | Region::SouthEastEngland
                 | Region::England
                 | Region::Wales
-                | Region::Scotland => assert!(true),
+                | Region::Scotland => {}
             }
         }
     }
@@ -90,7 +498,7 @@ mod tests {
     fn test_calculate_carbon_intensity() {
         // Suppose result is a Vec<(DateTime, u32)>
         // We'll use dummy values as (dt, intensity)
-        let result = vec![
+        let result = [
             ("2025-08-01T00:00:00Z", 200u32),
             ("2025-08-01T01:00:00Z", 300u32),
             ("2025-08-01T02:00:00Z", 500u32),
@@ -110,12 +518,177 @@ mod tests {
         assert!((carbon_grams - 3333.3333).abs() < 0.1);
     }
 
-    // If get_carbon_intensity or similar functions exist, add a test stub
-    // Uncomment and adjust as needed based on actual function signatures
+    #[test]
+    fn test_slot_carbon_weights_each_slot() {
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        let intensities: Intensities = BTreeMap::from([
+            (at("2025-08-01T00:00:00Z"), 100.0),
+            (at("2025-08-01T00:30:00Z"), 300.0),
+            (at("2025-08-01T01:30:00Z"), 200.0),
+        ]);
+        let readings = [
+            Reading { start: at("2025-08-01T00:00:00Z"), kwh: 1.0 },
+            // Off the half hour, so counted in the 00:30 slot
+            Reading { start: at("2025-08-01T00:31:00+00:00"), kwh: 2.0 },
+            // No intensity for 01:00, so the 200 g/kWh average is used
+            Reading { start: at("2025-08-01T01:00:00Z"), kwh: 0.5 },
+        ];
+
+        assert_eq!(average_intensity(&intensities), Some(200.0));
+        assert_eq!(slot_carbon(&readings, &intensities), Some(100.0 + 600.0 + 100.0));
+        // Shifting usage to the greenest slot is what the weighting rewards
+        let green = [Reading { start: at("2025-08-01T00:00:00Z"), kwh: 3.5 }];
+        assert_eq!(slot_carbon(&green, &intensities), Some(350.0));
+
+        assert_eq!(slot_carbon(&readings, &Intensities::new()), None);
+        assert_eq!(slot_carbon(&[], &intensities), Some(0.0));
+    }
+
+    #[test]
+    fn test_mix_usage_splits_each_slot() {
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        let mix = |wind: f64, gas: f64| GenerationMix::from([("wind".to_string(), wind), ("gas".to_string(), gas)]);
+        let mixes: GenerationMixes = BTreeMap::from([
+            (at("2025-08-01T00:00:00Z"), mix(80.0, 20.0)),
+            (at("2025-08-01T00:30:00Z"), mix(20.0, 80.0)),
+        ]);
+        let readings = [
+            Reading { start: at("2025-08-01T00:00:00Z"), kwh: 1.0 },
+            Reading { start: at("2025-08-01T00:30:00Z"), kwh: 2.0 },
+            // No mix for 01:00, so the 50/50 average is used
+            Reading { start: at("2025-08-01T01:00:00Z"), kwh: 1.0 },
+        ];
 
-    // #[tokio::test]
-    // async fn test_get_carbon_intensity_zero_usage() {
-    //     let grams = get_carbon_intensity(0.0, Region::England, "2025-08-01T00:00:00Z", Some("2025-08-02T00:00:00Z")).await;
-    //     assert!(grams.unwrap() >= 0.0);
-    // }
-}
\ No newline at end of file
+        let usage = mix_usage(&readings, &mixes).unwrap();
+        assert!((usage["wind"] - (0.8 + 0.4 + 0.5)).abs() < 1e-9);
+        assert!((usage["gas"] - (0.2 + 1.6 + 0.5)).abs() < 1e-9);
+        assert_eq!(mix_usage(&readings, &GenerationMixes::new()), None);
+    }
+
+    #[test]
+    fn test_region_mapping() {
+        // Should match known regions
+        let region_names = vec![
+            "North Scotland", "South Scotland", "North West England", "North East England",
+            "South Yorkshire", "North Wales, Merseyside and Cheshire", "South Wales", "West Midlands",
+            "East Midlands", "East England", "South West England", "South England", "London",
+            "South East England", "England", "Wales", "Scotland"
+        ];
+        for region in region_names {
+            assert!(region_by_name(region).is_some(), "{region} should map to a region");
+        }
+        assert!(region_by_name("Atlantis").is_none());
+    }
+
+    #[test]
+    fn test_outward_code() {
+        assert_eq!(outward_code("SW1A"), Ok("SW1A".to_string()));
+        assert_eq!(outward_code("sw1a 1aa"), Ok("SW1A".to_string()));
+        assert_eq!(outward_code("BS71AA"), Ok("BS7".to_string()));
+        assert_eq!(outward_code(" rg10 "), Ok("RG10".to_string()));
+        assert!(outward_code("1AB").is_err());
+        assert!(outward_code("S").is_err());
+        assert!(outward_code("SW1A-1AA").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_postcode_from_mock_server() {
+        let base_url = mock_server(vec![(
+            "/v1/regional/postcode/SW1A",
+            serde_json::json!({"data": [{"regionid": 13, "dnoregion": "UKPN London", "shortname": "London", "postcode": "SW1A", "data": []}]}),
+        )]);
+        let api = CarbonIntensityApi::new().with_base_url(base_url);
+
+        let resolved = api.resolve(&Location::Postcode("SW1A".to_string())).await.unwrap();
+        assert_eq!(resolved, ResolvedRegion { region: Region::London, name: "London".to_string(), postcode: Some("SW1A".to_string()) });
+        assert_eq!(resolved.id(), 13);
+        assert!(api.resolve(&Location::Postcode("ZZ9".to_string())).await.is_err());
+
+        // Regions given by name need no lookup
+        let resolved = api.resolve(&Location::Region(Region::SouthWales)).await.unwrap();
+        assert_eq!((resolved.id(), resolved.name.as_str()), (7, "South Wales"));
+    }
+
+    fn forecast_json() -> serde_json::Value {
+        let slot = |from: &str, to: &str, forecast: u32, index: &str| {
+            serde_json::json!({"from": from, "to": to, "intensity": {"forecast": forecast, "index": index}, "generationmix": [
+                {"fuel": "gas", "perc": forecast as f64 / 4.0},
+                {"fuel": "wind", "perc": 100.0 - forecast as f64 / 4.0},
+            ]})
+        };
+        serde_json::json!({"data": {"regionid": 13, "dnoregion": "UKPN London", "shortname": "London", "data": [
+            slot("2025-08-01T12:00Z", "2025-08-01T12:30Z", 180, "moderate"),
+            slot("2025-08-01T12:30Z", "2025-08-01T13:00Z", 90, "low"),
+            slot("2025-08-01T13:00Z", "2025-08-01T13:30Z", 40, "very low"),
+            slot("2025-08-01T13:30Z", "2025-08-01T14:00Z", 150, "moderate"),
+            // The forecast skips a slot here
+            slot("2025-08-01T14:30Z", "2025-08-01T15:00Z", 10, "very low"),
+            slot("2025-08-01T15:00Z", "2025-08-01T15:30Z", 20, "very low"),
+        ]}})
+    }
+
+    #[tokio::test]
+    async fn test_forecast_and_lowest_windows() {
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        let base_url = mock_server(vec![("/v1/regional/intensity/2025-08-01T12:10Z/fw48h/regionid/13", forecast_json())]);
+        let api = CarbonIntensityApi::new().with_base_url(base_url);
+
+        let now = at("2025-08-01T12:10:00Z");
+        let slots = api.forecast(Region::London, now).await.unwrap();
+        assert_eq!(slots.len(), 6);
+        let current = slot_at(&slots, now).unwrap();
+        assert_eq!((current.intensity.forecast, current.intensity.index.as_str()), (180.0, "moderate"));
+        assert_eq!(current.mix(), GenerationMix::from([("gas".to_string(), 45.0), ("wind".to_string(), 55.0)]));
+
+        let window = |s: &str| s.parse::<LowestWindow>().unwrap();
+        assert_eq!(lowest_window(&slots, window("30min"), now), Some((at("2025-08-01T14:30:00Z"), 10.0)));
+        assert_eq!(lowest_window(&slots, window("1h"), now), Some((at("2025-08-01T14:30:00Z"), 15.0)));
+        // The run over the gap isn't contiguous, so the best 1.5h starts at 12:30
+        assert_eq!(lowest_window(&slots, window("90min"), now), Some((at("2025-08-01T12:30:00Z"), 280.0 / 3.0)));
+        assert_eq!(lowest_window(&slots, window("3h"), now), None);
+        // Slots that have ended are no longer candidates
+        assert_eq!(lowest_window(&slots, window("30min"), at("2025-08-01T15:10:00Z")), Some((at("2025-08-01T15:00:00Z"), 20.0)));
+    }
+
+    #[tokio::test]
+    async fn test_history_fetched_in_chunks() {
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        let slot = |from: &str, to: &str, forecast: u32| {
+            serde_json::json!({"from": from, "to": to, "intensity": {"forecast": forecast, "index": "low"}, "generationmix": [{"fuel": "wind", "perc": 60.0}]})
+        };
+        let region = |slots: Vec<serde_json::Value>| serde_json::json!({"data": {"regionid": 13, "shortname": "London", "data": slots}});
+        let base_url = mock_server(vec![
+            (
+                "/v1/regional/intensity/2025-07-01T00:00Z/2025-07-14T00:00Z/regionid/13",
+                region(vec![slot("2025-07-01T00:00Z", "2025-07-01T00:30Z", 100), slot("2025-07-14T00:00Z", "2025-07-14T00:30Z", 120)]),
+            ),
+            (
+                "/v1/regional/intensity/2025-07-14T00:00Z/2025-07-20T00:00Z/regionid/13",
+                region(vec![slot("2025-07-14T00:00Z", "2025-07-14T00:30Z", 120), slot("2025-07-19T23:30Z", "2025-07-20T00:00Z", 90)]),
+            ),
+        ]);
+        let api = CarbonIntensityApi::new().with_base_url(base_url);
+
+        // 19 days takes two requests, which overlap on the slot between them
+        let slots = api.history(Region::London, at("2025-07-01T00:00:00Z"), at("2025-07-20T00:00:00Z")).await.unwrap();
+        let starts: Vec<_> = slots.iter().map(|slot| slot.from).collect();
+        assert_eq!(starts, [at("2025-07-01T00:00:00Z"), at("2025-07-14T00:00:00Z"), at("2025-07-19T23:30:00Z")]);
+        assert_eq!(intensities(&slots)[&at("2025-07-19T23:30:00Z")], 90.0);
+        assert_eq!(generation_mixes(&slots)[&at("2025-07-01T00:00:00Z")]["wind"], 60.0);
+
+        assert!(api.history(Region::London, at("2025-06-01T00:00:00Z"), at("2025-06-02T00:00:00Z")).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_lowest_windows() {
+        assert_eq!("3h".parse::<LowestWindow>().map(|w| w.label()), Ok("3h".to_string()));
+        assert_eq!("90min".parse::<LowestWindow>().map(|w| w.label()), Ok("90min".to_string()));
+        assert_eq!("120min".parse::<LowestWindow>().map(|w| w.label()), Ok("2h".to_string()));
+        assert!("45min".parse::<LowestWindow>().is_err());
+        assert!("0h".parse::<LowestWindow>().is_err());
+        assert!("2d".parse::<LowestWindow>().is_err());
+        // m is months in reporting windows, so it isn't taken for minutes here
+        assert!("30m".parse::<LowestWindow>().is_err());
+        assert!("307445734561825861h".parse::<LowestWindow>().is_err());
+    }
+}
@@ -0,0 +1,62 @@
+use chrono::{DateTime, Utc};
+
+/// Source of the current time, so polling can be driven by a fake clock in tests.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// Wall clock time.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Clock that only moves when told to.
+#[cfg(test)]
+pub struct FakeClock {
+    now: std::sync::Mutex<DateTime<Utc>>,
+}
+
+#[cfg(test)]
+impl FakeClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        FakeClock { now: std::sync::Mutex::new(now) }
+    }
+
+    pub fn advance(&self, by: chrono::Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_system_clock_is_current() {
+        let before = Utc::now();
+        let now = SystemClock.now();
+        assert!(now >= before);
+        assert!((now - before).num_seconds() < 3);
+    }
+
+    #[test]
+    fn test_fake_clock_advances() {
+        let start = DateTime::parse_from_rfc3339("2025-08-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let clock = FakeClock::new(start);
+        assert_eq!(clock.now(), start);
+
+        clock.advance(chrono::Duration::minutes(30));
+        assert_eq!(clock.now(), start + chrono::Duration::minutes(30));
+    }
+}
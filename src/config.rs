@@ -0,0 +1,799 @@
+use std::{collections::{BTreeMap, BTreeSet}, env, fs, path::{Path, PathBuf}};
+
+use chrono_tz::Tz;
+use clap::Args;
+use serde::Deserialize;
+
+use crate::cache::{self, CacheLocation, CacheSettings};
+use crate::gas::{self, GasSettings, GasUnit, MeterType};
+use crate::retry::{self, RetrySettings};
+use crate::tariff::{Tariff, TariffSettings};
+use crate::carbon_intensity::{self, Location, LowestWindow};
+use crate::usage::Fuel;
+use crate::web::{ListenAddress, WebConfig};
+use crate::window::{self, Window};
+
+/// Label names the exporter sets itself, which custom labels can't reuse.
+pub const RESERVED_LABELS: &[&str] = &["fuel", "meter_point", "window", "period", "fuel_type"];
+
+/// Address the metrics server listens on unless configured otherwise.
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:9090";
+
+/// Exporter settings, read from a TOML file such as the one below, or the
+/// same structure in YAML when the file ends in `.yaml` or `.yml`. Every
+/// top-level value can be overridden by its environment variable, which can
+/// in turn be overridden on the command line.
+///
+/// ```toml
+/// api_key_file = "/run/secrets/octopus_api_key"
+/// postcode = "SW1A"
+/// windows = ["2d", "mtd", "1y"]
+/// interval = 900
+/// listen = "0.0.0.0:9090"
+/// web_config_file = "web.yml"
+///
+/// [gas]
+/// unit = "m3"
+///
+/// [[account]]
+/// number = "A-1234ABCD"
+/// labels = { property = "flat" }
+///
+/// [[account]]
+/// api_key = "sk_live_..."
+/// labels = { property = "cottage" }
+///
+/// [[account.meter]]
+/// fuel = "electricity"
+/// meter_point = "1200000000009"
+/// serial_number = "21E0000009"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Key for accounts that don't set their own, or a file holding it.
+    pub api_key: Option<String>,
+    pub api_key_file: Option<PathBuf>,
+    /// Region to get carbon intensity data from, or the postcode to find it by.
+    pub region: Option<String>,
+    pub postcode: Option<String>,
+    pub windows: Option<Vec<Window>>,
+    /// Durations to find the lowest-carbon stretch of the forecast for.
+    pub lowest_carbon_windows: Option<Vec<LowestWindow>>,
+    pub timezone: Option<Tz>,
+    /// Seconds between polls, and before exiting when non-zero.
+    pub interval: Option<u64>,
+    pub timeout: Option<u64>,
+    pub listen: Option<ListenAddress>,
+    /// TLS and auth for the metrics server, in the exporter-toolkit format.
+    pub web_config_file: Option<PathBuf>,
+    pub legacy_metrics: Option<bool>,
+    /// Directory readings are cached in between polls, and hours before the
+    /// last cached slot checked again for late readings.
+    pub cache_dir: Option<PathBuf>,
+    pub cache_recheck_hours: Option<u64>,
+    /// SQLite database of history filled by `backfill`, used instead of `cache_dir`.
+    pub database: Option<PathBuf>,
+    pub electricity_tariff: Option<String>,
+    pub gas_tariff: Option<String>,
+    #[serde(default)]
+    pub gas: GasConfig,
+    #[serde(default)]
+    pub requests: RequestsConfig,
+    #[serde(default, rename = "account")]
+    pub accounts: Vec<AccountConfig>,
+}
+
+/// Defaults for gas meters that don't set their own unit or conversion.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GasConfig {
+    pub unit: Option<GasUnit>,
+    pub calorific_value: Option<f64>,
+    pub volume_correction: Option<f64>,
+    pub emission_factor: Option<f64>,
+}
+
+/// Retries and limits on requests to the Octopus and Carbon Intensity APIs.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestsConfig {
+    pub retries: Option<u32>,
+    pub max_concurrent: Option<usize>,
+    pub per_second: Option<f64>,
+}
+
+/// One account, polled with its own API key.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    /// Account to discover meters and tariffs on.
+    pub number: Option<String>,
+    /// Defaults to the top-level key.
+    pub api_key: Option<String>,
+    pub api_key_file: Option<PathBuf>,
+    /// Labels added to every meter of the account.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Meters set by hand, or overrides of discovered ones.
+    #[serde(default, rename = "meter")]
+    pub meters: Vec<MeterConfig>,
+}
+
+/// A meter set by hand. Without `meter_point` it overrides the discovered
+/// meter point of its fuel; without `serial_number` the installed meter is used.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeterConfig {
+    pub fuel: Fuel,
+    /// Records generation exported to the grid, electricity only.
+    #[serde(default)]
+    pub export: bool,
+    pub meter_point: Option<String>,
+    pub serial_number: Option<String>,
+    /// Tariff code, winning over the account's agreement and the tariff flags.
+    pub tariff: Option<String>,
+    /// Unit a gas meter reports in, or the meter type that implies it.
+    pub unit: Option<GasUnit>,
+    pub meter_type: Option<MeterType>,
+    /// Gas conversion overrides, in MJ/m³ and as a factor.
+    pub calorific_value: Option<f64>,
+    pub volume_correction: Option<f64>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Settings given on the command line or through their environment
+/// variables, which win over the config file.
+#[derive(Debug, Clone, Default, Args)]
+pub struct Overrides {
+    /// Seconds to run for before exiting, 0 to run forever [default: 0]
+    #[arg(short, long, env = "OCTOPUS_EXPORTER_TIMEOUT")]
+    pub timeout: Option<u64>,
+
+    /// Interval in seconds between metric updates [default: 1800]
+    #[arg(short, long, env = "OCTOPUS_EXPORTER_INTERVAL")]
+    pub interval: Option<u64>,
+
+    /// Region to get carbon intensity data from [default: England]
+    #[arg(short, long, env = "OCTOPUS_EXPORTER_REGION", conflicts_with = "postcode")]
+    pub region: Option<String>,
+
+    /// Outward postcode, e.g. SW1A, whose region carbon intensity data is read for
+    #[arg(short, long, env = "OCTOPUS_EXPORTER_POSTCODE")]
+    pub postcode: Option<String>,
+
+    /// Reporting window, e.g. 36h, 90d, 2w, 3m, 1y, mtd, ytd or cycle:14 (repeatable)
+    /// [default: 2d,1w,2w,4w,mtd,2m,3m,6m,1y]
+    #[arg(short, long = "window", value_delimiter = ',', env = "OCTOPUS_EXPORTER_WINDOWS")]
+    pub windows: Option<Vec<Window>>,
+
+    /// Duration to find the lowest-carbon stretch of the 48 hour forecast for,
+    /// e.g. 30min, 1h or 3h (repeatable) [default: 1h,3h]
+    #[arg(long = "lowest-carbon-window", value_delimiter = ',', env = "OCTOPUS_EXPORTER_LOWEST_CARBON_WINDOWS")]
+    pub lowest_carbon_windows: Option<Vec<LowestWindow>>,
+
+    /// IANA timezone calendar windows are aligned to [default: Europe/London]
+    #[arg(long, env = "OCTOPUS_EXPORTER_TIMEZONE")]
+    pub timezone: Option<Tz>,
+
+    /// Address the metrics server listens on, e.g. 0.0.0.0:9090, [::]:9090, :9090
+    /// or unix:/run/octopus-energy-exporter.sock [default: 127.0.0.1:9090]
+    #[arg(short, long, env = "OCTOPUS_EXPORTER_LISTEN")]
+    pub listen: Option<ListenAddress>,
+
+    /// Web config file enabling TLS and basic or bearer auth on /metrics,
+    /// in the Prometheus exporter-toolkit format
+    #[arg(long, env = "OCTOPUS_EXPORTER_WEB_CONFIG_FILE")]
+    pub web_config_file: Option<PathBuf>,
+
+    /// Also export the old one-gauge-per-window metric names
+    #[arg(long, env = "OCTOPUS_EXPORTER_LEGACY_METRICS")]
+    pub legacy_metrics: bool,
+
+    /// Electricity tariff code used for cost metrics, e.g. E-1R-VAR-22-11-01-C
+    /// [default: detected from the account]
+    #[arg(long, env = "OCTOPUS_ELECTRICITY_TARIFF", value_parser = |s: &str| Tariff::parse_for(s, Fuel::Electricity))]
+    pub electricity_tariff: Option<Tariff>,
+
+    /// Gas tariff code used for cost metrics, e.g. G-1R-VAR-22-11-01-C
+    /// [default: detected from the account]
+    #[arg(long, env = "OCTOPUS_GAS_TARIFF", value_parser = |s: &str| Tariff::parse_for(s, Fuel::Gas))]
+    pub gas_tariff: Option<Tariff>,
+
+    /// Unit gas meters report in, kwh (SMETS1) or m3 (SMETS2), unless set per meter [default: kwh]
+    #[arg(long, env = "OCTOPUS_GAS_UNIT")]
+    pub gas_unit: Option<GasUnit>,
+
+    /// Calorific value in MJ/m³ used to convert gas volume to kWh [default: 39.5]
+    #[arg(long, env = "OCTOPUS_GAS_CALORIFIC_VALUE")]
+    pub calorific_value: Option<f64>,
+
+    /// Volume correction factor used to convert gas volume to kWh [default: 1.02264]
+    #[arg(long, env = "OCTOPUS_GAS_VOLUME_CORRECTION")]
+    pub volume_correction: Option<f64>,
+
+    /// Emissions of gas in kgCO2e per kWh, e.g. 0 on a carbon-offset gas tariff [default: 0.1829]
+    #[arg(long, env = "OCTOPUS_GAS_EMISSION_FACTOR")]
+    pub gas_emission_factor: Option<f64>,
+
+    /// Directory to cache half-hourly readings in, so each poll only fetches
+    /// what's new and the cache survives restarts [default: no cache]
+    #[arg(long, env = "OCTOPUS_EXPORTER_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// SQLite database to keep the history of readings, carbon intensity and
+    /// tariff rates in, filled by backfill and kept up to date by run, which
+    /// then only fetches what's new. Takes the place of --cache-dir
+    #[arg(long, env = "OCTOPUS_EXPORTER_DATABASE", conflicts_with = "cache_dir")]
+    pub database: Option<PathBuf>,
+
+    /// Hours before the last cached reading fetched again each poll, for
+    /// readings that arrive late or are corrected [default: 48]
+    #[arg(long, env = "OCTOPUS_EXPORTER_CACHE_RECHECK_HOURS")]
+    pub cache_recheck_hours: Option<u64>,
+
+    /// Times to retry an API request that failed transiently, with backoff
+    /// and honouring Retry-After, 0 to not retry [default: 3]
+    #[arg(long, env = "OCTOPUS_EXPORTER_RETRIES")]
+    pub retries: Option<u32>,
+
+    /// Most API requests in flight at once, across every account [default: 4]
+    #[arg(long, env = "OCTOPUS_EXPORTER_MAX_CONCURRENT_REQUESTS")]
+    pub max_concurrent_requests: Option<usize>,
+
+    /// Most API requests started per second, across every account [default: 5]
+    #[arg(long, env = "OCTOPUS_EXPORTER_REQUESTS_PER_SECOND")]
+    pub requests_per_second: Option<f64>,
+}
+
+/// Everything `run` needs, once the config file, environment and flags are
+/// layered and checked.
+#[derive(Debug)]
+pub struct Settings {
+    pub timeout: u64,
+    pub interval: u64,
+    pub location: Location,
+    pub windows: Vec<Window>,
+    pub lowest_carbon_windows: Vec<LowestWindow>,
+    pub timezone: Tz,
+    pub listen: ListenAddress,
+    pub web: WebConfig,
+    pub legacy_metrics: bool,
+    pub tariffs: TariffSettings,
+    pub gas: GasSettings,
+    pub requests: RetrySettings,
+    pub cache: Option<CacheSettings>,
+    /// Accounts with the API key each is polled with.
+    pub accounts: Vec<(String, AccountConfig)>,
+    pub label_names: Vec<String>,
+}
+
+impl Settings {
+    /// Layers `overrides` over `config`, which should already have the
+    /// environment applied, and falls back to the defaults for anything unset.
+    pub fn resolve(config: Config, overrides: Overrides) -> Result<Self, String> {
+        config.validate()?;
+
+        let interval = overrides.interval.or(config.interval).unwrap_or(1800);
+        if interval == 0 {
+            return Err("interval must be at least 1 second".to_string());
+        }
+        // The most specific layer setting either decides, so a postcode flag beats a region in the file
+        let location = match (overrides.region, overrides.postcode) {
+            (None, None) => location(config.region.as_deref(), config.postcode.as_deref())?,
+            (region, postcode) => location(region.as_deref(), postcode.as_deref())?,
+        };
+        let windows = overrides.windows.or(config.windows.clone()).unwrap_or_else(window::default_windows);
+        if windows.is_empty() {
+            return Err("at least one window is needed".to_string());
+        }
+
+        let gas = GasSettings {
+            unit: overrides.gas_unit.or(config.gas.unit).unwrap_or_default(),
+            calorific_value: overrides.calorific_value.or(config.gas.calorific_value).unwrap_or(gas::DEFAULT_CALORIFIC_VALUE),
+            volume_correction: overrides
+                .volume_correction
+                .or(config.gas.volume_correction)
+                .unwrap_or(gas::DEFAULT_VOLUME_CORRECTION),
+            emission_factor: overrides
+                .gas_emission_factor
+                .or(config.gas.emission_factor)
+                .unwrap_or(gas::DEFAULT_EMISSION_FACTOR),
+        };
+        for value in [gas.calorific_value, gas.volume_correction] {
+            if !(value.is_finite() && value > 0.0) {
+                return Err(format!("gas conversion values must be positive, got {value}"));
+            }
+        }
+        emission_factor(gas.emission_factor)?;
+
+        let requests = RetrySettings {
+            retries: overrides.retries.or(config.requests.retries).unwrap_or(retry::DEFAULT_RETRIES),
+            max_concurrent: overrides
+                .max_concurrent_requests
+                .or(config.requests.max_concurrent)
+                .unwrap_or(retry::DEFAULT_MAX_CONCURRENT),
+            per_second: overrides.requests_per_second.or(config.requests.per_second).unwrap_or(retry::DEFAULT_PER_SECOND),
+        };
+        request_limits(requests.max_concurrent, requests.per_second)?;
+        // The most specific layer setting either decides, like the region and postcode
+        let cache_at = match (overrides.cache_dir, overrides.database) {
+            (None, None) => cache_location(config.cache_dir.clone(), config.database.clone())?,
+            (dir, database) => cache_location(dir, database)?,
+        };
+        let cache = cache_at.map(|location| CacheSettings {
+            location,
+            recheck_hours: overrides
+                .cache_recheck_hours
+                .or(config.cache_recheck_hours)
+                .unwrap_or(cache::DEFAULT_RECHECK_HOURS),
+        });
+
+        // Already checked against their fuel, by `validate` and the flag parsers
+        let tariffs = TariffSettings {
+            electricity: overrides
+                .electricity_tariff
+                .or_else(|| config.electricity_tariff.as_deref().and_then(|code| Tariff::parse_for(code, Fuel::Electricity).ok())),
+            gas: overrides
+                .gas_tariff
+                .or_else(|| config.gas_tariff.as_deref().and_then(|code| Tariff::parse_for(code, Fuel::Gas).ok())),
+        };
+
+        let listen = overrides
+            .listen
+            .or(config.listen.clone())
+            .unwrap_or_else(|| DEFAULT_LISTEN.parse().expect("default listen address is valid"));
+        let web = match overrides.web_config_file.as_ref().or(config.web_config_file.as_ref()) {
+            Some(path) => WebConfig::load(path)?,
+            None => WebConfig::default(),
+        };
+        if web.tls_server_config.is_some() && matches!(listen, ListenAddress::Unix(_)) {
+            return Err("TLS isn't supported when listening on a unix socket".to_string());
+        }
+
+        let mut accounts = Vec::new();
+        for (i, account) in config.accounts.iter().enumerate() {
+            let name = account.number.clone().unwrap_or_else(|| format!("account #{}", i + 1));
+            let api_key = config.api_key(account).map_err(|e| format!("{name}: {e}"))?;
+            accounts.push((api_key, account.clone()));
+        }
+
+        Ok(Settings {
+            timeout: overrides.timeout.or(config.timeout).unwrap_or(0),
+            interval,
+            location,
+            windows,
+            lowest_carbon_windows: overrides
+                .lowest_carbon_windows
+                .or(config.lowest_carbon_windows.clone())
+                .unwrap_or_else(carbon_intensity::default_lowest_windows),
+            timezone: overrides
+                .timezone
+                .or(config.timezone)
+                .unwrap_or_else(|| window::DEFAULT_TIMEZONE.parse().expect("default timezone is valid")),
+            listen,
+            web,
+            legacy_metrics: overrides.legacy_metrics || config.legacy_metrics.unwrap_or(false),
+            tariffs,
+            gas,
+            requests,
+            cache,
+            label_names: config.label_names(),
+            accounts,
+        })
+    }
+}
+
+impl Config {
+    /// Reads a config file, as YAML if it's named `.yaml` or `.yml` and TOML otherwise.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("can't read config {}: {e}", path.display()))?;
+        let yaml = path.extension().is_some_and(|ext| ext == "yaml" || ext == "yml");
+        let config = if yaml {
+            serde_yaml::from_str(&text).map_err(|e| e.to_string())
+        } else {
+            toml::from_str(&text).map_err(|e| e.to_string())
+        };
+        config.map_err(|e| format!("invalid config {}: {e}", path.display()))
+    }
+
+    /// Layers the environment over the file: `OCTOPUS_API_KEY` or
+    /// `OCTOPUS_API_KEY_FILE` replace the top-level key, and when the file
+    /// lists no accounts, the single account described by
+    /// `OCTOPUS_ACCOUNT_NUMBER`, `MPAN`, `E_SERIAL_NO`, `MPRN`, `G_SERIAL_NO`,
+    /// `EXPORT_MPAN` and `EXPORT_SERIAL_NO` is polled. Empty values are ignored.
+    pub fn with_env(mut self) -> Self {
+        let var = |name: &str| env::var(name).ok().filter(|value| !value.trim().is_empty());
+
+        if let Some(api_key) = var("OCTOPUS_API_KEY") {
+            self.api_key = Some(api_key);
+            self.api_key_file = None;
+        } else if let Some(path) = var("OCTOPUS_API_KEY_FILE") {
+            self.api_key = None;
+            self.api_key_file = Some(PathBuf::from(path));
+        }
+        if !self.accounts.is_empty() {
+            return self;
+        }
+
+        let mut meters = Vec::new();
+        for (fuel, export, point, serial) in [
+            (Fuel::Electricity, false, var("MPAN"), var("E_SERIAL_NO")),
+            (Fuel::Gas, false, var("MPRN"), var("G_SERIAL_NO")),
+            (Fuel::Electricity, true, var("EXPORT_MPAN"), var("EXPORT_SERIAL_NO")),
+        ] {
+            if point.is_some() || serial.is_some() {
+                meters.push(MeterConfig {
+                    fuel,
+                    export,
+                    meter_point: point,
+                    serial_number: serial,
+                    tariff: None,
+                    unit: None,
+                    meter_type: None,
+                    calorific_value: None,
+                    volume_correction: None,
+                    labels: BTreeMap::new(),
+                });
+            }
+        }
+        self.accounts.push(AccountConfig {
+            number: var("OCTOPUS_ACCOUNT_NUMBER"),
+            meters,
+            ..Default::default()
+        });
+        self
+    }
+
+    /// The API key an account is polled with: its own, or the top-level one.
+    pub fn api_key(&self, account: &AccountConfig) -> Result<String, String> {
+        let key = match (&account.api_key, &account.api_key_file, &self.api_key, &self.api_key_file) {
+            (Some(key), ..) => key.clone(),
+            (None, Some(path), ..) => read_secret(path)?,
+            (None, None, Some(key), _) => key.clone(),
+            (None, None, None, Some(path)) => read_secret(path)?,
+            (None, None, None, None) => {
+                return Err("no API key: set OCTOPUS_API_KEY, OCTOPUS_API_KEY_FILE or api_key in the config".to_string());
+            }
+        };
+        if key.trim().is_empty() {
+            return Err("the API key is empty".to_string());
+        }
+        Ok(key)
+    }
+
+    /// Custom label names used anywhere in the config, in the order they're exported.
+    pub fn label_names(&self) -> Vec<String> {
+        let mut names = BTreeSet::new();
+        for account in &self.accounts {
+            names.extend(account.labels.keys().cloned());
+            for meter in &account.meters {
+                names.extend(meter.labels.keys().cloned());
+            }
+        }
+        names.into_iter().collect()
+    }
+
+    /// Checks the values that can only come from the file or environment.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.accounts.is_empty() {
+            return Err("config has no [[account]] entries".to_string());
+        }
+        if self.api_key.is_some() && self.api_key_file.is_some() {
+            return Err("set only one of api_key and api_key_file".to_string());
+        }
+        location(self.region.as_deref(), self.postcode.as_deref())?;
+        cache_location(self.cache_dir.clone(), self.database.clone())?;
+        let gas = [self.gas.calorific_value, self.gas.volume_correction];
+        for value in gas.into_iter().flatten() {
+            if !(value.is_finite() && value > 0.0) {
+                return Err(format!("gas conversion values must be positive, got {value}"));
+            }
+        }
+        if let Some(factor) = self.gas.emission_factor {
+            emission_factor(factor)?;
+        }
+        request_limits(
+            self.requests.max_concurrent.unwrap_or(retry::DEFAULT_MAX_CONCURRENT),
+            self.requests.per_second.unwrap_or(retry::DEFAULT_PER_SECOND),
+        )?;
+        for (tariff, fuel) in [(&self.electricity_tariff, Fuel::Electricity), (&self.gas_tariff, Fuel::Gas)] {
+            if let Some(tariff) = tariff {
+                Tariff::parse_for(tariff, fuel)?;
+            }
+        }
+
+        for (i, account) in self.accounts.iter().enumerate() {
+            let name = account.number.clone().unwrap_or_else(|| format!("account #{}", i + 1));
+            if account.number.is_none() && account.meters.is_empty() {
+                return Err(format!(
+                    "{name} needs a number to discover meters (OCTOPUS_ACCOUNT_NUMBER), or meters set by hand"
+                ));
+            }
+            if account.api_key.is_some() && account.api_key_file.is_some() {
+                return Err(format!("{name}: set only one of api_key and api_key_file"));
+            }
+
+            for meter in &account.meters {
+                if meter.export && meter.fuel != Fuel::Electricity {
+                    return Err(format!("{name} has an export {} meter, only electricity can be exported", meter.fuel.as_str()));
+                }
+                if account.number.is_none() && (meter.meter_point.is_none() || meter.serial_number.is_none()) {
+                    return Err(format!("{name} has no number, so each meter needs meter_point and serial_number"));
+                }
+                let gas_settings = meter.unit.is_some()
+                    || meter.meter_type.is_some()
+                    || meter.calorific_value.is_some()
+                    || meter.volume_correction.is_some();
+                if gas_settings && meter.fuel != Fuel::Gas {
+                    return Err(format!("{name}: unit, meter_type, calorific_value and volume_correction only apply to gas meters"));
+                }
+                if let (Some(unit), Some(meter_type)) = (meter.unit, meter.meter_type)
+                    && unit != meter_type.unit()
+                {
+                    return Err(format!("{name}: unit {unit:?} contradicts meter_type {meter_type:?}"));
+                }
+                for value in [meter.calorific_value, meter.volume_correction].into_iter().flatten() {
+                    if !(value.is_finite() && value > 0.0) {
+                        return Err(format!("{name}: gas conversion values must be positive, got {value}"));
+                    }
+                }
+                if let Some(tariff) = &meter.tariff {
+                    Tariff::parse_for(tariff, meter.fuel).map_err(|e| format!("{name}: {e}"))?;
+                }
+            }
+
+            let labels = account.labels.keys().chain(account.meters.iter().flat_map(|meter| meter.labels.keys()));
+            for label in labels {
+                validate_label_name(label).map_err(|e| format!("{name}: {e}"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads an API key from a secrets file, ignoring surrounding whitespace.
+fn read_secret(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path)
+        .map(|key| key.trim().to_string())
+        .map_err(|e| format!("can't read API key from {}: {e}", path.display()))
+}
+
+/// Where carbon intensity is read for, from a region name or a postcode,
+/// which can't both be set. England when neither is.
+fn location(region: Option<&str>, postcode: Option<&str>) -> Result<Location, String> {
+    match (region, postcode) {
+        (Some(_), Some(_)) => Err("set either region or postcode, not both".to_string()),
+        (None, Some(postcode)) => carbon_intensity::outward_code(postcode).map(Location::Postcode),
+        (region, None) => {
+            let region = region.unwrap_or("England");
+            carbon_intensity::region_by_name(region)
+                .map(Location::Region)
+                .ok_or_else(|| format!("unknown region '{region}', expected a name such as England, London or North Scotland"))
+        }
+    }
+}
+
+/// Where readings are cached, in a directory or the database, which can't both be set.
+fn cache_location(dir: Option<PathBuf>, database: Option<PathBuf>) -> Result<Option<CacheLocation>, String> {
+    match (dir, database) {
+        (Some(_), Some(_)) => Err("set either cache_dir or database, not both".to_string()),
+        (Some(dir), None) => Ok(Some(CacheLocation::Dir(dir))),
+        (None, Some(path)) => Ok(Some(CacheLocation::Database(path))),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Gas emission factors can be zero, for gas that's offset, but not negative.
+fn emission_factor(factor: f64) -> Result<(), String> {
+    if !(factor.is_finite() && factor >= 0.0) {
+        return Err(format!("gas emission factor must be zero or more kgCO2e/kWh, got {factor}"));
+    }
+    Ok(())
+}
+
+/// Requests have to be allowed to go out at all.
+fn request_limits(max_concurrent: usize, per_second: f64) -> Result<(), String> {
+    if max_concurrent == 0 {
+        return Err("at least 1 concurrent request is needed".to_string());
+    }
+    if !(per_second.is_finite() && per_second >= retry::MIN_PER_SECOND) {
+        return Err(format!("requests per second must be at least {}, got {per_second}", retry::MIN_PER_SECOND));
+    }
+    Ok(())
+}
+
+fn validate_label_name(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("__");
+    if !valid {
+        return Err(format!("'{name}' is not a valid Prometheus label name"));
+    }
+    if RESERVED_LABELS.contains(&name) {
+        return Err(format!("label '{name}' is set by the exporter and can't be overridden"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn test_parse_accounts_and_meters() {
+        let config = parse(r#"
+            [[account]]
+            number = "A-1234ABCD"
+            labels = { property = "flat" }
+
+            [[account]]
+            api_key = "sk_test"
+            labels = { property = "cottage" }
+
+            [[account.meter]]
+            fuel = "gas"
+            meter_point = "3000000009"
+            serial_number = "G4P0000009"
+            tariff = "G-1R-VAR-22-11-01-C"
+            labels = { floor = "ground" }
+
+            [[account.meter]]
+            fuel = "gas"
+            meter_point = "3000000010"
+            serial_number = "E6S0000010"
+            meter_type = "smets2"
+            calorific_value = 39.2
+
+            [[account.meter]]
+            fuel = "electricity"
+            export = true
+            meter_point = "1400000000009"
+            serial_number = "21E0000009"
+        "#).unwrap();
+
+        assert_eq!(config.accounts.len(), 2);
+        assert_eq!(config.accounts[0].number.as_deref(), Some("A-1234ABCD"));
+        assert!(config.accounts[0].meters.is_empty());
+        assert_eq!(config.accounts[1].api_key.as_deref(), Some("sk_test"));
+        assert_eq!(config.accounts[1].meters[0].fuel, Fuel::Gas);
+        assert!(!config.accounts[1].meters[0].export);
+        assert_eq!(config.accounts[1].meters[1].meter_type, Some(MeterType::Smets2));
+        assert_eq!(config.accounts[1].meters[1].calorific_value, Some(39.2));
+        assert!(config.accounts[1].meters[2].export);
+        assert_eq!(config.label_names(), ["floor", "property"]);
+    }
+
+    #[test]
+    fn test_flags_win_over_file_over_defaults() {
+        let config = parse(r#"
+            api_key = "sk_file"
+            region = "London"
+            windows = ["2d", "mtd"]
+            interval = 900
+            listen = "0.0.0.0:9100"
+            gas_tariff = "G-1R-VAR-22-11-01-C"
+
+            [gas]
+            unit = "m3"
+            calorific_value = 39.2
+
+            [[account]]
+            number = "A-1234ABCD"
+        "#).unwrap();
+        let overrides = Overrides {
+            interval: Some(60),
+            calorific_value: Some(40.1),
+            ..Default::default()
+        };
+        let settings = Settings::resolve(config, overrides).unwrap();
+
+        assert_eq!(settings.interval, 60);
+        assert_eq!(settings.location, Location::Region(carbonintensity::Region::London));
+        assert_eq!(settings.windows.iter().map(Window::label).collect::<Vec<_>>(), ["2d", "mtd"]);
+        assert_eq!(settings.listen, "0.0.0.0:9100".parse().unwrap());
+        assert_eq!(settings.web, WebConfig::default());
+        assert_eq!(settings.gas.unit, GasUnit::CubicMetres);
+        assert_eq!(settings.gas.calorific_value, 40.1);
+        assert_eq!(settings.gas.volume_correction, gas::DEFAULT_VOLUME_CORRECTION);
+        assert_eq!(settings.gas.emission_factor, gas::DEFAULT_EMISSION_FACTOR);
+        assert_eq!(settings.requests, RetrySettings::default());
+        assert!(settings.cache.is_none());
+        assert_eq!(settings.tariffs.gas.unwrap().product_code, "VAR-22-11-01");
+        assert!(settings.tariffs.electricity.is_none());
+        assert_eq!(settings.timeout, 0);
+        assert_eq!(settings.timezone, chrono_tz::Europe::London);
+        assert_eq!(settings.lowest_carbon_windows, carbon_intensity::default_lowest_windows());
+        assert_eq!(settings.accounts[0].0, "sk_file");
+
+        // Values that only make sense once layered are checked too
+        let config = || parse("api_key = \"sk\"\n[[account]]\nnumber = \"A-1\"").unwrap();
+        let overrides = Overrides { region: Some("Atlantis".to_string()), ..Default::default() };
+        assert!(Settings::resolve(config(), overrides).unwrap_err().contains("unknown region"));
+        let overrides = Overrides { postcode: Some("sw1a 1aa".to_string()), ..Default::default() };
+        assert_eq!(Settings::resolve(config(), overrides).unwrap().location, Location::Postcode("SW1A".to_string()));
+        let overrides = Overrides { interval: Some(0), ..Default::default() };
+        assert!(Settings::resolve(config(), overrides).is_err());
+        let overrides = Overrides { cache_dir: Some(PathBuf::from("/var/cache/octopus")), ..Default::default() };
+        assert_eq!(
+            Settings::resolve(config(), overrides).unwrap().cache,
+            Some(CacheSettings {
+                location: CacheLocation::Dir(PathBuf::from("/var/cache/octopus")),
+                recheck_hours: cache::DEFAULT_RECHECK_HOURS,
+            }),
+        );
+        // A database flag wins over a cache directory in the file
+        let in_dir = || parse("api_key = \"sk\"\ncache_dir = \"/var/cache/octopus\"\n[[account]]\nnumber = \"A-1\"").unwrap();
+        let overrides = Overrides { database: Some(PathBuf::from("history.db")), ..Default::default() };
+        let cache = Settings::resolve(in_dir(), overrides).unwrap().cache.unwrap();
+        assert_eq!(cache.location, CacheLocation::Database(PathBuf::from("history.db")));
+        let without_key = parse("[[account]]\nnumber = \"A-1\"").unwrap();
+        assert!(Settings::resolve(without_key, Overrides::default()).unwrap_err().contains("no API key"));
+    }
+
+    #[test]
+    fn test_load_yaml_and_key_files() {
+        let dir = env::temp_dir().join(format!("octopus-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key_path = dir.join("api_key");
+        fs::write(&key_path, "sk_secret\n").unwrap();
+        let config_path = dir.join("octopus.yaml");
+        fs::write(&config_path, format!(
+            "api_key_file: {}\ntimezone: America/New_York\naccount:\n  - number: A-1234ABCD\n    labels:\n      property: flat\n  - number: A-5678EFGH\n    api_key: sk_own\n",
+            key_path.display(),
+        )).unwrap();
+
+        let config = Config::load(&config_path).unwrap();
+        assert_eq!(config.label_names(), ["property"]);
+        let settings = Settings::resolve(config, Overrides::default()).unwrap();
+        assert_eq!(settings.timezone, chrono_tz::America::New_York);
+        assert_eq!(settings.accounts[0].0, "sk_secret");
+        assert_eq!(settings.accounts[1].0, "sk_own");
+
+        fs::write(&config_path, "account: [{number: A-1, colour: red}]").unwrap();
+        assert!(Config::load(&config_path).unwrap_err().contains("unknown field"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_configs_rejected() {
+        let invalid = [
+            "",
+            "[[account]]\nlabels = { property = \"flat\" }",
+            "[[account]]\n[[account.meter]]\nfuel = \"electricity\"\nmeter_point = \"1200000000001\"",
+            "[[account]]\nnumber = \"A-1\"\n[[account.meter]]\nfuel = \"gas\"\ntariff = \"E-1R-VAR-22-11-01-C\"",
+            "[[account]]\nnumber = \"A-1\"\nlabels = { fuel = \"x\" }",
+            "[[account]]\nnumber = \"A-1\"\nlabels = { fuel_type = \"x\" }",
+            "[[account]]\nnumber = \"A-1\"\nlabels = { \"1st\" = \"x\" }",
+            "[[account]]\nnumber = \"A-1\"\nregion = \"London\"",
+            "[[account]]\nnumber = \"A-1\"\n[[account.meter]]\nfuel = \"water\"",
+            "[[account]]\nnumber = \"A-1\"\n[[account.meter]]\nfuel = \"gas\"\nexport = true",
+            "[[account]]\nnumber = \"A-1\"\n[[account.meter]]\nfuel = \"electricity\"\nunit = \"m3\"",
+            "[[account]]\nnumber = \"A-1\"\n[[account.meter]]\nfuel = \"gas\"\nunit = \"kwh\"\nmeter_type = \"smets2\"",
+            "[[account]]\nnumber = \"A-1\"\n[[account.meter]]\nfuel = \"gas\"\ncalorific_value = -39.5",
+            "windows = [\"fortnight\"]\n[[account]]\nnumber = \"A-1\"",
+            "lowest_carbon_windows = [\"45min\"]\n[[account]]\nnumber = \"A-1\"",
+            "listen = \"localhost\"\n[[account]]\nnumber = \"A-1\"",
+            "electricity_tariff = \"G-1R-VAR-22-11-01-C\"\n[[account]]\nnumber = \"A-1\"",
+            "api_key = \"sk\"\napi_key_file = \"/run/secrets/key\"\n[[account]]\nnumber = \"A-1\"",
+            "[gas]\nvolume_correction = 0\n[[account]]\nnumber = \"A-1\"",
+            "[gas]\nemission_factor = -0.2\n[[account]]\nnumber = \"A-1\"",
+            "[requests]\nmax_concurrent = 0\n[[account]]\nnumber = \"A-1\"",
+            "[requests]\nper_second = 0.0\n[[account]]\nnumber = \"A-1\"",
+            "[requests]\nper_second = 1e-20\n[[account]]\nnumber = \"A-1\"",
+            "region = \"London\"\npostcode = \"SW1A\"\n[[account]]\nnumber = \"A-1\"",
+            "cache_dir = \"cache\"\ndatabase = \"history.db\"\n[[account]]\nnumber = \"A-1\"",
+        ];
+        for text in invalid {
+            assert!(parse(text).is_err(), "{text:?} should be rejected");
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::{fmt, time::Duration};
+
+/// Everything that can go wrong loading settings or polling, by where it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// Settings that can't be used, e.g. no meters to poll.
+    Config(String),
+    /// A request to the Octopus Energy API that failed, with the HTTP status
+    /// when one came back and how long its `Retry-After` asked us to wait.
+    Octopus { status: Option<u16>, retry_after: Option<Duration>, message: String },
+    /// A request to the Carbon Intensity API that failed, see `Octopus`.
+    CarbonIntensity { status: Option<u16>, retry_after: Option<Duration>, message: String },
+    /// A response that came back but couldn't be understood.
+    Parse(String),
+    /// Responses that parsed but don't add up, such as pages of readings
+    /// that don't match the count the API gave.
+    Inconsistent(String),
+    /// The history database couldn't be opened, read or written.
+    Database(String),
+}
+
+impl Error {
+    pub fn octopus(message: impl fmt::Display) -> Self {
+        Error::Octopus { status: None, retry_after: None, message: message.to_string() }
+    }
+
+    pub fn carbon_intensity(message: impl fmt::Display) -> Self {
+        Error::CarbonIntensity { status: None, retry_after: None, message: message.to_string() }
+    }
+
+    /// HTTP status of a failed request, when one came back.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Error::Octopus { status, .. } | Error::CarbonIntensity { status, .. } => *status,
+            _ => None,
+        }
+    }
+
+    /// How long the API asked us to wait before trying again.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::Octopus { retry_after, .. } | Error::CarbonIntensity { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Whether the same request might succeed if tried again: it never got a
+    /// response, was throttled, or hit a server error.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Octopus { status, .. } | Error::CarbonIntensity { status, .. } => {
+                status.is_none_or(|status| status == 429 || status >= 500)
+            }
+            Error::Config(_) | Error::Parse(_) | Error::Inconsistent(_) | Error::Database(_) => false,
+        }
+    }
+
+    /// Used for the `kind` metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Config(_) => "config",
+            Error::Octopus { .. } => "octopus_api",
+            Error::CarbonIntensity { .. } => "carbon_intensity_api",
+            Error::Parse(_) => "parse",
+            Error::Inconsistent(_) => "inconsistent",
+            Error::Database(_) => "database",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(message) => f.write_str(message),
+            Error::Octopus { message, .. } => write!(f, "Octopus API request failed: {message}"),
+            Error::CarbonIntensity { message, .. } => write!(f, "Carbon Intensity API request failed: {message}"),
+            Error::Parse(message) => write!(f, "can't parse response: {message}"),
+            Error::Inconsistent(message) => write!(f, "inconsistent response: {message}"),
+            Error::Database(message) => write!(f, "database error: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// An error hit during a poll, with the account and window it cost figures
+/// of, where it's tied to one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    /// Account number, `None` for hand-configured meters and failures that
+    /// aren't any one account's, like the carbon intensity forecast.
+    pub source: Option<String>,
+    pub window: Option<String>,
+    pub error: Error,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(source) = &self.source {
+            write!(f, "account {source}: ")?;
+        }
+        if let Some(window) = &self.window {
+            write!(f, "window {window}: ")?;
+        }
+        write!(f, "{}", self.error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kind_and_message() {
+        let error = Error::Octopus { status: Some(502), retry_after: None, message: "error status 502 Bad Gateway".to_string() };
+        assert_eq!(error.kind(), "octopus_api");
+        assert!(error.is_transient());
+        assert!(Error::octopus("connection reset").is_transient());
+        assert!(!Error::CarbonIntensity { status: Some(400), retry_after: None, message: String::new() }.is_transient());
+        assert_eq!(error.to_string(), "Octopus API request failed: error status 502 Bad Gateway");
+        assert_eq!(Error::carbon_intensity("timed out").to_string(), "Carbon Intensity API request failed: timed out");
+        assert_eq!(Error::Parse("bad date".to_string()).kind(), "parse");
+
+        let failure = Failure { source: Some("A-1234ABCD".to_string()), window: Some("1w".to_string()), error };
+        assert_eq!(failure.to_string(), "account A-1234ABCD: window 1w: Octopus API request failed: error status 502 Bad Gateway");
+    }
+}
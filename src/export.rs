@@ -0,0 +1,292 @@
+use std::{io::{self, Write}, str::FromStr};
+
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, Months, Timelike, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+
+use crate::carbon_intensity::Intensities;
+use crate::gas::GasSettings;
+use crate::meter::Meter;
+use crate::tariff::{self, TariffRates};
+use crate::usage::{Fuel, Reading};
+use crate::window;
+
+/// How many half-hour slots each exported row adds up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Granularity {
+    #[default]
+    HalfHour,
+    Hour,
+    Day,
+    /// Starting Monday.
+    Week,
+    Month,
+}
+
+impl FromStr for Granularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "half-hour" | "halfhour" | "30m" => Ok(Granularity::HalfHour),
+            "hour" => Ok(Granularity::Hour),
+            "day" => Ok(Granularity::Day),
+            "week" => Ok(Granularity::Week),
+            "month" => Ok(Granularity::Month),
+            _ => Err(format!("invalid granularity '{s}', expected half-hour, hour, day, week or month")),
+        }
+    }
+}
+
+impl Granularity {
+    /// Start and end of the row the slot starting at `at` falls in. Days,
+    /// weeks and months start at local midnight in `tz`.
+    fn bucket(self, at: DateTime<Utc>, tz: Tz) -> (DateTime<Utc>, DateTime<Utc>) {
+        let local = at.with_timezone(&tz);
+        let date = local.date_naive();
+        match self {
+            Granularity::HalfHour => (at, at + ChronoDuration::minutes(30)),
+            Granularity::Hour => {
+                let start = at - ChronoDuration::minutes(local.minute() as i64) - ChronoDuration::seconds(local.second() as i64);
+                (start, start + ChronoDuration::hours(1))
+            }
+            Granularity::Day => (window::local_midnight(tz, date), window::local_midnight(tz, date + ChronoDuration::days(1))),
+            Granularity::Week => {
+                let monday = date - ChronoDuration::days(date.weekday().num_days_from_monday() as i64);
+                (window::local_midnight(tz, monday), window::local_midnight(tz, monday + ChronoDuration::days(7)))
+            }
+            Granularity::Month => {
+                let first = date.with_day(1).unwrap_or(date);
+                let next = first.checked_add_months(Months::new(1)).unwrap_or(first);
+                (window::local_midnight(tz, first), window::local_midnight(tz, next))
+            }
+        }
+    }
+}
+
+/// How exported rows are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Csv,
+    /// One array of every row.
+    Json,
+    /// A JSON object per line.
+    Ndjson,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            "ndjson" | "jsonl" => Ok(Format::Ndjson),
+            _ => Err(format!("invalid format '{s}', expected csv, json or ndjson")),
+        }
+    }
+}
+
+/// Usage, cost and carbon of one meter over one half-hour slot. Cost and
+/// carbon are `None` when there's no rate or intensity for the slot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Slot {
+    pub start: DateTime<Utc>,
+    pub kwh: f64,
+    pub cost_pence: Option<f64>,
+    pub carbon_grams: Option<f64>,
+}
+
+/// Every reading of `meter` as a slot, with gas converted to kWh. Each slot
+/// is priced at the unit rate it started in, plus its share of the standing
+/// charge on an import meter, like the windows in the metrics. Electricity
+/// carbon comes from the slot's regional intensity and gas from the
+/// emission factor.
+pub fn slots(
+    meter: &Meter,
+    readings: &[Reading],
+    rates: Option<&TariffRates>,
+    intensities: Option<&Intensities>,
+    gas: &GasSettings,
+) -> Vec<Slot> {
+    readings
+        .iter()
+        .map(|reading| {
+            let kwh = meter.gas_conversion.map_or(reading.kwh, |conversion| conversion.kwh(reading.kwh));
+            let cost_pence = rates.and_then(|rates| {
+                let energy = tariff::rate_at(&rates.unit_rates, reading.start)? * kwh;
+                let end = reading.start + ChronoDuration::minutes(30);
+                Some(if meter.export { energy } else { energy + rates.standing_charge_pence(reading.start, end) })
+            });
+            let carbon_grams = match meter.fuel {
+                Fuel::Electricity => intensities.and_then(|intensities| intensities.get(&reading.start)).map(|grams| kwh * grams),
+                Fuel::Gas => Some(gas.carbon_grams(kwh)),
+            };
+            Slot { start: reading.start, kwh, cost_pence, carbon_grams }
+        })
+        .collect()
+}
+
+/// One exported row: a meter's figures over a half hour, or the slots of a
+/// longer period added up. Times are RFC 3339 in the configured timezone.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Row {
+    pub fuel: &'static str,
+    pub meter_point: String,
+    pub serial_number: String,
+    pub export: bool,
+    pub start: String,
+    pub end: String,
+    pub kwh: f64,
+    /// `None` when any slot of the row couldn't be priced.
+    pub cost_pence: Option<f64>,
+    /// `None` when any slot of the row has no carbon.
+    pub carbon_grams: Option<f64>,
+}
+
+/// Adds up `slots`, in order, into a row per `granularity` period.
+pub fn rows(meter: &Meter, slots: &[Slot], granularity: Granularity, tz: Tz) -> Vec<Row> {
+    let mut rows: Vec<Row> = Vec::new();
+    let mut last = None;
+    for slot in slots {
+        let bucket = granularity.bucket(slot.start, tz);
+        match rows.last_mut() {
+            Some(row) if last == Some(bucket) => {
+                row.kwh += slot.kwh;
+                row.cost_pence = row.cost_pence.zip(slot.cost_pence).map(|(a, b)| a + b);
+                row.carbon_grams = row.carbon_grams.zip(slot.carbon_grams).map(|(a, b)| a + b);
+            }
+            _ => rows.push(Row {
+                fuel: meter.fuel.as_str(),
+                meter_point: meter.meter_point.clone(),
+                serial_number: meter.serial_number.clone(),
+                export: meter.export,
+                start: bucket.0.with_timezone(&tz).to_rfc3339(),
+                end: bucket.1.with_timezone(&tz).to_rfc3339(),
+                kwh: slot.kwh,
+                cost_pence: slot.cost_pence,
+                carbon_grams: slot.carbon_grams,
+            }),
+        }
+        last = Some(bucket);
+    }
+    rows
+}
+
+/// Writes `rows` to `out` as `format`.
+pub fn write(rows: &[Row], format: Format, mut out: impl Write) -> io::Result<()> {
+    match format {
+        Format::Csv => {
+            writeln!(out, "fuel,meter_point,serial_number,export,start,end,kwh,cost_pence,carbon_grams")?;
+            for row in rows {
+                let optional = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
+                writeln!(
+                    out,
+                    "{},{},{},{},{},{},{},{},{}",
+                    row.fuel,
+                    csv_field(&row.meter_point),
+                    csv_field(&row.serial_number),
+                    row.export,
+                    row.start,
+                    row.end,
+                    row.kwh,
+                    optional(row.cost_pence),
+                    optional(row.carbon_grams),
+                )?;
+            }
+        }
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut out, rows)?;
+            writeln!(out)?;
+        }
+        Format::Ndjson => {
+            for row in rows {
+                serde_json::to_writer(&mut out, row)?;
+                writeln!(out)?;
+            }
+        }
+    }
+    out.flush()
+}
+
+/// Quotes a field that would otherwise break the CSV row.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meter::test_meter;
+    use crate::tariff::Rate;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_slots_priced_and_grouped_by_local_day() {
+        let meter = test_meter(Fuel::Electricity, "1200000000001");
+        let readings: Vec<Reading> = ["2025-06-30T22:30:00Z", "2025-06-30T23:00:00Z", "2025-06-30T23:30:00Z"]
+            .iter()
+            .map(|start| Reading { start: at(start), kwh: 0.5 })
+            .collect();
+        let rates = TariffRates::new(
+            vec![Rate { valid_from: at("2025-06-01T00:00:00Z"), valid_to: None, pence: 20.0 }],
+            vec![Rate { valid_from: at("2025-06-01T00:00:00Z"), valid_to: None, pence: 48.0 }],
+        );
+        // No intensity for the last slot
+        let intensities: Intensities = [(at("2025-06-30T22:30:00Z"), 100.0), (at("2025-06-30T23:00:00Z"), 200.0)].into();
+
+        let slots = slots(&meter, &readings, Some(&rates), Some(&intensities), &GasSettings::default());
+        assert_eq!(slots[0], Slot { start: readings[0].start, kwh: 0.5, cost_pence: Some(11.0), carbon_grams: Some(50.0) });
+        assert_eq!(slots[2].carbon_grams, None);
+
+        // 23:00 UTC is midnight in London in summer, so the first slot is the day before
+        let rows = rows(&meter, &slots, Granularity::Day, chrono_tz::Europe::London);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].start, "2025-06-30T00:00:00+01:00");
+        assert_eq!(rows[1].start, "2025-07-01T00:00:00+01:00");
+        assert_eq!(rows[1].end, "2025-07-02T00:00:00+01:00");
+        assert_eq!(rows[1].kwh, 1.0);
+        assert_eq!(rows[1].cost_pence, Some(22.0));
+        assert_eq!(rows[1].carbon_grams, None);
+
+        let rows = super::rows(&meter, &slots, Granularity::Month, chrono_tz::Europe::London);
+        assert_eq!(rows[0].end, "2025-07-01T00:00:00+01:00");
+    }
+
+    #[test]
+    fn test_write_formats() {
+        let row = Row {
+            fuel: "gas",
+            meter_point: "2000000001".to_string(),
+            serial_number: "G4,01".to_string(),
+            export: false,
+            start: "2025-01-01T00:00:00+00:00".to_string(),
+            end: "2025-01-01T00:30:00+00:00".to_string(),
+            kwh: 1.5,
+            cost_pence: Some(9.0),
+            carbon_grams: None,
+        };
+        let written = |format| {
+            let mut out = Vec::new();
+            write(std::slice::from_ref(&row), format, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let csv = written(Format::Csv);
+        assert_eq!(csv.lines().nth(1), Some("gas,2000000001,\"G4,01\",false,2025-01-01T00:00:00+00:00,2025-01-01T00:30:00+00:00,1.5,9,"));
+        let ndjson = written(Format::Ndjson);
+        let parsed: serde_json::Value = serde_json::from_str(ndjson.lines().next().unwrap()).unwrap();
+        assert_eq!(parsed["kwh"], 1.5);
+        assert!(parsed["carbon_grams"].is_null());
+        let json: serde_json::Value = serde_json::from_str(&written(Format::Json)).unwrap();
+        assert_eq!(json.as_array().map(Vec::len), Some(1));
+    }
+}
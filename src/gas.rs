@@ -0,0 +1,126 @@
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+/// Calorific value used when none is configured, in MJ/m³. Bills use the
+/// month's average for the local distribution zone, which is usually close.
+pub const DEFAULT_CALORIFIC_VALUE: f64 = 39.5;
+
+/// Correction from the metered volume to standard temperature and pressure.
+pub const DEFAULT_VOLUME_CORRECTION: f64 = 1.02264;
+
+/// Emissions of burning natural gas in kgCO2e per kWh (gross CV), from the
+/// UK government's 2024 greenhouse gas conversion factors.
+pub const DEFAULT_EMISSION_FACTOR: f64 = 0.18290;
+
+/// Unit a gas meter's consumption is published in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum GasUnit {
+    #[default]
+    #[serde(rename = "kwh")]
+    Kwh,
+    #[serde(rename = "m3")]
+    CubicMetres,
+}
+
+impl FromStr for GasUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "kwh" => Ok(GasUnit::Kwh),
+            "m3" | "m³" => Ok(GasUnit::CubicMetres),
+            _ => Err(format!("invalid gas unit '{s}', expected kwh or m3")),
+        }
+    }
+}
+
+/// Generation of smart gas meter, which decides the unit it reports in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MeterType {
+    Smets1,
+    Smets2,
+}
+
+impl MeterType {
+    pub fn unit(self) -> GasUnit {
+        match self {
+            MeterType::Smets1 => GasUnit::Kwh,
+            MeterType::Smets2 => GasUnit::CubicMetres,
+        }
+    }
+}
+
+/// Gas settings applied to every meter that doesn't set its own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GasSettings {
+    pub unit: GasUnit,
+    pub calorific_value: f64,
+    pub volume_correction: f64,
+    /// kgCO2e per kWh burned.
+    pub emission_factor: f64,
+}
+
+impl GasSettings {
+    /// Emissions in grams of burning `kwh` of gas.
+    pub fn carbon_grams(&self, kwh: f64) -> f64 {
+        kwh * self.emission_factor * 1000.0
+    }
+}
+
+impl Default for GasSettings {
+    fn default() -> Self {
+        GasSettings {
+            unit: GasUnit::Kwh,
+            calorific_value: DEFAULT_CALORIFIC_VALUE,
+            volume_correction: DEFAULT_VOLUME_CORRECTION,
+            emission_factor: DEFAULT_EMISSION_FACTOR,
+        }
+    }
+}
+
+/// Turns a metered gas volume into the energy it's billed as.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GasConversion {
+    /// MJ/m³
+    pub calorific_value: f64,
+    pub volume_correction: f64,
+}
+
+impl GasConversion {
+    /// kWh = m³ × volume correction × calorific value ÷ 3.6
+    pub fn kwh(&self, cubic_metres: f64) -> f64 {
+        cubic_metres * self.volume_correction * self.calorific_value / 3.6
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_volume_converts_like_a_bill() {
+        let conversion = GasConversion { calorific_value: 39.2, volume_correction: DEFAULT_VOLUME_CORRECTION };
+        // 100 m³ at CV 39.2 is 1113.54 kWh on an Octopus bill
+        assert!((conversion.kwh(100.0) - 1113.541).abs() < 0.001);
+        assert_eq!(conversion.kwh(0.0), 0.0);
+    }
+
+    #[test]
+    fn test_carbon_from_emission_factor() {
+        // 1000 kWh at the default factor is 182.9 kgCO2e
+        assert!((GasSettings::default().carbon_grams(1000.0) - 182_900.0).abs() < 1e-6);
+        let green = GasSettings { emission_factor: 0.0, ..Default::default() };
+        assert_eq!(green.carbon_grams(1000.0), 0.0);
+    }
+
+    #[test]
+    fn test_parse_units() {
+        assert_eq!("kWh".parse::<GasUnit>(), Ok(GasUnit::Kwh));
+        assert_eq!("m3".parse::<GasUnit>(), Ok(GasUnit::CubicMetres));
+        assert!("litres".parse::<GasUnit>().is_err());
+        assert_eq!(MeterType::Smets2.unit(), GasUnit::CubicMetres);
+        assert_eq!(MeterType::Smets1.unit(), GasUnit::Kwh);
+    }
+}
@@ -0,0 +1,122 @@
+use std::{sync::{Arc, Mutex}, time::Duration};
+
+use chrono::{DateTime, Utc};
+
+use crate::clock::Clock;
+use crate::error::Failure;
+
+/// Polls that can go by without new figures before the exporter counts as unhealthy.
+const STALE_POLLS: u32 = 3;
+
+/// What `/health` reports.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    /// The last poll read everything, or the first hasn't finished yet.
+    Ok,
+    /// The last poll updated some figures but not all, with why.
+    Degraded(Vec<String>),
+    /// Nothing has been updated for too long, so every figure is stale.
+    Unhealthy(String),
+}
+
+#[derive(Debug, Default)]
+struct State {
+    last_poll: Option<DateTime<Utc>>,
+    last_success: Option<DateTime<Utc>>,
+    failures: Vec<String>,
+}
+
+/// Outcome of recent polls, shared between the poller and `/health`.
+pub struct Health {
+    clock: Arc<dyn Clock>,
+    started: DateTime<Utc>,
+    /// How long since the last poll that updated anything counts as stale.
+    stale_after: chrono::Duration,
+    state: Mutex<State>,
+}
+
+impl Health {
+    pub fn new(clock: Arc<dyn Clock>, interval: Duration) -> Self {
+        let interval = chrono::Duration::from_std(interval).unwrap_or(chrono::Duration::MAX);
+        Health {
+            started: clock.now(),
+            clock,
+            stale_after: interval.checked_mul(STALE_POLLS as i32).unwrap_or(chrono::Duration::MAX),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Records a finished poll, which `succeeded` when it updated any figure.
+    pub fn record(&self, succeeded: bool, failures: &[Failure]) {
+        let now = self.clock.now();
+        if let Ok(mut state) = self.state.lock() {
+            state.last_poll = Some(now);
+            if succeeded {
+                state.last_success = Some(now);
+            }
+            state.failures = failures.iter().map(Failure::to_string).collect();
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let now = self.clock.now();
+        let Ok(state) = self.state.lock() else {
+            return Status::Unhealthy("health state is poisoned".to_string());
+        };
+
+        let since = state.last_success.unwrap_or(self.started);
+        if now - since > self.stale_after {
+            return match state.last_success {
+                Some(at) => Status::Unhealthy(format!("no figures updated since {}", at.to_rfc3339())),
+                None if state.last_poll.is_none() => Status::Unhealthy("no poll has finished yet".to_string()),
+                None => Status::Unhealthy("no poll has updated any figures yet".to_string()),
+            };
+        }
+        if state.failures.is_empty() {
+            Status::Ok
+        } else {
+            Status::Degraded(state.failures.clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FakeClock;
+    use crate::error::Error;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_status_follows_polls() {
+        let clock = Arc::new(FakeClock::new(at("2025-08-01T12:00:00Z")));
+        let health = Health::new(clock.clone(), Duration::from_secs(600));
+        assert_eq!(health.status(), Status::Ok);
+
+        let failure = Failure { source: None, window: Some("1y".to_string()), error: Error::octopus("timed out") };
+        health.record(true, std::slice::from_ref(&failure));
+        assert_eq!(health.status(), Status::Degraded(vec![failure.to_string()]));
+        health.record(true, &[]);
+        assert_eq!(health.status(), Status::Ok);
+
+        // Polls that keep failing leave the last good figures to go stale
+        clock.advance(chrono::Duration::minutes(20));
+        health.record(false, &[failure]);
+        assert!(matches!(health.status(), Status::Degraded(_)));
+        clock.advance(chrono::Duration::minutes(11));
+        assert_eq!(health.status(), Status::Unhealthy("no figures updated since 2025-08-01T12:00:00+00:00".to_string()));
+        health.record(true, &[]);
+        assert_eq!(health.status(), Status::Ok);
+    }
+
+    #[test]
+    fn test_unhealthy_when_first_poll_never_finishes() {
+        let clock = Arc::new(FakeClock::new(at("2025-08-01T12:00:00Z")));
+        let health = Health::new(clock.clone(), Duration::from_secs(60));
+        clock.advance(chrono::Duration::minutes(4));
+        assert_eq!(health.status(), Status::Unhealthy("no poll has finished yet".to_string()));
+    }
+}
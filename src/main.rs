@@ -1,16 +1,42 @@
-use prometheus::{Encoder, TextEncoder, IntCounter, Gauge, Registry};
-use std::{env, time::Duration};
+use std::{fs::File, io::{self, BufWriter}, path::{Path, PathBuf}, time::Duration};
 use std::sync::Arc;
 use tokio::time;
-use warp::Filter;
-use chrono::{DateTime, Datelike, Duration as ChronoDuration, Timelike, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
 use clap::{Parser, Subcommand};
-use std::collections::HashMap;
 use log::{info, error};
 
 mod usage;
 mod carbon_intensity;
-use octopust::Client;
+mod metrics;
+mod window;
+mod clock;
+mod poller;
+mod octopus;
+mod account;
+mod cache;
+mod tariff;
+mod meter;
+mod config;
+mod gas;
+mod web;
+mod error;
+mod health;
+mod retry;
+mod store;
+mod export;
+mod report;
+use cache::{CacheLocation, CacheSettings, ReadingCache};
+use clock::{Clock, SystemClock};
+use poller::{Poller, Source};
+use report::Report;
+use config::{Config, Overrides, Settings};
+use error::Error;
+use export::{Format, Granularity};
+use health::Health;
+use metrics::Metrics;
+use retry::Retrier;
+use web::Auth;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -23,68 +49,146 @@ struct Cli {
 enum Commands {
     /// Run the Octopus Energy Prometheus exporter
     Run {
-        /// Timeout in seconds for the exporter
-        #[arg(short, long, default_value = "0")]
-        timeout: u64,
+        #[command(flatten)]
+        overrides: Overrides,
+
+        /// TOML or YAML file with the settings, accounts and meters to poll
+        /// [default: one account from OCTOPUS_ACCOUNT_NUMBER, MPAN, E_SERIAL_NO, MPRN and G_SERIAL_NO]
+        #[arg(short, long, env = "OCTOPUS_EXPORTER_CONFIG")]
+        config: Option<PathBuf>,
+
+        /// Forget the cached readings and fetch every window in full on the first poll
+        #[arg(long)]
+        rebuild_cache: bool,
+    },
+    /// Fetch every meter's readings since a date into the database, with the
+    /// carbon intensity and tariff rates of the same slots
+    Backfill {
+        #[command(flatten)]
+        overrides: Overrides,
+
+        /// TOML or YAML file with the settings, accounts and meters to backfill
+        #[arg(short, long, env = "OCTOPUS_EXPORTER_CONFIG")]
+        config: Option<PathBuf>,
+
+        /// First day to fetch, in the configured timezone
+        #[arg(long)]
+        from: NaiveDate,
+
+        /// Last day to fetch, included [default: now]
+        #[arg(long)]
+        to: Option<NaiveDate>,
+    },
+    /// Write the consumption, cost and carbon of every meter over a span of
+    /// days as CSV or JSON
+    Export {
+        #[command(flatten)]
+        overrides: Overrides,
+
+        /// TOML or YAML file with the settings, accounts and meters to export
+        #[arg(short, long, env = "OCTOPUS_EXPORTER_CONFIG")]
+        config: Option<PathBuf>,
+
+        /// Only export meters of this fuel, electricity or gas [default: both]
+        #[arg(long)]
+        fuel: Option<usage::Fuel>,
+
+        /// First day to export, in the configured timezone
+        #[arg(long)]
+        from: NaiveDate,
+
+        /// Last day to export, included [default: now]
+        #[arg(long)]
+        to: Option<NaiveDate>,
+
+        /// Period each row adds up: half-hour, hour, day, week or month
+        #[arg(long, default_value = "half-hour")]
+        granularity: Granularity,
+
+        /// csv, json (one array) or ndjson (an object per line)
+        #[arg(long, default_value = "csv")]
+        format: Format,
+
+        /// File to write to [default: stdout]
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Poll once, print every window's usage, cost and carbon, and exit,
+    /// non-zero if anything couldn't be read
+    Summary {
+        #[command(flatten)]
+        overrides: Overrides,
+
+        /// TOML or YAML file with the settings, accounts and meters to poll
+        #[arg(short, long, env = "OCTOPUS_EXPORTER_CONFIG")]
+        config: Option<PathBuf>,
+
+        /// Print JSON rather than a table
+        #[arg(long)]
+        json: bool,
+    },
+}
 
-        /// Interval in seconds between metric updates
-        #[arg(short, long, default_value = "1800")]
-        interval: u64,
+/// Settings from the config file, environment and flags, in rising precedence.
+fn load_settings(config: Option<&Path>, overrides: Overrides) -> Result<Settings, Error> {
+    let config = match config {
+        Some(path) => Config::load(path).map_err(Error::Config)?,
+        None => Config::default(),
+    };
+    Settings::resolve(config.with_env(), overrides).map_err(Error::Config)
+}
 
-        /// Region to get carbon intensity data from
-        #[arg(short, long, default_value = "England")]
-        region: String,
+/// The span `backfill` and `export` cover: from local midnight on `from` to
+/// the end of `to`, or up to `now`.
+fn day_span(from: NaiveDate, to: Option<NaiveDate>, tz: Tz, now: DateTime<Utc>) -> Result<(DateTime<Utc>, DateTime<Utc>), Error> {
+    let start = window::local_midnight(tz, from);
+    let end = match to {
+        Some(to) => window::local_midnight(tz, to.succ_opt().unwrap_or(to)).min(now),
+        None => now,
+    };
+    if start >= end {
+        let to = to.map_or("now".to_string(), |to| to.to_string());
+        return Err(Error::Config(format!("--from {from} must be before --to {to}")));
     }
+    Ok((start, end))
 }
 
-// Using years directly
-fn get_year_range(years_back: i32) -> (DateTime<Utc>, DateTime<Utc>) {
-    let end = Utc::now();
-    
-    let start = end
-        .with_year(end.year() - years_back).unwrap()
-        .with_day(1).unwrap()
-        .with_hour(0).unwrap()
-        .with_minute(0).unwrap()
-        .with_second(0).unwrap()
-        .with_nanosecond(0).unwrap();
-
-    (start, end)
+/// Metrics the poller records into, though only `run` serves them.
+fn register_metrics(settings: &Settings) -> Arc<Metrics> {
+    match Metrics::new(settings.legacy_metrics, &settings.label_names) {
+        Ok(metrics) => Arc::new(metrics),
+        Err(e) => {
+            error!("Failed to register metrics: {e}");
+            std::process::exit(1);
+        }
+    }
 }
 
-fn get_month_range(months_back: i32) -> (DateTime<Utc>, DateTime<Utc>) {
-    let end = Utc::now();
-    
-    if months_back == 0 {
-        // Current month
-        let start = end
-            .with_day(1).unwrap()
-            .with_hour(0).unwrap()
-            .with_minute(0).unwrap()
-            .with_second(0).unwrap()
-            .with_nanosecond(0).unwrap();
-        return (start, end);
-    }
-    
-    // Previous months
-    let mut year = end.year();
-    let mut month = end.month() as i32 - months_back;
-    
-    while month <= 0 {
-        year -= 1;
-        month += 12;
+/// The poller of every configured account, its clients sharing one
+/// retrier so its limits cover all of them.
+fn build_poller(settings: &Settings, metrics: &Arc<Metrics>, clock: Arc<SystemClock>, cache: Option<ReadingCache>) -> Poller {
+    let retrier = Arc::new(Retrier::new(settings.requests.clone()).with_metrics(metrics));
+    let sources = settings
+        .accounts
+        .iter()
+        .map(|(api_key, account)| Source::new(api_key, account.clone()).with_retrier(Arc::clone(&retrier)))
+        .collect();
+    let poller = Poller::new(
+        sources,
+        clock,
+        settings.windows.clone(),
+        settings.timezone,
+        settings.location.clone(),
+        Arc::clone(metrics),
+    )
+    .with_tariffs(settings.tariffs.clone())
+    .with_gas(settings.gas)
+    .with_lowest_windows(settings.lowest_carbon_windows.clone())
+    .with_retrier(retrier);
+    match cache {
+        Some(cache) => poller.with_cache(cache),
+        None => poller,
     }
-    
-    let start = end
-        .with_year(year).unwrap()
-        .with_month(month as u32).unwrap()
-        .with_day(1).unwrap()
-        .with_hour(0).unwrap()
-        .with_minute(0).unwrap()
-        .with_second(0).unwrap()
-        .with_nanosecond(0).unwrap();
-
-    (start, end)
 }
 
 #[tokio::main]
@@ -94,302 +198,162 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Cli::parse();
 
     match args.command {
-        Commands::Run { timeout, interval, region } => {
-            info!("Starting Octopus Energy Prometheus exporter with timeout: {timeout} seconds");
-            
-            // Verify API key is set
-            if env::var("OCTOPUS_API_KEY").is_err() {
-                error!("OCTOPUS_API_KEY environment variable must be set");
+        Commands::Run { overrides, config, rebuild_cache } => {
+            let settings = load_settings(config.as_deref(), overrides).unwrap_or_else(|e| {
+                error!("{e}");
+                std::process::exit(1);
+            });
+            let cache = settings.cache.as_ref().map(ReadingCache::open).transpose().unwrap_or_else(|e| {
+                error!("Failed to open the reading cache: {e}");
                 std::process::exit(1);
+            });
+            match (&cache, rebuild_cache) {
+                (Some(cache), true) => {
+                    if let Err(e) = cache.clear() {
+                        error!("Failed to clear the reading cache: {e}");
+                        std::process::exit(1);
+                    }
+                    info!("Cleared the reading cache, every window will be fetched in full");
+                }
+                (None, true) => {
+                    error!("--rebuild-cache needs a cache, set with --cache-dir or --database");
+                    std::process::exit(1);
+                }
+                _ => {}
             }
-
-            let mut group_by_opts = HashMap::new();
-            let mut periods: HashMap<String, DateTime<Utc>> = HashMap::new();
-            let now = Utc::now();
-
-            // Current month
-            let (start_current, end_current) = get_month_range(0);
-
-            // Last 2 months
-            let (start_2m, end_2m) = get_month_range(2);
-
-            // Last 3 months
-            let (start_3m, end_3m) = get_month_range(3);
-
-            // Last 6 months
-            let (start_6m, end_6m) = get_month_range(6);
-
-            // Last 1 year
-            let (start_1y_direct, end_1y_direct) = get_year_range(1);
-
-            group_by_opts.insert(String::from("hour"), "hour");
-
-            periods.insert("2d".to_string(), now - ChronoDuration::days(2));
-            periods.insert("1w".to_string(), now - ChronoDuration::weeks(1));
-            periods.insert("2w".to_string(), now - ChronoDuration::weeks(2));
-            periods.insert("4w".to_string(), now - ChronoDuration::weeks(4));
-            periods.insert("1m".to_string(), now - ChronoDuration::days(end_current.signed_duration_since(start_current).num_days()));
-            periods.insert("2m".to_string(), now - ChronoDuration::days(end_2m.signed_duration_since(start_2m).num_days()));
-            periods.insert("3m".to_string(), now - ChronoDuration::days(end_3m.signed_duration_since(start_3m).num_days()));
-            periods.insert("6m".to_string(), now - ChronoDuration::days(end_6m.signed_duration_since(start_6m).num_days()));
-            periods.insert("1y".to_string(), now - ChronoDuration::days(end_1y_direct.signed_duration_since(start_1y_direct).num_days()));
+            let timeout = settings.timeout;
+            info!("Starting Octopus Energy Prometheus exporter with timeout: {timeout} seconds");
 
             // Create Prometheus registry and metrics
-            let registry = Registry::new();
-
-            let electricity_usage_gauge_two_weeks = Gauge::new("octopus_electricity_usage_2w_kwh", "Total Octopus Energy electricity usage for last 2 weeks in kWh").unwrap();
-            registry.register(Box::new(electricity_usage_gauge_two_weeks.clone())).unwrap();
-
-            let electricity_usage_gauge_four_weeks = Gauge::new("octopus_electricity_usage_4w_kwh", "Total Octopus Energy electricity usage for last 4 weeks in kWh").unwrap();
-            registry.register(Box::new(electricity_usage_gauge_four_weeks.clone())).unwrap();
-
-            let electricity_usage_gauge_two_days = Gauge::new("octopus_electricity_usage_two_days_kwh", "Total Octopus Energy electricity usage for two days in kWh").unwrap();
-            registry.register(Box::new(electricity_usage_gauge_two_days.clone())).unwrap();
-
-            let electricity_usage_gauge_current_month = Gauge::new("octopus_electricity_usage_current_month_kwh", "Total Octopus Energy electricity usage for current month in kWh").unwrap();
-            registry.register(Box::new(electricity_usage_gauge_current_month.clone())).unwrap();
-
-            let electricity_usage_gauge_last_2_months = Gauge::new("octopus_electricity_usage_last_2_months_kwh", "Total Octopus Energy electricity usage for the last two months in kWh").unwrap();
-            registry.register(Box::new(electricity_usage_gauge_last_2_months.clone())).unwrap();
-
-            let electricity_usage_gauge_last_3_months = Gauge::new("octopus_electricity_usage_last_3_months_kwh", "Total Octopus Energy electricity usage for the last three months in kWh").unwrap();
-            registry.register(Box::new(electricity_usage_gauge_last_3_months.clone())).unwrap();
-            
-            let electricity_usage_gauge_last_6_months = Gauge::new("octopus_electricity_usage_last_6_months_kwh", "Total Octopus Energy electricity usage for the last six months in kWh").unwrap();
-            registry.register(Box::new(electricity_usage_gauge_last_6_months.clone())).unwrap();
-            
-            let electricity_usage_gauge_last_1_year = Gauge::new("octopus_electricity_usage_last_1_year_kwh", "Total Octopus Energy electricity usage for the last 1 year in kWh").unwrap();
-            registry.register(Box::new(electricity_usage_gauge_last_1_year.clone())).unwrap();
-
-            let electricity_usage_gauge_week = Gauge::new("octopus_electricity_usage_week_kwh", "Total Octopus Energy electricity usage on weekly basis in kWh").unwrap();
-            registry.register(Box::new(electricity_usage_gauge_week.clone())).unwrap();
-
-            // gas usage 
-            let gas_usage_gauge_two_weeks = Gauge::new("octopus_gas_usage_2w_kwh", "Total Octopus Energy gas usage for last 2 weeks in kWh").unwrap();
-            registry.register(Box::new(gas_usage_gauge_two_weeks.clone())).unwrap();
-
-            let gas_usage_gauge_four_weeks = Gauge::new("octopus_gas_usage_4w_kwh", "Total Octopus Energy gas usage for last 4 weeks in kWh").unwrap();
-            registry.register(Box::new(gas_usage_gauge_four_weeks.clone())).unwrap();
-
-            let gas_usage_gauge_two_days = Gauge::new("octopus_gas_usage_two_days_kwh", "Total Octopus Energy gas usage for two days in kWh").unwrap();
-            registry.register(Box::new(gas_usage_gauge_two_days.clone())).unwrap();
-
-            let gas_usage_gauge_current_month = Gauge::new("octopus_gas_usage_current_month_kwh", "Total Octopus Energy gas usage for current month in kWh").unwrap();
-            registry.register(Box::new(gas_usage_gauge_current_month.clone())).unwrap();
-
-            let gas_usage_gauge_last_2_months = Gauge::new("octopus_gas_usage_last_2_months_kwh", "Total Octopus Energy gas usage for the last two months in kWh").unwrap();
-            registry.register(Box::new(gas_usage_gauge_last_2_months.clone())).unwrap();
-
-            let gas_usage_gauge_last_3_months = Gauge::new("octopus_gas_usage_last_3_months_kwh", "Total Octopus Energy gas usage for the last three months in kWh").unwrap();
-            registry.register(Box::new(gas_usage_gauge_last_3_months.clone())).unwrap();
-            
-            let gas_usage_gauge_last_6_months = Gauge::new("octopus_gas_usage_last_6_months_kwh", "Total Octopus Energy gas usage for the last six months in kWh").unwrap();
-            registry.register(Box::new(gas_usage_gauge_last_6_months.clone())).unwrap();
-            
-            let gas_usage_gauge_last_1_year = Gauge::new("octopus_gas_usage_last_1_year_kwh", "Total Octopus Energy gas usage for the last 1 year in kWh").unwrap();
-            registry.register(Box::new(gas_usage_gauge_last_1_year.clone())).unwrap();
-
-            let gas_usage_gauge_week = Gauge::new("octopus_gas_usage_week_kwh", "Total Octopus Energy gas usage on weekly basis in kWh").unwrap();
-            registry.register(Box::new(gas_usage_gauge_week.clone())).unwrap();
-
-            // carbon intensity
-            let carbon_intensity_gauge_week = Gauge::new("octopus_energy_carbon_emissions_week_grams", "Total carbon emissions on weekly basis in grams").unwrap();
-            registry.register(Box::new(carbon_intensity_gauge_week.clone())).unwrap();
-
-            let carbon_intensity_gauge_two_weeks = Gauge::new("octopus_energy_carbon_emissions_2w_grams", "Total carbon emissions for last two weeks in grams").unwrap();
-            registry.register(Box::new(carbon_intensity_gauge_two_weeks.clone())).unwrap();
-
-            let carbon_intensity_gauge_four_weeks = Gauge::new("octopus_energy_carbon_emissions_4w_grams", "Total carbon emissions for last four weeks in grams").unwrap();
-            registry.register(Box::new(carbon_intensity_gauge_four_weeks.clone())).unwrap();
-
-            let carbon_intensity_gauge_two_days = Gauge::new("octopus_energy_carbon_emissions_2d_grams", "Total carbon emissions for last two days in grams").unwrap();
-            registry.register(Box::new(carbon_intensity_gauge_two_days.clone())).unwrap();
-
-            let carbon_intensity_gauge_current_month = Gauge::new("octopus_energy_carbon_emissions_current_month_grams", "Total carbon emissions for current month in grams").unwrap();
-            registry.register(Box::new(carbon_intensity_gauge_current_month.clone())).unwrap();
-
-            let carbon_intensity_gauge_last_2_months = Gauge::new("octopus_energy_carbon_emissions_last_2_months_grams", "Total carbon emissions for the last two months in kWh").unwrap();
-            registry.register(Box::new(carbon_intensity_gauge_last_2_months.clone())).unwrap();
-            
-            let carbon_intensity_gauge_last_3_months = Gauge::new("octopus_energy_carbon_emissions_last_3_months_grams", "Total carbon emissions for the last three months in kWh").unwrap();
-            registry.register(Box::new(carbon_intensity_gauge_last_3_months.clone())).unwrap();
-            
-            let carbon_intensity_gauge_last_6_months = Gauge::new("octopus_energy_carbon_emissions_last_6_months_grams", "Total carbon emissions for the last six months in kWh").unwrap();
-            registry.register(Box::new(carbon_intensity_gauge_last_6_months.clone())).unwrap();
-            
-            let carbon_intensity_gauge_last_1_year = Gauge::new("octopus_energy_carbon_emissions_last_1_months_grams", "Total carbon emissions for the last 1 year in kWh").unwrap();
-            registry.register(Box::new(carbon_intensity_gauge_last_1_year.clone())).unwrap();
-            
-            let error_counter = IntCounter::new("octopus_energy_errors_total", "Total number of errors encountered").unwrap();
-            registry.register(Box::new(error_counter.clone())).unwrap();
-
-            let registry = Arc::new(registry);
-
-            // Create a future that will complete after the timeout (if timeout > 0)
-            let timeout_future = if timeout > 0 {
-                Some(time::sleep(Duration::from_secs(timeout)))
-            } else {
-                None
-            };
+            let metrics = register_metrics(&settings);
+
+            let clock = Arc::new(SystemClock);
+            let interval = Duration::from_secs(settings.interval);
+            let health = Arc::new(Health::new(clock.clone(), interval));
 
             // Polling task for updating metrics
             {
-                let electricity_usage_gauge_two_weeks = electricity_usage_gauge_two_weeks.clone();
-                let electricity_usage_gauge_four_weeks = electricity_usage_gauge_four_weeks.clone();
-                let electricity_usage_gauge_two_days = electricity_usage_gauge_two_days.clone();
-                let electricity_usage_gauge_week = electricity_usage_gauge_week.clone();
-                let electricity_usage_gauge_current_month = electricity_usage_gauge_current_month.clone();
-                let electricity_usage_gauge_last_2_months = electricity_usage_gauge_last_2_months.clone();
-                let electricity_usage_gauge_last_3_months = electricity_usage_gauge_last_3_months.clone();
-                let electricity_usage_gauge_last_6_months = electricity_usage_gauge_last_6_months.clone();
-                let electricity_usage_gauge_last_1_year = electricity_usage_gauge_last_1_year.clone();
-
-                let gas_usage_gauge_two_weeks = gas_usage_gauge_two_weeks.clone();
-                let gas_usage_gauge_four_weeks = gas_usage_gauge_four_weeks.clone();
-                let gas_usage_gauge_two_days = gas_usage_gauge_two_days.clone();
-                let gas_usage_gauge_week = gas_usage_gauge_week.clone();
-                let gas_usage_gauge_current_month = gas_usage_gauge_current_month.clone();
-                let gas_usage_gauge_last_2_months = gas_usage_gauge_last_2_months.clone();
-                let gas_usage_gauge_last_3_months = gas_usage_gauge_last_3_months.clone();
-                let gas_usage_gauge_last_6_months = gas_usage_gauge_last_6_months.clone();
-                let gas_usage_gauge_last_1_year = gas_usage_gauge_last_1_year.clone();
-
-                let carbon_intensity_gauge_week = carbon_intensity_gauge_week.clone();
-                let carbon_intensity_gauge_two_weeks = carbon_intensity_gauge_two_weeks.clone();
-                let carbon_intensity_gauge_four_weeks = carbon_intensity_gauge_four_weeks.clone();
-                let carbon_intensity_gauge_two_days = carbon_intensity_gauge_two_days.clone();
-                let carbon_intensity_gauge_current_month = carbon_intensity_gauge_current_month.clone();
-                let carbon_intensity_gauge_last_2_months = carbon_intensity_gauge_last_2_months.clone();
-                let carbon_intensity_gauge_last_3_months = carbon_intensity_gauge_last_3_months.clone();
-                let carbon_intensity_gauge_last_6_months = carbon_intensity_gauge_last_6_months.clone();
-                let carbon_intensity_gauge_last_1_year = carbon_intensity_gauge_last_1_year.clone();
-
-                let error_counter = error_counter.clone();
-
-                tokio::spawn(async move {
-                    let api_key = env::var("OCTOPUS_API_KEY").expect("OCTOPUS_API_KEY not set");
-                    let client = Client::new(api_key);
-
-                    loop {
-                        let now = Utc::now();
-                        
-                        match usage::fetch_electricity_and_gas_consumption(&client, &now.format("%Y-%m-%dT%H:%M:%SZ").to_string(), &periods, &group_by_opts, region.as_str()).await {
-                            Ok(summary) => {
-                                electricity_usage_gauge_two_days.set(summary.e_usage_kwh_two_days);
-                                electricity_usage_gauge_week.set(summary.e_usage_kwh_week);
-                                electricity_usage_gauge_two_weeks.set(summary.e_usage_kwh_two_weeks);
-                                electricity_usage_gauge_four_weeks.set(summary.e_usage_kwh_four_weeks);
-                                electricity_usage_gauge_current_month.set(summary.e_usage_kwh_month);
-                                electricity_usage_gauge_last_2_months.set(summary.e_usage_kwh_two_months);
-                                electricity_usage_gauge_last_3_months.set(summary.e_usage_kwh_three_months);
-                                electricity_usage_gauge_last_6_months.set(summary.e_usage_kwh_six_months);
-                                electricity_usage_gauge_last_1_year.set(summary.e_usage_kwh_year);
-
-                                gas_usage_gauge_two_days.set(summary.g_usage_kwh_two_days);
-                                gas_usage_gauge_week.set(summary.g_usage_kwh_week);
-                                gas_usage_gauge_two_weeks.set(summary.g_usage_kwh_two_weeks);
-                                gas_usage_gauge_four_weeks.set(summary.g_usage_kwh_four_weeks);
-                                gas_usage_gauge_current_month.set(summary.g_usage_kwh_month);
-                                gas_usage_gauge_last_2_months.set(summary.g_usage_kwh_two_months);
-                                gas_usage_gauge_last_3_months.set(summary.g_usage_kwh_three_months);
-                                gas_usage_gauge_last_6_months.set(summary.g_usage_kwh_six_months);
-                                gas_usage_gauge_last_1_year.set(summary.g_usage_kwh_year);
-
-                                carbon_intensity_gauge_two_days.set(summary.carbon_intensity_two_days);
-                                carbon_intensity_gauge_current_month.set(summary.carbon_intensity_month);
-                                carbon_intensity_gauge_four_weeks.set(summary.carbon_intensity_four_weeks);
-                                carbon_intensity_gauge_week.set(summary.carbon_intensity_week);
-                                carbon_intensity_gauge_last_2_months.set(summary.carbon_intensity_two_months);
-                                carbon_intensity_gauge_last_3_months.set(summary.carbon_intensity_three_months);
-                                carbon_intensity_gauge_last_6_months.set(summary.carbon_intensity_six_months);
-                                carbon_intensity_gauge_two_weeks.set(summary.carbon_intensity_two_weeks);
-                                carbon_intensity_gauge_last_1_year.set(summary.carbon_intensity_year);
-                                
-                                info!(
-                                    "[DEBUG] Electricity Usage Summary: usage_kwh = 2d : {:.3}, current week: {:.3}, 2 weeks: {:.3}, 4 weeks: {:.3}, current month: {:.3}, 2 months: {:.3}, 3 months: {:.3}, 6 months: {:.3}, 1 year: {:.3}",
-                                    summary.e_usage_kwh_two_days,
-                                    summary.e_usage_kwh_week,
-                                    summary.e_usage_kwh_two_weeks,
-                                    summary.e_usage_kwh_four_weeks,
-                                    summary.e_usage_kwh_month,
-                                    summary.e_usage_kwh_two_months,
-                                    summary.e_usage_kwh_three_months,
-                                    summary.e_usage_kwh_six_months,
-                                    summary.e_usage_kwh_year,
-                                );
-
-                                info!(
-                                    "[DEBUG] Gas Usage Summary: usage_kwh = 2d : {:.3}, current week: {:.3}, 2 weeks: {:.3}, 4 weeks: {:.3}, current month: {:.3}, 2 months: {:.3}, 3 months: {:.3}, 6 months: {:.3}, 1 year: {:.3}",
-                                    summary.g_usage_kwh_two_days,
-                                    summary.g_usage_kwh_week,
-                                    summary.g_usage_kwh_two_weeks,
-                                    summary.g_usage_kwh_four_weeks,
-                                    summary.g_usage_kwh_month,
-                                    summary.g_usage_kwh_two_months,
-                                    summary.g_usage_kwh_three_months,
-                                    summary.g_usage_kwh_six_months,
-                                    summary.g_usage_kwh_year,
-                                );
-
-                                info!(
-                                    "[DEBUG] Carbon Usage Summary: usage_grams = 2d : {:.3}, current week: {:.3}, 2 weeks: {:.3}, 4 weeks: {:.3}, current month: {:.3}, 2 months: {:.3}, 3 months: {:.3}, 6 months: {:.3}, 1 year: {:.3}",
-                                    summary.carbon_intensity_two_days,
-                                    summary.carbon_intensity_week,
-                                    summary.carbon_intensity_two_weeks,
-                                    summary.carbon_intensity_four_weeks,
-                                    summary.carbon_intensity_month,
-                                    summary.carbon_intensity_two_months,
-                                    summary.carbon_intensity_three_months,
-                                    summary.carbon_intensity_six_months,
-                                    summary.carbon_intensity_year,
-                                );
-                            }
-                            Err(e) => {
-                                error_counter.inc();
-                                error!("[DEBUG] Error fetching  usage: {e}");
-                            }
-                        }
-                        info!("[DEBUG] Sleeping for {interval} seconds before next metrics push.");
-                        time::sleep(Duration::from_secs(interval)).await;
-                    }
-                });
+                let poller = build_poller(&settings, &metrics, clock, cache).with_health(Arc::clone(&health));
+                tokio::spawn(poller.run(interval));
             }
 
-            // Set up web server routes
-            let metrics_route = {
-                let registry = Arc::clone(&registry);
-                warp::path!("metrics").map(move || {
-                    let encoder = TextEncoder::new();
-                    let metric_families = registry.gather();
-                    let mut buffer = Vec::new();
-                    encoder.encode(&metric_families, &mut buffer).unwrap();
-                    let metrics = String::from_utf8(buffer).unwrap();
-
-                    warp::http::Response::builder()
-                        .header("Content-Type", "text/plain; version=0.0.4")
-                        .body(metrics)
-                })
+            let routes = web::routes(Arc::clone(&metrics), Arc::new(Auth::new(&settings.web)), health);
+            let tls = settings.web.tls_server_config.as_ref();
+            let scheme = if tls.is_some() { "https" } else { "http" };
+            let listen = &settings.listen;
+            info!("Starting server on {scheme}://{listen}");
+            info!("Metrics endpoint: {scheme}://{listen}/metrics");
+            info!("Health endpoint: {scheme}://{listen}/health");
+
+            // Run the server until the timeout, if there is one
+            let shutdown = async move {
+                if timeout > 0 {
+                    time::sleep(Duration::from_secs(timeout)).await;
+                    info!("Timeout reached after {timeout} seconds, shutting down...");
+                } else {
+                    std::future::pending::<()>().await;
+                }
             };
+            if let Err(e) = web::serve(routes, listen, tls, shutdown).await {
+                error!("{e}");
+                std::process::exit(1);
+            }
+        }
+        Commands::Backfill { overrides, config, from, to } => {
+            let settings = load_settings(config.as_deref(), overrides).unwrap_or_else(|e| {
+                error!("{e}");
+                std::process::exit(1);
+            });
+            let cache = match &settings.cache {
+                Some(cache @ CacheSettings { location: CacheLocation::Database(_), .. }) => ReadingCache::open(cache),
+                _ => Err(Error::Config("backfill needs a database, set with --database or database in the config".to_string())),
+            };
+            let cache = cache.unwrap_or_else(|e| {
+                error!("{e}");
+                std::process::exit(1);
+            });
 
-            let health_route = warp::path!("health").map(|| "OK");
-            let routes = metrics_route.or(health_route);
-
-            info!("Starting server on http://localhost:9090");
-            info!("Metrics endpoint: http://localhost:9090/metrics");
-            info!("Health endpoint: http://localhost:9090/health");
+            let metrics = register_metrics(&settings);
+            let clock = Arc::new(SystemClock);
+            let (from, to) = day_span(from, to, settings.timezone, clock.now()).unwrap_or_else(|e| {
+                error!("{e}");
+                std::process::exit(1);
+            });
+            info!("Backfilling from {from} to {to}");
 
-            // Run the server with optional timeout
-            if let Some(timeout_future) = timeout_future {
-                tokio::select! {
-                    _ = warp::serve(routes).run(([127, 0, 0, 1], 9090)) => {},
-                    _ = timeout_future => {
-                        info!("Timeout reached after {timeout} seconds, shutting down...");
-                    }
-                }
+            let poller = build_poller(&settings, &metrics, clock, Some(cache));
+            let failures = poller.backfill(from, to).await;
+            if !failures.is_empty() {
+                error!("Backfill finished with {} errors, run it again to fetch what's missing", failures.len());
+                std::process::exit(1);
+            }
+            info!("Backfill finished");
+        }
+        Commands::Export { overrides, config, fuel, from, to, granularity, format, output } => {
+            let settings = load_settings(config.as_deref(), overrides).unwrap_or_else(|e| {
+                error!("{e}");
+                std::process::exit(1);
+            });
+            let cache = settings.cache.as_ref().map(ReadingCache::open).transpose().unwrap_or_else(|e| {
+                error!("Failed to open the reading cache: {e}");
+                std::process::exit(1);
+            });
+            let metrics = register_metrics(&settings);
+            let clock = Arc::new(SystemClock);
+            let (from, to) = day_span(from, to, settings.timezone, clock.now()).unwrap_or_else(|e| {
+                error!("{e}");
+                std::process::exit(1);
+            });
+
+            let poller = build_poller(&settings, &metrics, clock, cache);
+            let (meters, failures) = poller.export(fuel, from, to).await;
+            let rows: Vec<_> = meters
+                .iter()
+                .flat_map(|(meter, slots)| export::rows(meter, slots, granularity, settings.timezone))
+                .collect();
+
+            let written = match &output {
+                Some(path) => File::create(path).and_then(|file| export::write(&rows, format, BufWriter::new(file))),
+                None => export::write(&rows, format, io::stdout().lock()),
+            };
+            if let Err(e) = written {
+                error!("Failed to write the export: {e}");
+                std::process::exit(1);
+            }
+            if !failures.is_empty() {
+                error!("Export finished with {} errors, what failed is missing from it", failures.len());
+                std::process::exit(1);
+            }
+            info!("Exported {} rows of {} meters", rows.len(), meters.len());
+        }
+        Commands::Summary { overrides, config, json } => {
+            let settings = load_settings(config.as_deref(), overrides).unwrap_or_else(|e| {
+                error!("{e}");
+                std::process::exit(1);
+            });
+            let cache = settings.cache.as_ref().map(ReadingCache::open).transpose().unwrap_or_else(|e| {
+                error!("Failed to open the reading cache: {e}");
+                std::process::exit(1);
+            });
+            let metrics = register_metrics(&settings);
+            let clock = Arc::new(SystemClock);
+            let now = clock.now();
+
+            let poller = build_poller(&settings, &metrics, clock, cache);
+            let windows: Vec<String> = poller.periods(now).into_iter().map(|(window, _, _)| window).collect();
+            let poll = poller.poll().await;
+            let report = Report::new(&poll.summaries, &poll.failures, &windows);
+            let written = if json {
+                report.write_json(io::stdout().lock())
             } else {
-                warp::serve(routes)
-                    .run(([127, 0, 0, 1], 9090))
-                    .await;
+                report.write_table(io::stdout().lock())
+            };
+            if let Err(e) = written {
+                error!("Failed to write the summary: {e}");
+                std::process::exit(1);
+            }
+            if !poll.failures.is_empty() {
+                std::process::exit(1);
             }
         }
     }
@@ -400,7 +364,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use chrono::{Datelike, Timelike, Utc};
     use clap::CommandFactory;
 
     #[test]
@@ -412,90 +375,227 @@ mod tests {
             "--timeout", "30",
             "--interval", "5",
             "--region", "England",
+            "--listen", "[::1]:9100",
         ]);
         assert!(cli.is_ok());
         let cli = cli.unwrap();
         match cli.command {
-            Commands::Run { timeout, interval, region } => {
-                assert_eq!(timeout, 30);
-                assert_eq!(interval, 5);
-                assert_eq!(region, "England");
+            Commands::Run { overrides, config, rebuild_cache } => {
+                assert_eq!(overrides.timeout, Some(30));
+                assert_eq!(overrides.interval, Some(5));
+                assert_eq!(overrides.region.as_deref(), Some("England"));
+                assert_eq!(overrides.listen, Some("[::1]:9100".parse().unwrap()));
+                assert!(overrides.web_config_file.is_none());
+                // Unset flags leave the config file and defaults to decide
+                assert!(overrides.windows.is_none());
+                assert!(overrides.lowest_carbon_windows.is_none());
+                assert!(overrides.timezone.is_none());
+                assert!(overrides.electricity_tariff.is_none());
+                assert!(overrides.gas_tariff.is_none());
+                assert!(overrides.gas_unit.is_none());
+                assert!(overrides.calorific_value.is_none());
+                assert!(overrides.volume_correction.is_none());
+                assert!(overrides.gas_emission_factor.is_none());
+                assert!(overrides.retries.is_none());
+                assert!(overrides.requests_per_second.is_none());
+                assert!(!overrides.legacy_metrics);
+                assert!(overrides.cache_dir.is_none());
+                assert!(config.is_none());
+                assert!(!rebuild_cache);
             }
+            other => panic!("expected run, got {other:?}"),
         }
     }
 
     #[test]
-    fn test_cli_command_help() {
-        // Ensure the clap CLI provides help without panic
-        Cli::command().debug_assert();
+    fn test_cli_parsing_postcode() {
+        let cli = Cli::try_parse_from(["testbin", "run", "--postcode", "SW1A"]).unwrap();
+        match cli.command {
+            Commands::Run { overrides, .. } => assert_eq!(overrides.postcode.as_deref(), Some("SW1A")),
+            other => panic!("expected run, got {other:?}"),
+        }
+
+        // A postcode picks the region, so both can't be given
+        assert!(Cli::try_parse_from(["testbin", "run", "--region", "London", "--postcode", "SW1A"]).is_err());
     }
 
     #[test]
-    fn test_env_key_required() {
-        unsafe { std::env::remove_var("OCTOPUS_API_KEY") }; // This is safe!
-        let key = std::env::var("OCTOPUS_API_KEY");
-        assert!(key.is_err());
+    fn test_cli_parsing_legacy_metrics_flag() {
+        let cli = Cli::try_parse_from(["testbin", "run", "--legacy-metrics"]).unwrap();
+        match cli.command {
+            Commands::Run { overrides, .. } => assert!(overrides.legacy_metrics),
+            other => panic!("expected run, got {other:?}"),
+        }
     }
 
     #[test]
-    fn test_get_year_range() {
-        // Freeze "now"
-        let now = Utc::now();
-        let years_back = 2;
-        let (start, end) = get_year_range(years_back);
-
-        // Start should be same month, day=1, hour/min/sec=0, nanosec=0, year shifted back
-        assert_eq!(start.year(), now.year() - years_back);
-        assert_eq!(start.month(), now.month());
-        assert_eq!(start.day(), 1);
-        assert_eq!(start.hour(), 0);
-        assert_eq!(start.minute(), 0);
-        assert_eq!(start.second(), 0);
-        assert_eq!(start.nanosecond(), 0);
-
-        // End should be "now" or very close to it
-        assert!((end.timestamp() - now.timestamp()).abs() < 3); // allow up to 3s difference
+    fn test_cli_parsing_windows() {
+        let cli = Cli::try_parse_from([
+            "testbin",
+            "run",
+            "--window", "36h",
+            "-w", "90d,ytd",
+            "--window", "cycle:14",
+        ]).unwrap();
+        match cli.command {
+            Commands::Run { overrides, .. } => {
+                let windows = overrides.windows.unwrap();
+                let labels: Vec<_> = windows.iter().map(|w| w.label()).collect();
+                assert_eq!(labels, ["36h", "90d", "ytd", "cycle:14"]);
+            }
+            other => panic!("expected run, got {other:?}"),
+        }
+
+        assert!(Cli::try_parse_from(["testbin", "run", "--window", "fortnight"]).is_err());
     }
 
     #[test]
-    fn test_get_month_range_current() {
-        let now = Utc::now();
-        let (start, end) = get_month_range(0);
-
-        // Start should be first day of current month at midnight
-        assert_eq!(start.year(), now.year());
-        assert_eq!(start.month(), now.month());
-        assert_eq!(start.day(), 1);
-        assert_eq!(start.hour(), 0);
-        assert_eq!(start.minute(), 0);
-        assert_eq!(start.second(), 0);
-        assert_eq!(start.nanosecond(), 0);
-
-        // End should be "now" or very close
-        assert!((end.timestamp() - now.timestamp()).abs() < 3);
+    fn test_cli_parsing_timezone() {
+        let cli = Cli::try_parse_from(["testbin", "run", "--timezone", "America/New_York"]).unwrap();
+        match cli.command {
+            Commands::Run { overrides, .. } => assert_eq!(overrides.timezone, Some(chrono_tz::America::New_York)),
+            other => panic!("expected run, got {other:?}"),
+        }
+
+        assert!(Cli::try_parse_from(["testbin", "run", "--timezone", "Mars/Olympus"]).is_err());
     }
 
     #[test]
-    fn test_get_month_range_previous_month() {
-        let now = Utc::now();
-        let (start, end) = get_month_range(1);
-
-        // Calculate expected year and month
-        let mut expected_year = now.year();
-        let mut expected_month = now.month() as i32 - 1;
-        if expected_month == 0 {
-            expected_month = 12;
-            expected_year -= 1;
+    fn test_cli_parsing_tariffs() {
+        let cli = Cli::try_parse_from([
+            "testbin",
+            "run",
+            "--electricity-tariff", "E-1R-VAR-22-11-01-C",
+            "--gas-tariff", "G-1R-VAR-22-11-01-C",
+        ]).unwrap();
+        match cli.command {
+            Commands::Run { overrides, .. } => {
+                assert_eq!(overrides.electricity_tariff.unwrap().product_code, "VAR-22-11-01");
+                assert_eq!(overrides.gas_tariff.unwrap().fuel, usage::Fuel::Gas);
+            }
+            other => panic!("expected run, got {other:?}"),
         }
-        assert_eq!(start.year(), expected_year);
-        assert_eq!(start.month(), expected_month as u32);
-        assert_eq!(start.day(), 1);
-        assert_eq!(start.hour(), 0);
-        assert_eq!(start.minute(), 0);
-        assert_eq!(start.second(), 0);
-        assert_eq!(start.nanosecond(), 0);
-
-        // End should be "now" or very close
-        assert!((end.timestamp() - now.timestamp()).abs() < 3);
+
+        // Tariff codes must match the fuel they're given for
+        assert!(Cli::try_parse_from(["testbin", "run", "--gas-tariff", "E-1R-VAR-22-11-01-C"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_gas_conversion() {
+        let cli = Cli::try_parse_from(["testbin", "run", "--gas-unit", "m3", "--calorific-value", "39.2"]).unwrap();
+        match cli.command {
+            Commands::Run { overrides, .. } => {
+                assert_eq!(overrides.gas_unit, Some(gas::GasUnit::CubicMetres));
+                assert_eq!(overrides.calorific_value, Some(39.2));
+            }
+            other => panic!("expected run, got {other:?}"),
+        }
+
+        assert!(Cli::try_parse_from(["testbin", "run", "--gas-unit", "therms"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_backfill() {
+        let cli = Cli::try_parse_from(["testbin", "backfill", "--from", "2022-01-01", "--database", "/var/lib/octopus.db"]).unwrap();
+        match cli.command {
+            Commands::Backfill { overrides, from, to, .. } => {
+                assert_eq!(from, NaiveDate::from_ymd_opt(2022, 1, 1).unwrap());
+                assert!(to.is_none());
+                assert_eq!(overrides.database, Some(PathBuf::from("/var/lib/octopus.db")));
+            }
+            other => panic!("expected backfill, got {other:?}"),
+        }
+
+        assert!(Cli::try_parse_from(["testbin", "backfill"]).is_err());
+        assert!(Cli::try_parse_from(["testbin", "backfill", "--from", "last year"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_export() {
+        let cli = Cli::try_parse_from([
+            "testbin",
+            "export",
+            "--fuel", "gas",
+            "--from", "2025-01-01",
+            "--to", "2025-02-01",
+            "--granularity", "day",
+            "--format", "ndjson",
+            "-o", "gas.ndjson",
+        ]).unwrap();
+        match cli.command {
+            Commands::Export { fuel, to, granularity, format, output, .. } => {
+                assert_eq!(fuel, Some(usage::Fuel::Gas));
+                assert_eq!(to, NaiveDate::from_ymd_opt(2025, 2, 1));
+                assert_eq!(granularity, Granularity::Day);
+                assert_eq!(format, Format::Ndjson);
+                assert_eq!(output, Some(PathBuf::from("gas.ndjson")));
+            }
+            other => panic!("expected export, got {other:?}"),
+        }
+
+        // Half-hourly CSV to stdout unless asked otherwise
+        match Cli::try_parse_from(["testbin", "export", "--from", "2025-01-01"]).unwrap().command {
+            Commands::Export { fuel, granularity, format, output, .. } => {
+                assert!(fuel.is_none());
+                assert_eq!(granularity, Granularity::HalfHour);
+                assert_eq!(format, Format::Csv);
+                assert!(output.is_none());
+            }
+            other => panic!("expected export, got {other:?}"),
+        }
+
+        assert!(Cli::try_parse_from(["testbin", "export", "--from", "2025-01-01", "--granularity", "fortnight"]).is_err());
+        assert!(Cli::try_parse_from(["testbin", "export", "--from", "2025-01-01", "--format", "xlsx"]).is_err());
+    }
+
+    #[test]
+    fn test_day_span_includes_the_last_day() {
+        let tz = chrono_tz::Europe::London;
+        let day = |s: &str| s.parse::<NaiveDate>().unwrap();
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        let now = at("2025-09-10T12:00:00Z");
+
+        let (from, to) = day_span(day("2025-08-01"), Some(day("2025-08-31")), tz, now).unwrap();
+        assert_eq!((from, to), (at("2025-07-31T23:00:00Z"), at("2025-08-31T23:00:00Z")));
+        // A single day, and up to now when --to is today or later
+        assert_eq!(day_span(day("2025-08-31"), Some(day("2025-08-31")), tz, now).unwrap().1, at("2025-08-31T23:00:00Z"));
+        assert_eq!(day_span(day("2025-09-01"), Some(day("2025-12-31")), tz, now).unwrap().1, now);
+        assert_eq!(day_span(day("2025-09-01"), None, tz, now).unwrap().1, now);
+
+        for (from, to) in [("2025-08-31", Some("2025-08-01")), ("2025-09-11", None)] {
+            let err = day_span(day(from), to.map(day), tz, now).unwrap_err();
+            assert_eq!(err.kind(), "config");
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_summary() {
+        match Cli::try_parse_from(["testbin", "summary", "--json", "-w", "2d,mtd"]).unwrap().command {
+            Commands::Summary { overrides, json, .. } => {
+                assert!(json);
+                assert_eq!(overrides.windows.map(|windows| windows.len()), Some(2));
+            }
+            other => panic!("expected summary, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_load_settings_reports_bad_files() {
+        let err = load_settings(Some(Path::new("/nonexistent/octopus.toml")), Overrides::default()).unwrap_err();
+        assert_eq!(err.kind(), "config");
+        assert!(err.to_string().contains("can't read config"), "{err}");
+    }
+
+    #[test]
+    fn test_cli_command_help() {
+        // Ensure the clap CLI provides help without panic
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_env_key_required() {
+        unsafe { std::env::remove_var("OCTOPUS_API_KEY") }; // This is safe!
+        let key = std::env::var("OCTOPUS_API_KEY");
+        assert!(key.is_err());
     }
 }
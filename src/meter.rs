@@ -0,0 +1,302 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use log::warn;
+
+use crate::account::Account;
+use crate::config::{AccountConfig, MeterConfig};
+use crate::gas::{GasConversion, GasSettings, GasUnit};
+use crate::tariff::Tariff;
+use crate::usage::Fuel;
+
+/// A meter consumption is read from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Meter {
+    pub fuel: Fuel,
+    pub meter_point: String,
+    pub serial_number: String,
+    /// Records generation exported to the grid rather than energy imported.
+    pub export: bool,
+    /// Property on the account the meter supplies, pairing export meters
+    /// with the import meter they offset. `None` for meters set by hand.
+    pub property: Option<usize>,
+    /// Tariff set by hand for this meter.
+    pub tariff: Option<Tariff>,
+    /// Tariff of the agreement active on the account, when it's known.
+    pub tariff_code: Option<String>,
+    /// How to turn the volume a gas meter reports into kWh, `None` when it reports kWh.
+    pub gas_conversion: Option<GasConversion>,
+    /// Custom labels of the account and meter, exported on every series.
+    pub labels: BTreeMap<String, String>,
+}
+
+/// The meters of an account to poll as of `at`: hand-set meters first, each
+/// overriding the discovered meter point it names, then every other
+/// discovered meter point on a live agreement. Gas meters that don't set
+/// their own unit and conversion use `gas`.
+pub fn resolve_meters(config: &AccountConfig, account: Option<&Account>, at: DateTime<Utc>, gas: &GasSettings) -> Vec<Meter> {
+    let discovered: Vec<_> = match account {
+        Some(account) => [Fuel::Electricity, Fuel::Gas]
+            .into_iter()
+            .flat_map(|fuel| account.meter_points(fuel, at))
+            .collect(),
+        None => Vec::new(),
+    };
+    let mut meters: Vec<Meter> = Vec::new();
+
+    for meter_config in &config.meters {
+        let fuel = meter_config.fuel;
+        let mut points = discovered.iter().filter(|point| point.fuel == fuel && point.export == meter_config.export);
+        let point = match &meter_config.meter_point {
+            Some(meter_point) => points.find(|point| &point.meter_point == meter_point),
+            // Prefer the meter point on a live agreement
+            None => points.clone().find(|point| point.tariff_code.is_some()).or(points.next()),
+        };
+
+        let Some(meter_point) = meter_config.meter_point.as_deref().or(point.map(|p| p.meter_point.as_str())) else {
+            warn!("No {} meter point found on the account, skipping the configured meter", meter_kind(fuel, meter_config.export));
+            continue;
+        };
+        // The last listed meter is the one currently installed
+        let serial_number = meter_config
+            .serial_number
+            .as_deref()
+            .or(point.and_then(|p| p.serial_numbers.last().map(String::as_str)));
+        let Some(serial_number) = serial_number else {
+            warn!("No meter serial number found for {} meter point {meter_point}, skipping it", fuel.as_str());
+            continue;
+        };
+
+        let mut labels = config.labels.clone();
+        labels.extend(meter_config.labels.clone());
+        meters.push(Meter {
+            fuel,
+            meter_point: meter_point.to_string(),
+            serial_number: serial_number.to_string(),
+            export: meter_config.export,
+            property: point.map(|p| p.property),
+            tariff: meter_config.tariff.as_deref().and_then(|code| Tariff::parse_for(code, fuel).ok()),
+            tariff_code: point.and_then(|p| p.tariff_code.clone()),
+            gas_conversion: gas_conversion(fuel, Some(meter_config), gas),
+            labels,
+        });
+    }
+
+    for point in &discovered {
+        let overridden = meters.iter().any(|meter| meter.fuel == point.fuel && meter.meter_point == point.meter_point);
+        if overridden || point.tariff_code.is_none() {
+            continue;
+        }
+        let Some(serial_number) = point.serial_numbers.last() else {
+            warn!("No meter serial number found for {} meter point {}, skipping it", point.fuel.as_str(), point.meter_point);
+            continue;
+        };
+
+        meters.push(Meter {
+            fuel: point.fuel,
+            meter_point: point.meter_point.clone(),
+            serial_number: serial_number.clone(),
+            export: point.export,
+            property: Some(point.property),
+            tariff: None,
+            tariff_code: point.tariff_code.clone(),
+            gas_conversion: gas_conversion(point.fuel, None, gas),
+            labels: config.labels.clone(),
+        });
+    }
+    meters
+}
+
+/// The conversion of a gas meter reporting m³, from its own settings or the defaults.
+fn gas_conversion(fuel: Fuel, meter_config: Option<&MeterConfig>, gas: &GasSettings) -> Option<GasConversion> {
+    if fuel != Fuel::Gas {
+        return None;
+    }
+    let unit = meter_config
+        .and_then(|m| m.unit.or(m.meter_type.map(|meter_type| meter_type.unit())))
+        .unwrap_or(gas.unit);
+    if unit != GasUnit::CubicMetres {
+        return None;
+    }
+
+    Some(GasConversion {
+        calorific_value: meter_config.and_then(|m| m.calorific_value).unwrap_or(gas.calorific_value),
+        volume_correction: meter_config.and_then(|m| m.volume_correction).unwrap_or(gas.volume_correction),
+    })
+}
+
+fn meter_kind(fuel: Fuel, export: bool) -> String {
+    if export {
+        format!("{} export", fuel.as_str())
+    } else {
+        fuel.as_str().to_string()
+    }
+}
+
+/// A meter with no labels or tariff for tests elsewhere.
+#[cfg(test)]
+pub fn test_meter(fuel: Fuel, meter_point: &str) -> Meter {
+    Meter {
+        fuel,
+        meter_point: meter_point.to_string(),
+        serial_number: "S0000001".to_string(),
+        export: false,
+        property: None,
+        tariff: None,
+        tariff_code: None,
+        gas_conversion: None,
+        labels: BTreeMap::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gas::MeterType;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn account() -> Account {
+        serde_json::from_value(serde_json::json!({
+            "properties": [{
+                "electricity_meter_points": [
+                    {
+                        "mpan": "1200000000001",
+                        "meters": [{"serial_number": "19L0000001"}, {"serial_number": "21E0000001"}],
+                        "agreements": [{"tariff_code": "E-1R-VAR-22-11-01-C", "valid_from": "2023-01-01T00:00:00Z", "valid_to": null}]
+                    },
+                    {
+                        "mpan": "1400000000009",
+                        "is_export": true,
+                        "meters": [{"serial_number": "21E0000001"}],
+                        "agreements": [{"tariff_code": "E-1R-OUTGOING-FIX-12M-19-05-13-C", "valid_from": "2023-01-01T00:00:00Z", "valid_to": null}]
+                    }
+                ],
+                "gas_meter_points": [
+                    {"mprn": "3000000001", "meters": [{"serial_number": ""}], "agreements": []}
+                ]
+            }]
+        }))
+        .unwrap()
+    }
+
+    fn meter_config(fuel: Fuel, meter_point: Option<&str>, serial_number: Option<&str>) -> MeterConfig {
+        MeterConfig {
+            fuel,
+            export: false,
+            meter_point: meter_point.map(str::to_string),
+            serial_number: serial_number.map(str::to_string),
+            tariff: None,
+            unit: None,
+            meter_type: None,
+            calorific_value: None,
+            volume_correction: None,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_meters_discovered_from_account() {
+        let config = AccountConfig {
+            labels: BTreeMap::from([("property".to_string(), "flat".to_string())]),
+            ..Default::default()
+        };
+        let meters = resolve_meters(&config, Some(&account()), at("2025-01-01T00:00:00Z"), &GasSettings::default());
+
+        // Gas has no live agreement, so isn't polled
+        assert_eq!(meters, vec![
+            Meter {
+                fuel: Fuel::Electricity,
+                meter_point: "1200000000001".to_string(),
+                serial_number: "21E0000001".to_string(),
+                export: false,
+                property: Some(0),
+                tariff: None,
+                tariff_code: Some("E-1R-VAR-22-11-01-C".to_string()),
+                gas_conversion: None,
+                labels: config.labels.clone(),
+            },
+            Meter {
+                fuel: Fuel::Electricity,
+                meter_point: "1400000000009".to_string(),
+                serial_number: "21E0000001".to_string(),
+                export: true,
+                property: Some(0),
+                tariff: None,
+                tariff_code: Some("E-1R-OUTGOING-FIX-12M-19-05-13-C".to_string()),
+                gas_conversion: None,
+                labels: config.labels.clone(),
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_overrides_win_over_discovery() {
+        let mut gas = meter_config(Fuel::Gas, None, Some("G4P0000001"));
+        gas.tariff = Some("G-1R-VAR-22-11-01-C".to_string());
+        gas.labels.insert("floor".to_string(), "ground".to_string());
+        let config = AccountConfig {
+            meters: vec![meter_config(Fuel::Electricity, None, Some("OVERRIDE1")), gas],
+            ..Default::default()
+        };
+        let meters = resolve_meters(&config, Some(&account()), at("2025-01-01T00:00:00Z"), &GasSettings::default());
+
+        // The discovered import meter point is overridden, not polled twice
+        assert_eq!(meters.len(), 3);
+        assert!(meters[2].export);
+        assert_eq!(meters[0].serial_number, "OVERRIDE1");
+        assert_eq!(meters[0].meter_point, "1200000000001");
+        assert_eq!((meters[1].meter_point.as_str(), meters[1].serial_number.as_str()), ("3000000001", "G4P0000001"));
+        assert_eq!(meters[1].tariff_code, None);
+        assert_eq!(meters[1].tariff.as_ref().unwrap().product_code, "VAR-22-11-01");
+        assert_eq!(meters[1].labels["floor"], "ground");
+    }
+
+    #[test]
+    fn test_gas_conversion_per_meter() {
+        let mut smets2 = meter_config(Fuel::Gas, Some("3000000002"), Some("E6S0000002"));
+        smets2.meter_type = Some(MeterType::Smets2);
+        smets2.calorific_value = Some(39.2);
+        let config = AccountConfig {
+            meters: vec![smets2, meter_config(Fuel::Gas, Some("3000000003"), Some("G4P0000003"))],
+            ..Default::default()
+        };
+
+        let meters = resolve_meters(&config, None, at("2025-01-01T00:00:00Z"), &GasSettings::default());
+        assert_eq!(meters[0].gas_conversion, Some(GasConversion { calorific_value: 39.2, volume_correction: 1.02264 }));
+        assert_eq!(meters[1].gas_conversion, None);
+
+        // Meters without their own unit follow the defaults
+        let gas = GasSettings { unit: GasUnit::CubicMetres, calorific_value: 40.0, ..Default::default() };
+        let meters = resolve_meters(&config, None, at("2025-01-01T00:00:00Z"), &gas);
+        assert_eq!(meters[1].gas_conversion, Some(GasConversion { calorific_value: 40.0, volume_correction: 1.02264 }));
+    }
+
+    #[test]
+    fn test_meters_without_account() {
+        let config = AccountConfig {
+            meters: vec![
+                meter_config(Fuel::Electricity, Some("1200000000002"), Some("22E0000002")),
+                meter_config(Fuel::Gas, Some("3000000002"), None),
+            ],
+            ..Default::default()
+        };
+        let meters = resolve_meters(&config, None, at("2025-01-01T00:00:00Z"), &GasSettings::default());
+
+        // The gas meter point has no serial to read from
+        assert_eq!(meters, vec![Meter {
+            fuel: Fuel::Electricity,
+            meter_point: "1200000000002".to_string(),
+            serial_number: "22E0000002".to_string(),
+            export: false,
+            property: None,
+            tariff: None,
+            tariff_code: None,
+            gas_conversion: None,
+            labels: BTreeMap::new(),
+        }]);
+        assert!(resolve_meters(&AccountConfig::default(), None, at("2025-01-01T00:00:00Z"), &GasSettings::default()).is_empty());
+    }
+}
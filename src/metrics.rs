@@ -0,0 +1,741 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use prometheus::{Gauge, GaugeVec, IntCounter, IntCounterVec, Opts, Registry};
+
+use chrono::{DateTime, Utc};
+
+use crate::carbon_intensity::{self, IntensitySlot, LowestWindow, ResolvedRegion};
+use crate::error::Failure;
+use crate::meter::Meter;
+use crate::usage::{Fuel, Summary};
+
+/// Which figure of a `Summary` a legacy gauge mirrors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LegacySource {
+    Usage(Fuel),
+    Carbon,
+}
+
+/// A pre-label metric name kept alive for existing dashboards.
+struct LegacyGauge {
+    source: LegacySource,
+    window: &'static str,
+    name: &'static str,
+    help: &'static str,
+}
+
+const LEGACY_GAUGES: &[LegacyGauge] = &[
+    LegacyGauge { source: LegacySource::Usage(Fuel::Electricity), window: "2w", name: "octopus_electricity_usage_2w_kwh", help: "Total Octopus Energy electricity usage for last 2 weeks in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Electricity), window: "4w", name: "octopus_electricity_usage_4w_kwh", help: "Total Octopus Energy electricity usage for last 4 weeks in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Electricity), window: "2d", name: "octopus_electricity_usage_two_days_kwh", help: "Total Octopus Energy electricity usage for two days in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Electricity), window: "mtd", name: "octopus_electricity_usage_current_month_kwh", help: "Total Octopus Energy electricity usage for current month in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Electricity), window: "2m", name: "octopus_electricity_usage_last_2_months_kwh", help: "Total Octopus Energy electricity usage for the last two months in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Electricity), window: "3m", name: "octopus_electricity_usage_last_3_months_kwh", help: "Total Octopus Energy electricity usage for the last three months in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Electricity), window: "6m", name: "octopus_electricity_usage_last_6_months_kwh", help: "Total Octopus Energy electricity usage for the last six months in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Electricity), window: "1y", name: "octopus_electricity_usage_last_1_year_kwh", help: "Total Octopus Energy electricity usage for the last 1 year in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Electricity), window: "1w", name: "octopus_electricity_usage_week_kwh", help: "Total Octopus Energy electricity usage on weekly basis in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Gas), window: "2w", name: "octopus_gas_usage_2w_kwh", help: "Total Octopus Energy gas usage for last 2 weeks in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Gas), window: "4w", name: "octopus_gas_usage_4w_kwh", help: "Total Octopus Energy gas usage for last 4 weeks in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Gas), window: "2d", name: "octopus_gas_usage_two_days_kwh", help: "Total Octopus Energy gas usage for two days in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Gas), window: "mtd", name: "octopus_gas_usage_current_month_kwh", help: "Total Octopus Energy gas usage for current month in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Gas), window: "2m", name: "octopus_gas_usage_last_2_months_kwh", help: "Total Octopus Energy gas usage for the last two months in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Gas), window: "3m", name: "octopus_gas_usage_last_3_months_kwh", help: "Total Octopus Energy gas usage for the last three months in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Gas), window: "6m", name: "octopus_gas_usage_last_6_months_kwh", help: "Total Octopus Energy gas usage for the last six months in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Gas), window: "1y", name: "octopus_gas_usage_last_1_year_kwh", help: "Total Octopus Energy gas usage for the last 1 year in kWh" },
+    LegacyGauge { source: LegacySource::Usage(Fuel::Gas), window: "1w", name: "octopus_gas_usage_week_kwh", help: "Total Octopus Energy gas usage on weekly basis in kWh" },
+    LegacyGauge { source: LegacySource::Carbon, window: "1w", name: "octopus_energy_carbon_emissions_week_grams", help: "Total carbon emissions on weekly basis in grams" },
+    LegacyGauge { source: LegacySource::Carbon, window: "2w", name: "octopus_energy_carbon_emissions_2w_grams", help: "Total carbon emissions for last two weeks in grams" },
+    LegacyGauge { source: LegacySource::Carbon, window: "4w", name: "octopus_energy_carbon_emissions_4w_grams", help: "Total carbon emissions for last four weeks in grams" },
+    LegacyGauge { source: LegacySource::Carbon, window: "2d", name: "octopus_energy_carbon_emissions_2d_grams", help: "Total carbon emissions for last two days in grams" },
+    LegacyGauge { source: LegacySource::Carbon, window: "mtd", name: "octopus_energy_carbon_emissions_current_month_grams", help: "Total carbon emissions for current month in grams" },
+    LegacyGauge { source: LegacySource::Carbon, window: "2m", name: "octopus_energy_carbon_emissions_last_2_months_grams", help: "Total carbon emissions for the last two months in kWh" },
+    LegacyGauge { source: LegacySource::Carbon, window: "3m", name: "octopus_energy_carbon_emissions_last_3_months_grams", help: "Total carbon emissions for the last three months in kWh" },
+    LegacyGauge { source: LegacySource::Carbon, window: "6m", name: "octopus_energy_carbon_emissions_last_6_months_grams", help: "Total carbon emissions for the last six months in kWh" },
+    LegacyGauge { source: LegacySource::Carbon, window: "1y", name: "octopus_energy_carbon_emissions_last_1_months_grams", help: "Total carbon emissions for the last 1 year in kWh" },
+];
+
+/// All metrics exported on `/metrics`, registered against a single registry.
+pub struct Metrics {
+    pub registry: Registry,
+    pub usage_kwh: GaugeVec,
+    pub gas_volume_m3: GaugeVec,
+    pub cost_pence: GaugeVec,
+    pub standing_charge_pence: GaugeVec,
+    pub unit_rate_pence: GaugeVec,
+    pub carbon_grams: GaugeVec,
+    pub carbon_average_grams: GaugeVec,
+    pub usage_by_generation_kwh: GaugeVec,
+    pub export_kwh: GaugeVec,
+    pub net_import_kwh: GaugeVec,
+    pub export_earnings_pence: GaugeVec,
+    pub export_rate_pence: GaugeVec,
+    pub carbon_avoided_grams: GaugeVec,
+    pub carbon_region_info: GaugeVec,
+    pub carbon_intensity: Gauge,
+    pub carbon_intensity_index: GaugeVec,
+    pub generation_mix_percent: GaugeVec,
+    pub carbon_intensity_forecast: GaugeVec,
+    pub lowest_carbon_window_start: GaugeVec,
+    pub lowest_carbon_window_intensity: GaugeVec,
+    pub errors: IntCounter,
+    pub poll_errors: IntCounterVec,
+    pub last_success: Gauge,
+    pub api_retries: IntCounterVec,
+    pub api_throttled: IntCounterVec,
+    pub consumption_pages: IntCounterVec,
+    pub consumption_readings: IntCounterVec,
+    /// Custom label names, exported between `meter_point` and the window or period.
+    labels: Vec<String>,
+    legacy: Vec<(&'static LegacyGauge, Gauge)>,
+}
+
+impl Metrics {
+    /// Registers the labelled metric families, with `labels` added to every
+    /// per-meter series, plus the old one-gauge-per-window names when `legacy` is set.
+    pub fn new(legacy: bool, labels: &[String]) -> Result<Self, prometheus::Error> {
+        let registry = Registry::new();
+        let meter_labels = |extra: Option<&'static str>| {
+            let mut names = vec!["fuel", "meter_point"];
+            names.extend(labels.iter().map(String::as_str));
+            names.extend(extra);
+            names
+        };
+
+        let usage_kwh = GaugeVec::new(
+            Opts::new("octopus_energy_usage_kwh", "Total Octopus Energy usage over the reporting window in kWh"),
+            &meter_labels(Some("window")),
+        )?;
+        registry.register(Box::new(usage_kwh.clone()))?;
+
+        let gas_volume_m3 = GaugeVec::new(
+            Opts::new("octopus_energy_gas_volume_m3", "Gas volume measured over the reporting window in m³, by meters that report volume"),
+            &meter_labels(Some("window")),
+        )?;
+        registry.register(Box::new(gas_volume_m3.clone()))?;
+
+        let cost_pence = GaugeVec::new(
+            Opts::new("octopus_energy_cost_pence", "Total cost of usage and standing charges over the reporting window in pence, including VAT"),
+            &meter_labels(Some("window")),
+        )?;
+        registry.register(Box::new(cost_pence.clone()))?;
+
+        let standing_charge_pence = GaugeVec::new(
+            Opts::new("octopus_energy_standing_charge_pence_per_day", "Current standing charge in pence per day, including VAT"),
+            &meter_labels(None),
+        )?;
+        registry.register(Box::new(standing_charge_pence.clone()))?;
+
+        let unit_rate_pence = GaugeVec::new(
+            Opts::new(
+                "octopus_energy_unit_rate_pence_per_kwh",
+                "Unit rate in pence per kWh including VAT, for the current and next slot and today's min, max and time-weighted average",
+            ),
+            &meter_labels(Some("period")),
+        )?;
+        registry.register(Box::new(unit_rate_pence.clone()))?;
+
+        let carbon_grams = GaugeVec::new(
+            Opts::new(
+                "octopus_energy_carbon_emissions_grams",
+                "Total carbon emissions over the reporting window in grams: electricity at each half hour's intensity, gas at its emission factor, and fuel=\"total\" both together",
+            ),
+            &meter_labels(Some("window")),
+        )?;
+        registry.register(Box::new(carbon_grams.clone()))?;
+
+        let carbon_average_grams = GaugeVec::new(
+            Opts::new(
+                "octopus_energy_carbon_emissions_average_intensity_grams",
+                "Total carbon emissions over the reporting window in grams, estimated from the window's average intensity",
+            ),
+            &meter_labels(Some("window")),
+        )?;
+        registry.register(Box::new(carbon_average_grams.clone()))?;
+
+        let mut generation_labels = meter_labels(Some("window"));
+        generation_labels.push("fuel_type");
+        let usage_by_generation_kwh = GaugeVec::new(
+            Opts::new(
+                "octopus_energy_usage_by_generation_kwh",
+                "Usage over the reporting window in kWh by the fuel type that generated it, each half hour split by that half hour's regional generation mix",
+            ),
+            &generation_labels,
+        )?;
+        registry.register(Box::new(usage_by_generation_kwh.clone()))?;
+
+        let export_kwh = GaugeVec::new(
+            Opts::new("octopus_energy_export_kwh", "Total energy exported to the grid over the reporting window in kWh"),
+            &meter_labels(Some("window")),
+        )?;
+        registry.register(Box::new(export_kwh.clone()))?;
+
+        let net_import_kwh = GaugeVec::new(
+            Opts::new("octopus_energy_net_import_kwh", "Usage less the energy exported from the same property over the reporting window in kWh"),
+            &meter_labels(Some("window")),
+        )?;
+        registry.register(Box::new(net_import_kwh.clone()))?;
+
+        let export_earnings_pence = GaugeVec::new(
+            Opts::new("octopus_energy_export_earnings_pence", "Total earned from exported energy over the reporting window in pence, including VAT"),
+            &meter_labels(Some("window")),
+        )?;
+        registry.register(Box::new(export_earnings_pence.clone()))?;
+
+        let export_rate_pence = GaugeVec::new(
+            Opts::new(
+                "octopus_energy_export_rate_pence_per_kwh",
+                "Export rate in pence per kWh including VAT, for the current and next slot and today's min, max and time-weighted average",
+            ),
+            &meter_labels(Some("period")),
+        )?;
+        registry.register(Box::new(export_rate_pence.clone()))?;
+
+        let carbon_avoided_grams = GaugeVec::new(
+            Opts::new("octopus_energy_carbon_avoided_grams", "Carbon emissions avoided by exported energy over the reporting window in grams"),
+            &meter_labels(Some("window")),
+        )?;
+        registry.register(Box::new(carbon_avoided_grams.clone()))?;
+
+        let carbon_region_info = GaugeVec::new(
+            Opts::new("octopus_energy_carbon_region_info", "Region carbon intensity is read for, always 1"),
+            &["region_id", "region", "postcode"],
+        )?;
+        registry.register(Box::new(carbon_region_info.clone()))?;
+
+        let carbon_intensity = Gauge::new(
+            "octopus_energy_carbon_intensity_grams_per_kwh",
+            "Carbon intensity of the region's electricity in the current half hour in gCO2/kWh",
+        )?;
+        registry.register(Box::new(carbon_intensity.clone()))?;
+
+        let carbon_intensity_index = GaugeVec::new(
+            Opts::new("octopus_energy_carbon_intensity_index", "1 for the intensity band of the current half hour, 0 for the others"),
+            &["index"],
+        )?;
+        registry.register(Box::new(carbon_intensity_index.clone()))?;
+
+        let generation_mix_percent = GaugeVec::new(
+            Opts::new("octopus_energy_generation_mix_percent", "Share of the region's generation in the current half hour by fuel type in percent"),
+            &["fuel_type"],
+        )?;
+        registry.register(Box::new(generation_mix_percent.clone()))?;
+
+        let carbon_intensity_forecast = GaugeVec::new(
+            Opts::new(
+                "octopus_energy_carbon_intensity_forecast_grams_per_kwh",
+                "Forecast carbon intensity of the half hour starting minutes_ahead after the current one in gCO2/kWh",
+            ),
+            &["minutes_ahead"],
+        )?;
+        registry.register(Box::new(carbon_intensity_forecast.clone()))?;
+
+        let lowest_carbon_window_start = GaugeVec::new(
+            Opts::new(
+                "octopus_energy_carbon_lowest_window_start_timestamp_seconds",
+                "Start of the lowest-carbon stretch of the given duration in the forecast, as a Unix timestamp",
+            ),
+            &["duration"],
+        )?;
+        registry.register(Box::new(lowest_carbon_window_start.clone()))?;
+
+        let lowest_carbon_window_intensity = GaugeVec::new(
+            Opts::new(
+                "octopus_energy_carbon_lowest_window_intensity_grams_per_kwh",
+                "Average forecast carbon intensity of the lowest-carbon stretch of the given duration in gCO2/kWh",
+            ),
+            &["duration"],
+        )?;
+        registry.register(Box::new(lowest_carbon_window_intensity.clone()))?;
+
+        let errors = IntCounter::new("octopus_energy_errors_total", "Total number of errors encountered")?;
+        registry.register(Box::new(errors.clone()))?;
+
+        let poll_errors = IntCounterVec::new(
+            Opts::new(
+                "octopus_energy_poll_errors_total",
+                "Errors while polling by account, window and kind; source and window are empty where the error isn't tied to one",
+            ),
+            &["source", "window", "kind"],
+        )?;
+        registry.register(Box::new(poll_errors.clone()))?;
+
+        let last_success = Gauge::new(
+            "octopus_energy_last_successful_poll_timestamp_seconds",
+            "When a poll last updated any meter's figures, as a Unix timestamp",
+        )?;
+        registry.register(Box::new(last_success.clone()))?;
+
+        let api_retries = IntCounterVec::new(
+            Opts::new("octopus_energy_api_retries_total", "API requests sent again after a transient failure, by API"),
+            &["api"],
+        )?;
+        registry.register(Box::new(api_retries.clone()))?;
+
+        let api_throttled = IntCounterVec::new(
+            Opts::new("octopus_energy_api_throttled_total", "API requests turned away with 429 Too Many Requests, by API"),
+            &["api"],
+        )?;
+        registry.register(Box::new(api_throttled.clone()))?;
+
+        let consumption_pages = IntCounterVec::new(
+            Opts::new("octopus_energy_consumption_pages_total", "Pages of consumption readings fetched from the Octopus API"),
+            &meter_labels(None),
+        )?;
+        registry.register(Box::new(consumption_pages.clone()))?;
+
+        let consumption_readings = IntCounterVec::new(
+            Opts::new("octopus_energy_consumption_readings_total", "Half-hourly consumption readings fetched from the Octopus API"),
+            &meter_labels(None),
+        )?;
+        registry.register(Box::new(consumption_readings.clone()))?;
+
+        let mut legacy_gauges = Vec::new();
+        if legacy {
+            for def in LEGACY_GAUGES {
+                let gauge = Gauge::new(def.name, def.help)?;
+                registry.register(Box::new(gauge.clone()))?;
+                legacy_gauges.push((def, gauge));
+            }
+        }
+
+        Ok(Metrics {
+            registry,
+            usage_kwh,
+            gas_volume_m3,
+            cost_pence,
+            standing_charge_pence,
+            unit_rate_pence,
+            carbon_grams,
+            carbon_average_grams,
+            usage_by_generation_kwh,
+            export_kwh,
+            net_import_kwh,
+            export_earnings_pence,
+            export_rate_pence,
+            carbon_avoided_grams,
+            carbon_region_info,
+            carbon_intensity,
+            carbon_intensity_index,
+            generation_mix_percent,
+            carbon_intensity_forecast,
+            lowest_carbon_window_start,
+            lowest_carbon_window_intensity,
+            errors,
+            poll_errors,
+            last_success,
+            api_retries,
+            api_throttled,
+            consumption_pages,
+            consumption_readings,
+            labels: labels.to_vec(),
+            legacy: legacy_gauges,
+        })
+    }
+
+    /// Copies every figure of a poll into the gauges.
+    pub fn update(&self, summaries: &[Summary]) {
+        for summary in summaries {
+            let labels = self.label_values(&summary.meter);
+            if let Some((pages, readings)) = summary.fetched() {
+                self.consumption_pages.with_label_values(&labels).inc_by(u64::from(pages));
+                self.consumption_readings.with_label_values(&labels).inc_by(readings as u64);
+            }
+            if summary.meter.export {
+                self.update_export(summary, &labels);
+                continue;
+            }
+
+            for (window, kwh) in summary.usage() {
+                self.usage_kwh.with_label_values(&extend(&labels, window)).set(kwh);
+            }
+            for (window, cubic_metres) in summary.volume() {
+                self.gas_volume_m3.with_label_values(&extend(&labels, window)).set(cubic_metres);
+            }
+            for (window, kwh) in summary.net_import() {
+                self.net_import_kwh.with_label_values(&extend(&labels, window)).set(kwh);
+            }
+            for (window, pence) in summary.cost() {
+                self.cost_pence.with_label_values(&extend(&labels, window)).set(pence);
+            }
+            if let Some(pence) = summary.standing_charge() {
+                self.standing_charge_pence.with_label_values(&labels).set(pence);
+            }
+            for (period, pence) in summary.unit_rates() {
+                self.unit_rate_pence.with_label_values(&extend(&labels, period)).set(pence);
+            }
+            for (window, grams) in summary.carbon() {
+                self.carbon_grams.with_label_values(&extend(&labels, window)).set(grams);
+            }
+            for (window, grams) in summary.carbon_average() {
+                self.carbon_average_grams.with_label_values(&extend(&labels, window)).set(grams);
+            }
+            for (window, fuel_type, kwh) in summary.generation() {
+                let mut values = extend(&labels, window);
+                values.push(fuel_type);
+                self.usage_by_generation_kwh.with_label_values(&values).set(kwh);
+            }
+        }
+        self.update_carbon_totals(summaries);
+
+        // The old names predate per-meter labels, so they carry the total of every meter
+        for (def, gauge) in &self.legacy {
+            let values: Vec<f64> = match def.source {
+                LegacySource::Usage(fuel) => summaries
+                    .iter()
+                    .filter(|summary| !summary.meter.export && summary.fuel() == fuel)
+                    .filter_map(|summary| summary.usage_for(def.window))
+                    .collect(),
+                // These only ever counted electricity
+                LegacySource::Carbon => summaries
+                    .iter()
+                    .filter(|summary| !summary.meter.export && summary.fuel() == Fuel::Electricity)
+                    .filter_map(|summary| summary.carbon_for(def.window))
+                    .collect(),
+            };
+            if !values.is_empty() {
+                gauge.set(values.iter().sum());
+            }
+        }
+    }
+
+    /// Counts every failure of a poll, in total and by where it happened.
+    pub fn record_failures(&self, failures: &[Failure]) {
+        for failure in failures {
+            self.errors.inc();
+            let source = failure.source.as_deref().unwrap_or("");
+            let window = failure.window.as_deref().unwrap_or("");
+            self.poll_errors.with_label_values(&[source, window, failure.error.kind()]).inc();
+        }
+    }
+
+    /// The `fuel="total"` carbon series: every import meter's emissions in a
+    /// window, per set of custom labels, so each household gets a footprint.
+    /// A window any of those meters has no carbon for is left as it was, as
+    /// the total would fall short.
+    fn update_carbon_totals(&self, summaries: &[Summary]) {
+        let mut households: BTreeMap<Vec<&str>, Vec<&Summary>> = BTreeMap::new();
+        for summary in summaries.iter().filter(|summary| !summary.meter.export) {
+            let custom = self.label_values(&summary.meter).split_off(2);
+            households.entry(custom).or_default().push(summary);
+        }
+
+        for (custom, members) in households {
+            let windows: BTreeSet<&str> = members.iter().flat_map(|summary| summary.carbon().map(|(window, _)| window)).collect();
+            for window in windows {
+                let Some(grams) = members.iter().map(|summary| summary.carbon_for(window)).sum::<Option<f64>>() else {
+                    continue;
+                };
+                let mut values = vec!["total", ""];
+                values.extend(&custom);
+                values.push(window);
+                self.carbon_grams.with_label_values(&values).set(grams);
+            }
+        }
+    }
+
+    /// Records the region carbon intensity is read for, replacing any earlier one.
+    pub fn set_carbon_region(&self, region: &ResolvedRegion) {
+        self.carbon_region_info.reset();
+        let id = region.id().to_string();
+        let postcode = region.postcode.as_deref().unwrap_or("");
+        self.carbon_region_info.with_label_values(&[id.as_str(), region.name.as_str(), postcode]).set(1.0);
+    }
+
+    /// Current intensity, band and generation mix, the forecast from now on,
+    /// and the greenest stretch of each of `windows` in it.
+    pub fn update_carbon_forecast(&self, slots: &[IntensitySlot], windows: &[LowestWindow], now: DateTime<Utc>) {
+        let Some(current) = carbon_intensity::slot_at(slots, now) else {
+            return;
+        };
+        self.carbon_intensity.set(current.intensity.forecast);
+        for index in carbon_intensity::INDEXES {
+            let value = if current.intensity.index == *index { 1.0 } else { 0.0 };
+            self.carbon_intensity_index.with_label_values(&[index]).set(value);
+        }
+        for share in &current.generation_mix {
+            self.generation_mix_percent.with_label_values(&[share.fuel.as_str()]).set(share.percent);
+        }
+
+        // The forecast shrinks towards its end, so drop horizons it no longer reaches
+        self.carbon_intensity_forecast.reset();
+        for slot in slots.iter().filter(|slot| slot.from >= current.from) {
+            let minutes_ahead = (slot.from - current.from).num_minutes().to_string();
+            self.carbon_intensity_forecast.with_label_values(&[minutes_ahead.as_str()]).set(slot.intensity.forecast);
+        }
+
+        for window in windows {
+            let label = window.label();
+            match carbon_intensity::lowest_window(slots, *window, now) {
+                Some((start, intensity)) => {
+                    self.lowest_carbon_window_start.with_label_values(&[label.as_str()]).set(start.timestamp() as f64);
+                    self.lowest_carbon_window_intensity.with_label_values(&[label.as_str()]).set(intensity);
+                }
+                None => {
+                    let _ = self.lowest_carbon_window_start.remove_label_values(&[label.as_str()]);
+                    let _ = self.lowest_carbon_window_intensity.remove_label_values(&[label.as_str()]);
+                }
+            }
+        }
+    }
+
+    /// An export meter's generation, earnings, rates and avoided carbon.
+    fn update_export(&self, summary: &Summary, labels: &[&str]) {
+        for (window, kwh) in summary.usage() {
+            self.export_kwh.with_label_values(&extend(labels, window)).set(kwh);
+        }
+        for (window, pence) in summary.cost() {
+            self.export_earnings_pence.with_label_values(&extend(labels, window)).set(pence);
+        }
+        for (period, pence) in summary.unit_rates() {
+            self.export_rate_pence.with_label_values(&extend(labels, period)).set(pence);
+        }
+        for (window, grams) in summary.carbon() {
+            self.carbon_avoided_grams.with_label_values(&extend(labels, window)).set(grams);
+        }
+    }
+
+    /// `fuel`, `meter_point` and the custom label values of a meter, empty
+    /// where the meter doesn't set a label.
+    fn label_values<'a>(&'a self, meter: &'a Meter) -> Vec<&'a str> {
+        let mut values = vec![meter.fuel.as_str(), meter.meter_point.as_str()];
+        values.extend(self.labels.iter().map(|name| meter.labels.get(name).map_or("", String::as_str)));
+        values
+    }
+}
+
+fn extend<'a>(labels: &[&'a str], last: &'a str) -> Vec<&'a str> {
+    labels.iter().copied().chain([last]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meter::test_meter;
+
+    fn meter(fuel: Fuel, meter_point: &str, property: &str) -> Meter {
+        let mut meter = test_meter(fuel, meter_point);
+        meter.labels.insert("property".to_string(), property.to_string());
+        meter
+    }
+
+    fn sample_summaries() -> Vec<Summary> {
+        let mut flat = Summary::new(meter(Fuel::Electricity, "1200000000001", "flat"));
+        flat.set_usage("2w", 42.5);
+        flat.set_carbon("1y", 9000.0);
+        flat.set_carbon_average("1y", 9500.0);
+        flat.set_generation("2w", [("wind".to_string(), 17.0), ("gas".to_string(), 12.75)].into());
+        flat.set_unit_rate("next", -1.5);
+
+        let mut cottage = Summary::new(meter(Fuel::Electricity, "1200000000002", "cottage"));
+        cottage.set_usage("2w", 7.5);
+        cottage.set_carbon("1y", 1000.0);
+
+        let mut gas = Summary::new(meter(Fuel::Gas, "3000000001", "flat"));
+        gas.set_usage("1y", 1200.0);
+        gas.set_cost("1y", 8000.0);
+        gas.set_standing_charge(31.5);
+        gas.set_volume("1y", 105.6);
+        gas.set_carbon("1y", 219_480.0);
+
+        let mut solar = Summary::new(Meter { export: true, ..meter(Fuel::Electricity, "1400000000009", "flat") });
+        solar.set_usage("2w", 60.0);
+        solar.set_cost("2w", 900.0);
+        solar.set_carbon("1y", 4000.0);
+        solar.set_unit_rate("current", 15.0);
+        flat.set_net_import("2w", -17.5);
+
+        vec![flat, cottage, gas, solar]
+    }
+
+    fn labels() -> Vec<String> {
+        vec!["floor".to_string(), "property".to_string()]
+    }
+
+    fn family_names(metrics: &Metrics) -> Vec<String> {
+        metrics
+            .registry
+            .gather()
+            .iter()
+            .map(|family| family.name().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_carbon_region_info_replaced() {
+        let metrics = Metrics::new(false, &[]).unwrap();
+        let region = |region, name: &str, postcode: Option<&str>| ResolvedRegion {
+            region,
+            name: name.to_string(),
+            postcode: postcode.map(str::to_string),
+        };
+        metrics.set_carbon_region(&region(carbonintensity::Region::England, "England", None));
+        metrics.set_carbon_region(&region(carbonintensity::Region::London, "London", Some("SW1A")));
+
+        assert_eq!(metrics.carbon_region_info.with_label_values(&["13", "London", "SW1A"]).get(), 1.0);
+        let series = metrics.registry.gather().into_iter().find(|f| f.name() == "octopus_energy_carbon_region_info").unwrap();
+        assert_eq!(series.get_metric().len(), 1);
+    }
+
+    #[test]
+    fn test_carbon_forecast_updated() {
+        use crate::carbon_intensity::{GenerationShare, SlotIntensity};
+
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        let slot = |from: &str, to: &str, forecast: f64, index: &str| IntensitySlot {
+            from: at(from),
+            to: at(to),
+            intensity: SlotIntensity { forecast, index: index.to_string() },
+            generation_mix: vec![
+                GenerationShare { fuel: "wind".to_string(), percent: 100.0 - forecast / 4.0 },
+                GenerationShare { fuel: "gas".to_string(), percent: forecast / 4.0 },
+            ],
+        };
+        let slots = [
+            slot("2025-08-01T12:00:00Z", "2025-08-01T12:30:00Z", 180.0, "moderate"),
+            slot("2025-08-01T12:30:00Z", "2025-08-01T13:00:00Z", 40.0, "very low"),
+            slot("2025-08-01T13:00:00Z", "2025-08-01T13:30:00Z", 60.0, "low"),
+        ];
+        let windows = ["30min".parse().unwrap(), "3h".parse().unwrap()];
+        let metrics = Metrics::new(false, &[]).unwrap();
+        metrics.update_carbon_forecast(&slots, &windows, at("2025-08-01T12:10:00Z"));
+
+        assert_eq!(metrics.carbon_intensity.get(), 180.0);
+        assert_eq!(metrics.carbon_intensity_index.with_label_values(&["moderate"]).get(), 1.0);
+        assert_eq!(metrics.carbon_intensity_index.with_label_values(&["very low"]).get(), 0.0);
+        assert_eq!(metrics.generation_mix_percent.with_label_values(&["gas"]).get(), 45.0);
+        assert_eq!(metrics.carbon_intensity_forecast.with_label_values(&["60"]).get(), 60.0);
+        assert_eq!(metrics.lowest_carbon_window_start.with_label_values(&["30min"]).get(), at("2025-08-01T12:30:00Z").timestamp() as f64);
+        assert_eq!(metrics.lowest_carbon_window_intensity.with_label_values(&["30min"]).get(), 40.0);
+
+        // Half an hour on, the first slot has passed and 3h still doesn't fit
+        metrics.update_carbon_forecast(&slots, &windows, at("2025-08-01T12:40:00Z"));
+        assert_eq!(metrics.carbon_intensity.get(), 40.0);
+        assert_eq!(metrics.generation_mix_percent.with_label_values(&["wind"]).get(), 90.0);
+        let forecast = metrics.registry.gather().into_iter().find(|f| f.name() == "octopus_energy_carbon_intensity_forecast_grams_per_kwh").unwrap();
+        assert_eq!(forecast.get_metric().len(), 2);
+        let lowest = metrics.registry.gather().into_iter().find(|f| f.name() == "octopus_energy_carbon_lowest_window_start_timestamp_seconds").unwrap();
+        assert_eq!(lowest.get_metric().len(), 1);
+    }
+
+    #[test]
+    fn test_labelled_metrics_updated() {
+        let metrics = Metrics::new(false, &labels()).unwrap();
+        metrics.update(&sample_summaries());
+
+        let usage = |values: &[&str]| metrics.usage_kwh.with_label_values(values).get();
+        assert_eq!(usage(&["electricity", "1200000000001", "", "flat", "2w"]), 42.5);
+        assert_eq!(usage(&["electricity", "1200000000002", "", "cottage", "2w"]), 7.5);
+        assert_eq!(usage(&["gas", "3000000001", "", "flat", "1y"]), 1200.0);
+        assert_eq!(metrics.gas_volume_m3.with_label_values(&["gas", "3000000001", "", "flat", "1y"]).get(), 105.6);
+        assert_eq!(metrics.carbon_grams.with_label_values(&["electricity", "1200000000001", "", "flat", "1y"]).get(), 9000.0);
+        assert_eq!(metrics.carbon_average_grams.with_label_values(&["electricity", "1200000000001", "", "flat", "1y"]).get(), 9500.0);
+        assert_eq!(metrics.usage_by_generation_kwh.with_label_values(&["electricity", "1200000000001", "", "flat", "2w", "wind"]).get(), 17.0);
+        assert_eq!(metrics.cost_pence.with_label_values(&["gas", "3000000001", "", "flat", "1y"]).get(), 8000.0);
+        assert_eq!(metrics.standing_charge_pence.with_label_values(&["gas", "3000000001", "", "flat"]).get(), 31.5);
+        assert_eq!(metrics.unit_rate_pence.with_label_values(&["electricity", "1200000000001", "", "flat", "next"]).get(), -1.5);
+        assert!(!family_names(&metrics).contains(&"octopus_electricity_usage_2w_kwh".to_string()));
+    }
+
+    #[test]
+    fn test_fetched_pages_add_up_over_polls() {
+        let metrics = Metrics::new(false, &[]).unwrap();
+        let mut summary = Summary::new(test_meter(Fuel::Electricity, "1200000000001"));
+        summary.set_fetched(2, 17_520);
+        metrics.update(std::slice::from_ref(&summary));
+        summary.set_fetched(1, 96);
+        metrics.update(&[summary]);
+
+        assert_eq!(metrics.consumption_pages.with_label_values(&["electricity", "1200000000001"]).get(), 3);
+        assert_eq!(metrics.consumption_readings.with_label_values(&["electricity", "1200000000001"]).get(), 17_616);
+    }
+
+    #[test]
+    fn test_carbon_totals_per_household() {
+        let metrics = Metrics::new(false, &labels()).unwrap();
+        metrics.update(&sample_summaries());
+
+        let carbon = |values: &[&str]| metrics.carbon_grams.with_label_values(values).get();
+        assert_eq!(carbon(&["gas", "3000000001", "", "flat", "1y"]), 219_480.0);
+        // Exported energy's avoided carbon doesn't come off the footprint
+        assert_eq!(carbon(&["total", "", "", "flat", "1y"]), 228_480.0);
+        assert_eq!(carbon(&["total", "", "", "cottage", "1y"]), 1000.0);
+
+        // Without a region electricity has no carbon, so neither does the total
+        let mut summaries = sample_summaries();
+        summaries[0] = Summary::new(summaries[0].meter.clone());
+        let metrics = Metrics::new(false, &labels()).unwrap();
+        metrics.update(&summaries);
+        let family = metrics.registry.gather().into_iter().find(|f| f.name() == "octopus_energy_carbon_emissions_grams").unwrap();
+        let totals = family.get_metric().iter().filter(|m| m.get_label().iter().any(|l| l.value() == "total")).count();
+        assert_eq!(totals, 1);
+    }
+
+    #[test]
+    fn test_failures_counted_by_source_and_window() {
+        use crate::error::Error;
+
+        let metrics = Metrics::new(false, &[]).unwrap();
+        let failure = |source: Option<&str>, window: Option<&str>, error: Error| Failure {
+            source: source.map(str::to_string),
+            window: window.map(str::to_string),
+            error,
+        };
+        metrics.record_failures(&[
+            failure(Some("A-1234ABCD"), Some("1y"), Error::Octopus { status: Some(502), retry_after: None, message: "Bad Gateway".to_string() }),
+            failure(Some("A-1234ABCD"), Some("1y"), Error::octopus("timed out")),
+            failure(None, None, Error::carbon_intensity("timed out")),
+        ]);
+
+        assert_eq!(metrics.errors.get(), 3);
+        assert_eq!(metrics.poll_errors.with_label_values(&["A-1234ABCD", "1y", "octopus_api"]).get(), 2);
+        assert_eq!(metrics.poll_errors.with_label_values(&["", "", "carbon_intensity_api"]).get(), 1);
+    }
+
+    #[test]
+    fn test_export_meters_use_export_families() {
+        let metrics = Metrics::new(false, &labels()).unwrap();
+        metrics.update(&sample_summaries());
+
+        let solar = |last: &'static str| ["electricity", "1400000000009", "", "flat", last];
+        assert_eq!(metrics.export_kwh.with_label_values(&solar("2w")).get(), 60.0);
+        assert_eq!(metrics.export_earnings_pence.with_label_values(&solar("2w")).get(), 900.0);
+        assert_eq!(metrics.export_rate_pence.with_label_values(&solar("current")).get(), 15.0);
+        assert_eq!(metrics.carbon_avoided_grams.with_label_values(&solar("1y")).get(), 4000.0);
+        assert_eq!(metrics.net_import_kwh.with_label_values(&["electricity", "1200000000001", "", "flat", "2w"]).get(), -17.5);
+
+        // Exports never show up as usage
+        let usage = metrics.registry.gather().into_iter().find(|family| family.name() == "octopus_energy_usage_kwh").unwrap();
+        assert_eq!(usage.get_metric().len(), 3);
+    }
+
+    #[test]
+    fn test_legacy_metrics_total_every_meter() {
+        let metrics = Metrics::new(true, &[]).unwrap();
+        metrics.update(&sample_summaries());
+
+        let names = family_names(&metrics);
+        assert!(names.contains(&"octopus_energy_usage_kwh".to_string()));
+        assert!(names.contains(&"octopus_electricity_usage_2w_kwh".to_string()));
+        assert!(names.contains(&"octopus_gas_usage_last_1_year_kwh".to_string()));
+
+        let gauge = |name: &str| {
+            metrics
+                .legacy
+                .iter()
+                .find(|(def, _)| def.name == name)
+                .map(|(_, g)| g.get())
+                .unwrap()
+        };
+        assert_eq!(gauge("octopus_electricity_usage_2w_kwh"), 50.0);
+        assert_eq!(gauge("octopus_gas_usage_last_1_year_kwh"), 1200.0);
+        assert_eq!(gauge("octopus_energy_carbon_emissions_last_1_months_grams"), 10000.0);
+    }
+
+    #[test]
+    fn test_legacy_names_unique() {
+        let mut names: Vec<_> = LEGACY_GAUGES.iter().map(|def| def.name).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), 27);
+    }
+}
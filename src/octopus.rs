@@ -0,0 +1,169 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use log::debug;
+use reqwest::Client as HttpClient;
+use serde::de::DeserializeOwned;
+
+use crate::error::{Error, Result};
+use crate::retry::{self, Api, Retrier};
+
+pub const DEFAULT_BASE_URL: &str = "https://api.octopus.energy/v1/";
+
+/// Minimal Octopus REST client, used for every request.
+///
+/// `octopust::Client` pins its base URL and drops the response headers, so
+/// requests go through here instead, where they can run against a local
+/// mock server and a `Retry-After` on a throttled response is honoured.
+#[derive(Clone)]
+pub struct OctopusApi {
+    http: HttpClient,
+    base_url: String,
+    api_key: String,
+    retrier: Arc<Retrier>,
+}
+
+impl OctopusApi {
+    pub fn new<S: Into<String>>(api_key: S) -> Self {
+        OctopusApi {
+            http: HttpClient::new(),
+            base_url: DEFAULT_BASE_URL.to_string(),
+            api_key: api_key.into(),
+            retrier: Arc::new(Retrier::default()),
+        }
+    }
+
+    /// Paces and retries requests with `retrier`, shared with the other clients.
+    pub fn with_retrier(mut self, retrier: Arc<Retrier>) -> Self {
+        self.retrier = retrier;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_base_url<S: Into<String>>(mut self, base_url: S) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    /// GETs `path` relative to the base URL and parses the JSON body.
+    pub async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        params: &[(&str, String)],
+    ) -> Result<T> {
+        let url = format!("{}/{}", self.base_url.trim_end_matches('/'), path.trim_start_matches('/'));
+        self.get_url(&url, params).await
+    }
+
+    /// GETs the `next` link of a paged response. It has to point back at
+    /// the same API, so the key is never sent anywhere else.
+    pub async fn get_next<T: DeserializeOwned>(&self, url: &str) -> Result<T> {
+        let base = self.base_url.trim_end_matches('/');
+        if !url.strip_prefix(base).is_some_and(|rest| rest.starts_with('/')) {
+            return Err(Error::Inconsistent(format!("next page {url} isn't on {base}")));
+        }
+        self.get_url(url, &[]).await
+    }
+
+    async fn get_url<T: DeserializeOwned>(&self, url: &str, params: &[(&str, String)]) -> Result<T> {
+        debug!("GET {url}");
+        let body = self.retrier.run(Api::Octopus, || self.send(url, params)).await?;
+        serde_json::from_slice(&body).map_err(|e| Error::Parse(format!("JSON from {url}: {e}")))
+    }
+
+    /// Sends one GET and returns the body of a successful response.
+    async fn send(&self, url: &str, params: &[(&str, String)]) -> Result<Vec<u8>> {
+        let resp = self
+            .http
+            .get(url)
+            .basic_auth(&self.api_key, None::<&str>)
+            .query(params)
+            .send()
+            .await
+            .map_err(Error::octopus)?;
+        let status = resp.status();
+        let retry_after = retry::retry_after(resp.headers(), Utc::now());
+        let body = resp.bytes().await.map_err(Error::octopus)?;
+
+        if !status.is_success() {
+            return Err(Error::Octopus {
+                status: Some(status.as_u16()),
+                retry_after,
+                message: format!("error status {status}: {}", String::from_utf8_lossy(&body)),
+            });
+        }
+        Ok(body.to_vec())
+    }
+}
+
+/// Serves canned JSON bodies by request path on an ephemeral local port and
+/// returns the base URL to point an `OctopusApi` at.
+#[cfg(test)]
+pub fn mock_server(responses: Vec<(&'static str, serde_json::Value)>) -> String {
+    use warp::Filter;
+
+    let responses = std::sync::Arc::new(responses);
+    let routes = warp::path::full().map(move |path: warp::path::FullPath| {
+        match responses.iter().find(|(p, _)| *p == path.as_str()) {
+            Some((_, body)) => warp::reply::with_status(warp::reply::json(body), warp::http::StatusCode::OK),
+            None => warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"detail": "Not found."})),
+                warp::http::StatusCode::NOT_FOUND,
+            ),
+        }
+    });
+
+    let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    format!("http://{addr}/v1/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    struct Detail {
+        detail: String,
+    }
+
+    #[tokio::test]
+    async fn test_get_parses_json() {
+        let base_url = mock_server(vec![("/v1/ping/", serde_json::json!({"detail": "pong"}))]);
+        let api = OctopusApi::new("key").with_base_url(base_url);
+
+        let detail: Detail = api.get("ping/", &[]).await.unwrap();
+        assert_eq!(detail.detail, "pong");
+    }
+
+    #[tokio::test]
+    async fn test_get_reports_error_status() {
+        let base_url = mock_server(vec![]);
+        let api = OctopusApi::new("key").with_base_url(base_url);
+
+        let err = api.get::<Detail>("missing/", &[]).await.unwrap_err();
+        assert!(matches!(err, Error::Octopus { status: Some(404), .. }), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_get_reads_retry_after() {
+        use warp::Filter;
+
+        let routes = warp::any().map(|| {
+            warp::reply::with_header(
+                warp::reply::with_status("slow down", warp::http::StatusCode::TOO_MANY_REQUESTS),
+                "retry-after",
+                "7",
+            )
+        });
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let retrier = Retrier::new(retry::RetrySettings { retries: 0, ..Default::default() });
+        let api = OctopusApi::new("key").with_base_url(format!("http://{addr}/v1/")).with_retrier(Arc::new(retrier));
+
+        let err = api.get::<Detail>("ping/", &[]).await.unwrap_err();
+        assert_eq!(err.status(), Some(429));
+        assert_eq!(err.retry_after(), Some(std::time::Duration::from_secs(7)));
+    }
+}
@@ -0,0 +1,777 @@
+use std::{collections::{BTreeMap, BTreeSet}, sync::{Arc, Mutex}, time::Duration};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use chrono_tz::Tz;
+use log::{error, info, warn};
+use tokio::time;
+
+use crate::account::{self, Account};
+use crate::cache::{Coverage, ReadingCache};
+use crate::carbon_intensity::{self, CarbonIntensityApi, IntensitySlot, Location, LowestWindow, ResolvedRegion};
+use crate::clock::Clock;
+use crate::config::AccountConfig;
+use crate::error::{self, Error, Failure};
+use crate::export::{self, Slot};
+use crate::gas::GasSettings;
+use crate::health::Health;
+use crate::meter::{self, Meter};
+use crate::metrics::Metrics;
+use crate::octopus::OctopusApi;
+use crate::retry::Retrier;
+use crate::store::Store;
+use crate::tariff::{self, Tariff, TariffRates, TariffSettings};
+use crate::usage::{self, Fuel, Summary};
+use crate::window::{self, Window};
+
+/// Days of readings fetched at a time by `backfill`, about a page's worth.
+const BACKFILL_CHUNK_DAYS: i64 = 31;
+
+/// An account, or a set of hand-configured meters, with the clients used to read it.
+pub struct Source {
+    api: OctopusApi,
+    config: AccountConfig,
+}
+
+impl Source {
+    pub fn new(api_key: &str, config: AccountConfig) -> Self {
+        Source { api: OctopusApi::new(api_key), config }
+    }
+
+    /// Paces and retries the account's requests with `retrier`, shared with every other source.
+    pub fn with_retrier(mut self, retrier: Arc<Retrier>) -> Self {
+        self.api = self.api.with_retrier(retrier);
+        self
+    }
+
+    /// How the source is named in logs.
+    fn name(&self) -> String {
+        match &self.config.number {
+            Some(number) => format!("account {number}"),
+            None => "configured meters".to_string(),
+        }
+    }
+}
+
+/// Figures of every meter read by a poll, and what couldn't be read.
+#[derive(Debug, Default)]
+pub struct Poll {
+    pub summaries: Vec<Summary>,
+    pub failures: Vec<Failure>,
+}
+
+impl Poll {
+    /// Whether any meter's usage was read, so there's something fresh to export.
+    pub fn succeeded(&self) -> bool {
+        self.summaries.iter().any(|summary| summary.usage().next().is_some())
+    }
+}
+
+/// Periodically fetches usage of every meter for every reporting window and
+/// updates the metrics.
+pub struct Poller {
+    sources: Vec<Source>,
+    clock: Arc<dyn Clock>,
+    windows: Vec<Window>,
+    timezone: Tz,
+    location: Location,
+    metrics: Arc<Metrics>,
+    tariffs: TariffSettings,
+    gas: GasSettings,
+    carbon_api: CarbonIntensityApi,
+    lowest_windows: Vec<LowestWindow>,
+    /// `location`'s region, once it's been looked up.
+    region: Mutex<Option<ResolvedRegion>>,
+    health: Option<Arc<Health>>,
+    cache: Option<ReadingCache>,
+}
+
+impl Poller {
+    pub fn new(
+        sources: Vec<Source>,
+        clock: Arc<dyn Clock>,
+        windows: Vec<Window>,
+        timezone: Tz,
+        location: Location,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let mut unique: Vec<Window> = Vec::new();
+        for window in windows {
+            if unique.iter().any(|w| w.label() == window.label()) {
+                warn!("Ignoring duplicate window '{window}'");
+                continue;
+            }
+            unique.push(window);
+        }
+
+        Poller {
+            sources,
+            clock,
+            windows: unique,
+            timezone,
+            location,
+            metrics,
+            tariffs: TariffSettings::default(),
+            gas: GasSettings::default(),
+            carbon_api: CarbonIntensityApi::new(),
+            lowest_windows: carbon_intensity::default_lowest_windows(),
+            region: Mutex::new(None),
+            health: None,
+            cache: None,
+        }
+    }
+
+    /// Prices usage with these tariffs, see `TariffSettings`.
+    pub fn with_tariffs(mut self, tariffs: TariffSettings) -> Self {
+        self.tariffs = tariffs;
+        self
+    }
+
+    /// Converts gas meters with these defaults, see `GasSettings`.
+    pub fn with_gas(mut self, gas: GasSettings) -> Self {
+        self.gas = gas;
+        self
+    }
+
+    /// Looks for the greenest stretch of each of these durations in the forecast.
+    pub fn with_lowest_windows(mut self, windows: Vec<LowestWindow>) -> Self {
+        self.lowest_windows = windows;
+        self
+    }
+
+    /// Paces and retries carbon intensity requests with `retrier`, shared with the sources.
+    pub fn with_retrier(mut self, retrier: Arc<Retrier>) -> Self {
+        self.carbon_api = self.carbon_api.with_retrier(retrier);
+        self
+    }
+
+    /// Keeps readings in `cache` between polls, so each only fetches what's new.
+    pub fn with_cache(mut self, cache: ReadingCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Reports the outcome of every poll to `health`.
+    pub fn with_health(mut self, health: Arc<Health>) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// Start and end of every window as of `now`.
+    pub fn periods(&self, now: DateTime<Utc>) -> Vec<(String, DateTime<Utc>, DateTime<Utc>)> {
+        self.windows
+            .iter()
+            .map(|window| {
+                let (start, end) = window.range(now, self.timezone);
+                (window.label().to_string(), start, end)
+            })
+            .collect()
+    }
+
+    /// Runs a single fetch of every meter, with window starts recomputed from
+    /// the clock. Nothing that fails stops the rest of the poll; it's left
+    /// out of the figures and recorded in `failures`.
+    pub async fn poll(&self) -> Poll {
+        let now = self.clock.now();
+        let periods = self.periods(now);
+
+        // Rates run to the end of today, and a slot beyond, for today's
+        // spread and the next slot's price
+        let today = window::get_day_range(now, self.timezone);
+        let earliest = periods.iter().map(|(_, from, _)| *from).min().unwrap_or(now);
+        let rates_to = today.1 + ChronoDuration::minutes(30);
+
+        let mut failures = Vec::new();
+        let region = self.carbon_region(&mut failures).await;
+        if let Some(region) = region {
+            self.update_carbon_forecast(region, now, &mut failures).await;
+        }
+
+        // Meters on the same tariff share its rates, and every electricity
+        // meter the regional intensity, fetched for the first that needs it
+        let mut rates = BTreeMap::new();
+        let mut history = None;
+        let mut summaries = Vec::new();
+
+        for source in &self.sources {
+            let first = summaries.len();
+            let account = self.fetch_account(source, &mut failures).await;
+            let meters = meter::resolve_meters(&source.config, account.as_ref(), now, &self.gas);
+            if meters.is_empty() {
+                warn!("No meters found to poll for {}", source.name());
+            }
+
+            for meter in meters {
+                let tariff_rates = self.tariff_rates(source, &meter, &mut rates, earliest, rates_to, &mut failures).await;
+                if meter.fuel == Fuel::Electricity
+                    && history.is_none()
+                    && let Some(region) = region
+                {
+                    history = Some(self.carbon_history(region, &periods, &mut failures).await);
+                }
+                let mut summary = usage::fetch_consumption(
+                    &source.api,
+                    &meter,
+                    &periods,
+                    history.as_ref().and_then(Option::as_deref),
+                    tariff_rates,
+                    self.cache.as_ref(),
+                ).await;
+                for (window, error) in summary.failures() {
+                    match window {
+                        Some(window) => error!("Error reading meter point {} for {window}: {error}", meter.meter_point),
+                        None => error!("Error reading meter point {}: {error}", meter.meter_point),
+                    }
+                    failures.push(Failure { source: source.config.number.clone(), window: window.map(str::to_string), error: error.clone() });
+                }
+
+                if let Some(tariff_rates) = tariff_rates {
+                    set_current_rates(&mut summary, tariff_rates, now, today);
+                }
+                set_gas_carbon(&mut summary, &self.gas);
+                summaries.push(summary);
+            }
+            set_net_import(&mut summaries[first..]);
+        }
+
+        if summaries.is_empty() {
+            let error = Error::Config("No meters to poll, set OCTOPUS_ACCOUNT_NUMBER or MPAN/E_SERIAL_NO and MPRN/G_SERIAL_NO".to_string());
+            error!("{error}");
+            failures.push(Failure { source: None, window: None, error });
+        }
+        Poll { summaries, failures }
+    }
+
+    /// Regional intensity across every window, in one go however many
+    /// meters it's shared by.
+    async fn carbon_history(
+        &self,
+        region: carbonintensity::Region,
+        periods: &[(String, DateTime<Utc>, DateTime<Utc>)],
+        failures: &mut Vec<Failure>,
+    ) -> Option<Vec<IntensitySlot>> {
+        let from = periods.iter().map(|(_, from, _)| *from).min()?;
+        let to = periods.iter().map(|(_, _, to)| *to).max()?;
+        match self.intensity_history(region, from, to).await {
+            Ok(slots) => Some(slots),
+            Err(e) => {
+                error!("Failed to fetch carbon intensity history: {e}");
+                failures.push(Failure { source: None, window: None, error: e });
+                None
+            }
+        }
+    }
+
+    /// Regional intensity from `from` to `to`. With a database only what's
+    /// new since it was last fetched comes from the API, the rest from the
+    /// database.
+    async fn intensity_history(&self, region: carbonintensity::Region, from: DateTime<Utc>, to: DateTime<Utc>) -> error::Result<Vec<IntensitySlot>> {
+        let Some((store, recheck)) = self.store() else {
+            return self.carbon_api.history(region, from, to).await;
+        };
+        let id = region as u8;
+        let fetch_from = stored_from(store.intensity_coverage(id), from, recheck);
+        if fetch_from < to {
+            let slots = self.carbon_api.history(region, fetch_from, to).await?;
+            store.save_intensity(id, fetch_from, to, &slots)?;
+        }
+        store.intensity(id, from, to)
+    }
+
+    /// The database history is kept in, and how far back each poll checks again.
+    fn store(&self) -> Option<(&Store, ChronoDuration)> {
+        let cache = self.cache.as_ref()?;
+        Some((cache.store()?, cache.recheck()))
+    }
+
+    /// The region carbon intensity is read for, looked up on the first poll
+    /// that can reach the API. Until then carbon is left out.
+    async fn carbon_region(&self, failures: &mut Vec<Failure>) -> Option<carbonintensity::Region> {
+        if let Some(resolved) = self.region.lock().ok()?.as_ref() {
+            return Some(resolved.region);
+        }
+        match self.carbon_api.resolve(&self.location).await {
+            Ok(resolved) => {
+                info!("Reading carbon intensity for region {} ({})", resolved.name, resolved.id());
+                self.metrics.set_carbon_region(&resolved);
+                let region = resolved.region;
+                if let Ok(mut cached) = self.region.lock() {
+                    *cached = Some(resolved);
+                }
+                Some(region)
+            }
+            Err(e) => {
+                error!("Error looking up the carbon intensity region of {:?}: {e}", self.location);
+                failures.push(Failure { source: None, window: None, error: e });
+                None
+            }
+        }
+    }
+
+    /// Live intensity and the forecast, which don't depend on any meter. A
+    /// failure is recorded and logged, leaving the previous values in place.
+    async fn update_carbon_forecast(&self, region: carbonintensity::Region, now: DateTime<Utc>, failures: &mut Vec<Failure>) {
+        match self.carbon_api.forecast(region, now).await {
+            Ok(slots) => self.metrics.update_carbon_forecast(&slots, &self.lowest_windows, now),
+            Err(e) => {
+                error!("Error fetching the carbon intensity forecast: {e}");
+                failures.push(Failure { source: None, window: None, error: e });
+            }
+        }
+    }
+
+    /// The source's account, if it has one. A failed lookup is recorded and
+    /// logged, and the poll carries on with the configured meters alone.
+    async fn fetch_account(&self, source: &Source, failures: &mut Vec<Failure>) -> Option<Account> {
+        let number = source.config.number.as_deref()?;
+        match account::fetch_account(&source.api, number).await {
+            Ok(account) => Some(account),
+            Err(e) => {
+                error!("Error fetching account {number}: {e}");
+                failures.push(Failure { source: Some(number.to_string()), window: None, error: e });
+                None
+            }
+        }
+    }
+
+    /// Rates of the meter's tariff: set on the meter, by flag, or found on
+    /// the account, in that order. A meter that can't be priced gets `None`,
+    /// so only its cost is missing. Rates are fetched once per tariff per poll.
+    async fn tariff_rates<'a>(
+        &self,
+        source: &Source,
+        meter: &Meter,
+        rates: &'a mut BTreeMap<String, Option<TariffRates>>,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        failures: &mut Vec<Failure>,
+    ) -> Option<&'a TariffRates> {
+        // The tariff flags are for import meters
+        let flag = if meter.export { None } else { self.tariffs.configured(meter.fuel) };
+        let tariff = match (&meter.tariff, flag, &meter.tariff_code) {
+            (Some(tariff), _, _) | (None, Some(tariff), _) => tariff.clone(),
+            (None, None, Some(code)) => match Tariff::parse_for(code, meter.fuel) {
+                Ok(tariff) => tariff,
+                Err(e) => {
+                    warn!("Can't price usage of meter point {}: {e}", meter.meter_point);
+                    return None;
+                }
+            },
+            (None, None, None) => return None,
+        };
+
+        if !rates.contains_key(&tariff.tariff_code) {
+            let fetched = match self.fetch_rates(source, &tariff, from, to).await {
+                Ok(tariff_rates) => Some(tariff_rates),
+                Err(e) => {
+                    error!("Error fetching rates for tariff {}: {e}", tariff.tariff_code);
+                    failures.push(Failure { source: source.config.number.clone(), window: None, error: e });
+                    None
+                }
+            };
+            rates.insert(tariff.tariff_code.clone(), fetched);
+        }
+        rates[&tariff.tariff_code].as_ref()
+    }
+
+    /// Rates of `tariff` from `from` to `to`, with a database only fetching
+    /// what's new since they were last fetched.
+    async fn fetch_rates(&self, source: &Source, tariff: &Tariff, from: DateTime<Utc>, to: DateTime<Utc>) -> error::Result<TariffRates> {
+        let Some((store, recheck)) = self.store() else {
+            return tariff::fetch_tariff_rates(&source.api, tariff, from, to).await;
+        };
+        let code = &tariff.tariff_code;
+        let fetch_from = stored_from(store.rates_coverage(code), from, recheck);
+        if fetch_from < to {
+            let fetched = tariff::fetch_tariff_rates(&source.api, tariff, fetch_from, to).await?;
+            store.save_tariff_rates(code, fetch_from, to, &fetched)?;
+        }
+        store.tariff_rates(code, from, to)
+    }
+
+    /// Fills the database with every meter's readings from `from` to `to`,
+    /// and the regional intensity and tariff rates over the same span.
+    /// Readings are fetched a month at a time, newest first, so an
+    /// interrupted backfill keeps what it got and the next one picks up
+    /// where it stopped. A meter that fails is left for the next run while
+    /// the rest carry on.
+    pub async fn backfill(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<Failure> {
+        let mut failures = Vec::new();
+        let Some((store, _)) = self.store() else {
+            let error = Error::Config("backfill needs a database, set with --database".to_string());
+            failures.push(Failure { source: None, window: None, error });
+            return failures;
+        };
+
+        if let Some(region) = self.carbon_region(&mut failures).await {
+            let id = region as u8;
+            for (chunk_from, chunk_to) in backfill_chunks(from, to, held(store.intensity_coverage(id))) {
+                let saved = match self.carbon_api.history(region, chunk_from, chunk_to).await {
+                    Ok(slots) => store.save_intensity(id, chunk_from, chunk_to, &slots),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = saved {
+                    error!("Error backfilling carbon intensity from {chunk_from}: {e}");
+                    failures.push(Failure { source: None, window: None, error: e });
+                    break;
+                }
+            }
+        }
+
+        let mut rates = BTreeMap::new();
+        for source in &self.sources {
+            let account = self.fetch_account(source, &mut failures).await;
+            let meters = meter::resolve_meters(&source.config, account.as_ref(), to, &self.gas);
+            if meters.is_empty() {
+                warn!("No meters found to backfill for {}", source.name());
+            }
+
+            for meter in meters {
+                self.tariff_rates(source, &meter, &mut rates, from, to, &mut failures).await;
+
+                let series = Store::meter_series(&meter);
+                let mut count = 0;
+                for (chunk_from, chunk_to) in backfill_chunks(from, to, held(store.coverage(&series))) {
+                    let saved = match usage::fetch_readings(&source.api, &meter, chunk_from, chunk_to).await {
+                        Ok((readings, _)) => {
+                            count += readings.len();
+                            store.save_readings(&meter, chunk_from, chunk_to, &readings)
+                        }
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = saved {
+                        error!("Error backfilling meter point {} from {chunk_from}: {e}", meter.meter_point);
+                        failures.push(Failure { source: source.config.number.clone(), window: None, error: e });
+                        break;
+                    }
+                }
+                info!("Backfilled {count} readings of {} meter point {}", meter.fuel.as_str(), meter.meter_point);
+            }
+        }
+        failures
+    }
+
+    /// Every half-hour slot of each meter of `fuel`, or of both fuels, from
+    /// `from` to `to` with its cost and carbon, read the same way a poll
+    /// reads them. Meters that fail are left out and recorded in the failures.
+    pub async fn export(&self, fuel: Option<Fuel>, from: DateTime<Utc>, to: DateTime<Utc>) -> (Vec<(Meter, Vec<Slot>)>, Vec<Failure>) {
+        let mut failures = Vec::new();
+        let region = self.carbon_region(&mut failures).await;
+
+        let mut rates = BTreeMap::new();
+        let mut intensities = None;
+        let mut exported = Vec::new();
+        for source in &self.sources {
+            let account = self.fetch_account(source, &mut failures).await;
+            let meters = meter::resolve_meters(&source.config, account.as_ref(), to, &self.gas);
+
+            for meter in meters.into_iter().filter(|meter| fuel.is_none_or(|fuel| meter.fuel == fuel)) {
+                let tariff_rates = self.tariff_rates(source, &meter, &mut rates, from, to, &mut failures).await;
+                if meter.fuel == Fuel::Electricity
+                    && intensities.is_none()
+                    && let Some(region) = region
+                {
+                    let periods = [(String::new(), from, to)];
+                    let history = self.carbon_history(region, &periods, &mut failures).await;
+                    intensities = Some(history.as_deref().map(carbon_intensity::intensities));
+                }
+
+                match usage::load_readings(&source.api, &meter, from, to, self.cache.as_ref()).await {
+                    Ok((readings, _, _)) => {
+                        let slots = export::slots(&meter, &readings, tariff_rates, intensities.as_ref().and_then(Option::as_ref), &self.gas);
+                        exported.push((meter, slots));
+                    }
+                    Err(e) => {
+                        error!("Error reading meter point {}: {e}", meter.meter_point);
+                        failures.push(Failure { source: source.config.number.clone(), window: None, error: e });
+                    }
+                }
+            }
+        }
+        (exported, failures)
+    }
+
+    /// Polls forever, sleeping `interval` between polls. Figures a poll
+    /// couldn't read keep their last good values.
+    pub async fn run(self, interval: Duration) {
+        loop {
+            let poll = self.poll().await;
+            self.metrics.update(&poll.summaries);
+            self.metrics.record_failures(&poll.failures);
+            if poll.succeeded() {
+                self.metrics.last_success.set(self.clock.now().timestamp() as f64);
+            }
+            if let Some(health) = &self.health {
+                health.record(poll.succeeded(), &poll.failures);
+            }
+            log_summaries(&poll.summaries);
+            if !poll.failures.is_empty() {
+                warn!("Poll finished with {} errors, keeping the last good values of what failed", poll.failures.len());
+            }
+            info!("[DEBUG] Sleeping for {} seconds before next metrics push.", interval.as_secs());
+            time::sleep(interval).await;
+        }
+    }
+}
+
+/// What the database holds of a series. When it can't say, it's all
+/// fetched again.
+fn held(coverage: error::Result<Option<Coverage>>) -> Option<Coverage> {
+    coverage.unwrap_or_else(|e| {
+        warn!("Can't read what the database holds, fetching it again: {e}");
+        None
+    })
+}
+
+/// Where to fetch a stored series from for everything from `from` onwards.
+fn stored_from(coverage: error::Result<Option<Coverage>>, from: DateTime<Utc>, recheck: ChronoDuration) -> DateTime<Utc> {
+    held(coverage).map_or(from, |coverage| coverage.fetch_from(from, recheck))
+}
+
+/// Month-long spans from `from` to `to`, newest first, leaving out those
+/// already held in full.
+fn backfill_chunks(from: DateTime<Utc>, to: DateTime<Utc>, held: Option<Coverage>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut chunks = Vec::new();
+    let mut end = to;
+    while end > from {
+        let start = (end - ChronoDuration::days(BACKFILL_CHUNK_DAYS)).max(from);
+        let covered = held.is_some_and(|held| held.from <= start && held.complete_to.is_some_and(|complete_to| end <= complete_to));
+        if !covered {
+            chunks.push((start, end));
+        }
+        end = start;
+    }
+    chunks
+}
+
+/// Standing charge and unit rates of a meter as of `now`, with the spread over `today`.
+fn set_current_rates(
+    summary: &mut Summary,
+    rates: &TariffRates,
+    now: DateTime<Utc>,
+    today: (DateTime<Utc>, DateTime<Utc>),
+) {
+    if let Some(pence) = tariff::rate_at(&rates.standing_charges, now) {
+        summary.set_standing_charge(pence);
+    }
+    if let Some(pence) = tariff::rate_at(&rates.unit_rates, now) {
+        summary.set_unit_rate("current", pence);
+    }
+    if let Some(pence) = rates.next_unit_rate(now) {
+        summary.set_unit_rate("next", pence);
+    }
+    if let Some(stats) = rates.unit_rate_stats(today.0, today.1) {
+        summary.set_unit_rate("today_min", stats.min);
+        summary.set_unit_rate("today_max", stats.max);
+        summary.set_unit_rate("today_average", stats.average);
+    }
+}
+
+/// Emissions of a gas meter's usage in each window, at the gas emission factor.
+fn set_gas_carbon(summary: &mut Summary, gas: &GasSettings) {
+    if summary.fuel() != Fuel::Gas {
+        return;
+    }
+    let carbon: Vec<(String, f64)> = summary.usage().map(|(window, kwh)| (window.to_string(), gas.carbon_grams(kwh))).collect();
+    for (window, grams) in carbon {
+        summary.set_carbon(&window, grams);
+    }
+}
+
+/// Import less export of each import electricity meter sharing a property
+/// with export meters. A window an export meter couldn't be read for is
+/// skipped, as the net would come out too high, and every window when it
+/// couldn't be read at all.
+fn set_net_import(summaries: &mut [Summary]) {
+    let mut exported: BTreeMap<(Option<usize>, String), Option<f64>> = BTreeMap::new();
+    // Properties with an export meter that couldn't be read at all
+    let mut failed = BTreeSet::new();
+    for summary in summaries.iter().filter(|summary| summary.meter.export) {
+        for (window, kwh) in summary.usage() {
+            if let Some(total) = exported.entry((summary.meter.property, window.to_string())).or_insert(Some(0.0)) {
+                *total += kwh;
+            }
+        }
+        for (window, _) in summary.failures() {
+            match window {
+                Some(window) => {
+                    exported.insert((summary.meter.property, window.to_string()), None);
+                }
+                None => {
+                    failed.insert(summary.meter.property);
+                }
+            }
+        }
+    }
+
+    for summary in summaries.iter_mut() {
+        if summary.meter.export || summary.fuel() != Fuel::Electricity || failed.contains(&summary.meter.property) {
+            continue;
+        }
+        let net: Vec<(String, f64)> = summary
+            .usage()
+            .filter_map(|(window, kwh)| {
+                let exported = (*exported.get(&(summary.meter.property, window.to_string()))?)?;
+                Some((window.to_string(), kwh - exported))
+            })
+            .collect();
+        for (window, kwh) in net {
+            summary.set_net_import(&window, kwh);
+        }
+    }
+}
+
+fn log_summaries(summaries: &[Summary]) {
+    for summary in summaries {
+        let meter = &summary.meter;
+        let usage: Vec<String> = summary.usage().map(|(window, kwh)| format!("{window}: {kwh:.3}")).collect();
+        if meter.export {
+            info!("[DEBUG] {} Export Summary: export_kwh = {}", meter.meter_point, usage.join(", "));
+            continue;
+        }
+        info!("[DEBUG] {} {} Usage Summary: usage_kwh = {}", meter.fuel.as_str(), meter.meter_point, usage.join(", "));
+
+        let carbon: Vec<String> = summary.carbon().map(|(window, grams)| format!("{window}: {grams:.3}")).collect();
+        if !carbon.is_empty() {
+            info!("[DEBUG] {} Carbon Usage Summary: usage_grams = {}", meter.meter_point, carbon.join(", "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FakeClock;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn poller(clock: Arc<dyn Clock>, windows: &[&str]) -> Poller {
+        Poller::new(
+            vec![Source::new("test", AccountConfig::default())],
+            clock,
+            windows.iter().map(|w| w.parse().unwrap()).collect(),
+            chrono_tz::Europe::London,
+            Location::Region(carbonintensity::Region::England),
+            Arc::new(Metrics::new(false, &[]).unwrap()),
+        )
+    }
+
+    #[test]
+    fn test_periods_roll_forward_with_clock() {
+        let clock = Arc::new(FakeClock::new(at("2025-01-01T12:00:00Z")));
+        let poller = poller(clock.clone(), &["2d", "mtd"]);
+
+        let periods = poller.periods(clock.now());
+        assert_eq!(periods, vec![
+            ("2d".to_string(), at("2024-12-30T12:00:00Z"), at("2025-01-01T12:00:00Z")),
+            ("mtd".to_string(), at("2025-01-01T00:00:00Z"), at("2025-01-01T12:00:00Z")),
+        ]);
+
+        // A week later the 2d window must still only cover two days
+        clock.advance(chrono::Duration::weeks(1));
+        let periods = poller.periods(clock.now());
+        assert_eq!(periods, vec![
+            ("2d".to_string(), at("2025-01-06T12:00:00Z"), at("2025-01-08T12:00:00Z")),
+            ("mtd".to_string(), at("2025-01-01T00:00:00Z"), at("2025-01-08T12:00:00Z")),
+        ]);
+
+        // Crossing into a new month moves the calendar window too
+        clock.advance(chrono::Duration::weeks(4));
+        let periods = poller.periods(clock.now());
+        assert_eq!(periods[1].1, at("2025-02-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn test_current_rates_from_half_hourly_slots() {
+        let now = at("2025-06-01T16:10:00+01:00");
+        let rates = TariffRates::new(
+            vec![
+                tariff::Rate { valid_from: at("2025-06-01T16:00:00+01:00"), valid_to: Some(at("2025-06-01T16:30:00+01:00")), pence: 30.0 },
+                tariff::Rate { valid_from: at("2025-06-01T16:30:00+01:00"), valid_to: Some(at("2025-06-01T17:00:00+01:00")), pence: 12.0 },
+            ],
+            vec![tariff::Rate { valid_from: at("2025-01-01T00:00:00Z"), valid_to: None, pence: 48.0 }],
+        );
+
+        let mut summary = Summary::new(meter::test_meter(Fuel::Electricity, "1200000000001"));
+        set_current_rates(&mut summary, &rates, now, window::get_day_range(now, chrono_tz::Europe::London));
+
+        let unit_rates: Vec<_> = summary.unit_rates().collect();
+        assert_eq!(unit_rates, [("current", 30.0), ("next", 12.0), ("today_average", 21.0), ("today_max", 30.0), ("today_min", 12.0)]);
+        assert_eq!(summary.standing_charge(), Some(48.0));
+    }
+
+    #[test]
+    fn test_net_import_pairs_meters_by_property() {
+        let summary = |fuel: Fuel, meter_point: &str, export: bool, property: usize, kwh: f64| {
+            let mut meter = meter::test_meter(fuel, meter_point);
+            meter.export = export;
+            meter.property = Some(property);
+            let mut summary = Summary::new(meter);
+            summary.set_usage("1w", kwh);
+            summary
+        };
+        let mut summaries = vec![
+            summary(Fuel::Electricity, "1200000000001", false, 0, 50.0),
+            summary(Fuel::Electricity, "1400000000009", true, 0, 80.0),
+            summary(Fuel::Gas, "3000000001", false, 0, 200.0),
+            summary(Fuel::Electricity, "1200000000002", false, 1, 30.0),
+        ];
+        // One of two export meters couldn't be read for 2w, so there's no net figure for it
+        summaries[0].set_usage("2w", 100.0);
+        summaries[1].fail(Some("2w"), Error::octopus("timed out"));
+        let mut battery = summary(Fuel::Electricity, "1400000000010", true, 0, 0.0);
+        battery.set_usage("2w", 20.0);
+        summaries.push(battery);
+        // Nor is there for any window of a property whose export meter couldn't be read at all
+        let mut unread = Summary::new(meter::test_meter(Fuel::Electricity, "1400000000011"));
+        unread.meter.export = true;
+        unread.meter.property = Some(1);
+        unread.fail(None, Error::octopus("timed out"));
+        summaries.push(unread);
+        set_net_import(&mut summaries);
+
+        assert_eq!(summaries[0].net_import().collect::<Vec<_>>(), [("1w", -30.0)]);
+        // Only import electricity on a property with an export meter has a net figure
+        assert!(summaries[1..].iter().all(|summary| summary.net_import().next().is_none()));
+    }
+
+    #[test]
+    fn test_gas_carbon_from_emission_factor() {
+        let gas = GasSettings { emission_factor: 0.2, ..Default::default() };
+        let mut summary = Summary::new(meter::test_meter(Fuel::Gas, "3000000001"));
+        summary.set_usage("1w", 150.0);
+        set_gas_carbon(&mut summary, &gas);
+        assert_eq!(summary.carbon().collect::<Vec<_>>(), [("1w", 30_000.0)]);
+
+        // Electricity carbon comes from the grid's intensity instead
+        let mut summary = Summary::new(meter::test_meter(Fuel::Electricity, "1200000000001"));
+        summary.set_usage("1w", 150.0);
+        set_gas_carbon(&mut summary, &gas);
+        assert!(summary.carbon().next().is_none());
+    }
+
+    #[test]
+    fn test_duplicate_windows_ignored() {
+        let clock = Arc::new(FakeClock::new(at("2025-08-01T12:00:00Z")));
+        let poller = poller(clock.clone(), &["2d", "1w", "2d"]);
+
+        let labels: Vec<_> = poller.periods(clock.now()).into_iter().map(|(label, _, _)| label).collect();
+        assert_eq!(labels, ["2d", "1w"]);
+    }
+
+    #[test]
+    fn test_backfill_chunks_newest_first_skipping_what_is_held() {
+        let (from, to) = (at("2025-01-01T00:00:00Z"), at("2025-03-15T00:00:00Z"));
+        let chunks = backfill_chunks(from, to, None);
+        assert_eq!(chunks, [
+            (at("2025-02-12T00:00:00Z"), to),
+            (at("2025-01-12T00:00:00Z"), at("2025-02-12T00:00:00Z")),
+            (from, at("2025-01-12T00:00:00Z")),
+        ]);
+
+        // An interrupted backfill picks up where it stopped, after the newest month again
+        let held = Coverage { from: at("2025-01-12T00:00:00Z"), complete_to: Some(at("2025-03-14T12:00:00Z")) };
+        assert_eq!(backfill_chunks(from, to, Some(held)), [chunks[0], chunks[2]]);
+    }
+}
@@ -0,0 +1,161 @@
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+use crate::error::Failure;
+use crate::usage::Summary;
+
+/// What a single poll read, for `summary` to print.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub meters: Vec<MeterReport>,
+    pub failures: Vec<FailureReport>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MeterReport {
+    pub fuel: &'static str,
+    pub meter_point: String,
+    pub serial_number: String,
+    pub export: bool,
+    pub windows: Vec<WindowReport>,
+}
+
+/// A meter's figures over one window, `None` where they couldn't be read.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WindowReport {
+    pub window: String,
+    pub kwh: Option<f64>,
+    pub cost_pence: Option<f64>,
+    pub carbon_grams: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FailureReport {
+    pub source: Option<String>,
+    pub window: Option<String>,
+    pub kind: &'static str,
+    pub error: String,
+}
+
+impl Report {
+    /// Every meter's figures over `windows`, in the order they're configured.
+    pub fn new(summaries: &[Summary], failures: &[Failure], windows: &[String]) -> Self {
+        let meters = summaries
+            .iter()
+            .map(|summary| MeterReport {
+                fuel: summary.meter.fuel.as_str(),
+                meter_point: summary.meter.meter_point.clone(),
+                serial_number: summary.meter.serial_number.clone(),
+                export: summary.meter.export,
+                windows: windows
+                    .iter()
+                    .map(|window| WindowReport {
+                        window: window.clone(),
+                        kwh: summary.usage_for(window),
+                        cost_pence: summary.cost_for(window),
+                        carbon_grams: summary.carbon_for(window),
+                    })
+                    .collect(),
+            })
+            .collect();
+        let failures = failures
+            .iter()
+            .map(|failure| FailureReport {
+                source: failure.source.clone(),
+                window: failure.window.clone(),
+                kind: failure.error.kind(),
+                error: failure.error.to_string(),
+            })
+            .collect();
+        Report { meters, failures }
+    }
+
+    /// Writes a table of each meter's windows, then any errors.
+    pub fn write_table(&self, mut out: impl Write) -> io::Result<()> {
+        let width = self
+            .meters
+            .iter()
+            .flat_map(|meter| meter.windows.iter().map(|w| w.window.len()))
+            .max()
+            .unwrap_or(0)
+            .max("window".len());
+        let figure = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+
+        for (i, meter) in self.meters.iter().enumerate() {
+            if i > 0 {
+                writeln!(out)?;
+            }
+            let direction = if meter.export { " export" } else { "" };
+            writeln!(out, "{}{direction} {} ({})", meter.fuel, meter.meter_point, meter.serial_number)?;
+            writeln!(out, "  {:<width$} {:>12} {:>10} {:>12}", "window", "kWh", "cost", "carbon")?;
+            for window in &meter.windows {
+                writeln!(
+                    out,
+                    "  {:<width$} {:>12} {:>10} {:>12}",
+                    window.window,
+                    figure(window.kwh.map(|kwh| format!("{kwh:.3}"))),
+                    figure(window.cost_pence.map(|pence| format!("£{:.2}", pence / 100.0))),
+                    figure(window.carbon_grams.map(|grams| format!("{:.3} kg", grams / 1000.0))),
+                )?;
+            }
+        }
+
+        if !self.failures.is_empty() {
+            writeln!(out)?;
+            writeln!(out, "Errors:")?;
+            for failure in &self.failures {
+                let at = [failure.source.as_deref(), failure.window.as_deref()].into_iter().flatten().collect::<Vec<_>>();
+                if at.is_empty() {
+                    writeln!(out, "  {}", failure.error)?;
+                } else {
+                    writeln!(out, "  {}: {}", at.join(" "), failure.error)?;
+                }
+            }
+        }
+        out.flush()
+    }
+
+    /// Writes the report as a JSON object.
+    pub fn write_json(&self, mut out: impl Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut out, self)?;
+        writeln!(out)?;
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::meter::test_meter;
+    use crate::usage::Fuel;
+
+    #[test]
+    fn test_report_table_and_json() {
+        let mut summary = Summary::new(test_meter(Fuel::Electricity, "1200000000001"));
+        summary.set_usage("2d", 12.5);
+        summary.set_cost("2d", 345.0);
+        summary.set_carbon("2d", 1500.0);
+        summary.fail(Some("cycle:14"), Error::octopus("timed out"));
+        let failures = [Failure { source: Some("A-1234".to_string()), window: Some("cycle:14".to_string()), error: Error::octopus("timed out") }];
+        let report = Report::new(&[summary], &failures, &["2d".to_string(), "cycle:14".to_string()]);
+
+        let mut table = Vec::new();
+        report.write_table(&mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(lines[0].starts_with("electricity 1200000000001"), "{table}");
+        assert_eq!(lines[2].split_whitespace().collect::<Vec<_>>(), ["2d", "12.500", "£3.45", "1.500", "kg"]);
+        assert_eq!(lines[3].split_whitespace().collect::<Vec<_>>(), ["cycle:14", "-", "-", "-"]);
+        assert!(lines.contains(&"Errors:"), "{table}");
+        assert!(table.contains("A-1234 cycle:14: "), "{table}");
+
+        let mut json = Vec::new();
+        report.write_json(&mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["meters"][0]["windows"][0]["cost_pence"], 345.0);
+        assert!(json["meters"][0]["windows"][1]["kwh"].is_null());
+        assert_eq!(json["failures"][0]["kind"], "octopus_api");
+    }
+}
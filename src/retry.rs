@@ -0,0 +1,277 @@
+use std::{
+    future::Future,
+    hash::{BuildHasher, RandomState},
+    sync::Mutex,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use prometheus::IntCounterVec;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use tokio::{sync::Semaphore, time::{self, Instant}};
+
+use crate::error::{Error, Result};
+use crate::metrics::Metrics;
+
+pub const DEFAULT_RETRIES: u32 = 3;
+pub const DEFAULT_MAX_CONCURRENT: usize = 4;
+pub const DEFAULT_PER_SECOND: f64 = 5.0;
+/// Slowest request rate accepted, a request every ~17 minutes.
+pub const MIN_PER_SECOND: f64 = 0.001;
+
+/// Backoff before the first retry, doubled for each one after.
+const BASE_DELAY: Duration = Duration::from_secs(1);
+/// Longest backoff, and the longest `Retry-After` waited for rather than giving up.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Which API a request goes to, for the `api` metric label.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Api {
+    Octopus,
+    CarbonIntensity,
+}
+
+impl Api {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Api::Octopus => "octopus",
+            Api::CarbonIntensity => "carbon_intensity",
+        }
+    }
+}
+
+/// How hard the exporter leans on the APIs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetrySettings {
+    /// Retries of a request after a transient failure, 0 to fail straight away.
+    pub retries: u32,
+    /// Requests in flight at once, across every account and both APIs.
+    pub max_concurrent: usize,
+    /// Requests started per second, across every account and both APIs.
+    pub per_second: f64,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        RetrySettings {
+            retries: DEFAULT_RETRIES,
+            max_concurrent: DEFAULT_MAX_CONCURRENT,
+            per_second: DEFAULT_PER_SECOND,
+        }
+    }
+}
+
+/// Paces and retries API requests. One is shared by every client so the
+/// limits hold however many accounts and meters are polled.
+pub struct Retrier {
+    settings: RetrySettings,
+    base_delay: Duration,
+    max_delay: Duration,
+    permits: Semaphore,
+    /// Earliest the next request may start.
+    next_start: Mutex<Instant>,
+    retries: Option<IntCounterVec>,
+    throttled: Option<IntCounterVec>,
+}
+
+impl Default for Retrier {
+    fn default() -> Self {
+        Retrier::new(RetrySettings::default())
+    }
+}
+
+impl Retrier {
+    pub fn new(settings: RetrySettings) -> Self {
+        Retrier {
+            permits: Semaphore::new(settings.max_concurrent.max(1)),
+            settings,
+            base_delay: BASE_DELAY,
+            max_delay: MAX_DELAY,
+            next_start: Mutex::new(Instant::now()),
+            retries: None,
+            throttled: None,
+        }
+    }
+
+    /// Counts retries and throttled responses in these metrics.
+    pub fn with_metrics(mut self, metrics: &Metrics) -> Self {
+        self.retries = Some(metrics.api_retries.clone());
+        self.throttled = Some(metrics.api_throttled.clone());
+        self
+    }
+
+    #[cfg(test)]
+    fn with_delays(mut self, base_delay: Duration, max_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Sends `request` once a slot is free, and again after a backoff for as
+    /// long as it fails transiently and retries are left.
+    pub async fn run<T, F, Fut>(&self, api: Api, mut request: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let result = {
+                let _permit = self.permits.acquire().await.expect("the semaphore is never closed");
+                self.pace().await;
+                request().await
+            };
+            let error = match result {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+
+            if error.status() == Some(429)
+                && let Some(throttled) = &self.throttled
+            {
+                throttled.with_label_values(&[api.as_str()]).inc();
+            }
+            let Some(delay) = self.delay(attempt, &error) else {
+                return Err(error);
+            };
+            attempt += 1;
+            if let Some(retries) = &self.retries {
+                retries.with_label_values(&[api.as_str()]).inc();
+            }
+            warn!("{error}, retry {attempt} of {} in {:.1}s", self.settings.retries, delay.as_secs_f64());
+            time::sleep(delay).await;
+        }
+    }
+
+    /// Waits for the next start slot, keeping requests `1 / per_second` apart.
+    async fn pace(&self) {
+        // Settings are checked, but a bad rate must never panic a poll
+        let gap = Duration::try_from_secs_f64(1.0 / self.settings.per_second).unwrap_or(MAX_DELAY);
+        let start = {
+            let mut next_start = self.next_start.lock().unwrap_or_else(|e| e.into_inner());
+            let start = (*next_start).max(Instant::now());
+            *next_start = start + gap;
+            start
+        };
+        time::sleep_until(start).await;
+    }
+
+    /// How long to wait before retrying after `attempt` retries, or `None`
+    /// to give up. A `Retry-After` longer than the backoff wins, unless it's
+    /// longer than we're prepared to wait.
+    fn delay(&self, attempt: u32, error: &Error) -> Option<Duration> {
+        if attempt >= self.settings.retries || !error.is_transient() {
+            return None;
+        }
+        let backoff = self.base_delay.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_delay);
+        let backoff = jitter(backoff);
+        match error.retry_after() {
+            Some(wait) if wait > self.max_delay => None,
+            Some(wait) => Some(wait.max(backoff)),
+            None => Some(backoff),
+        }
+    }
+}
+
+/// Somewhere between half and all of `delay`, so clients that failed
+/// together don't all retry together.
+fn jitter(delay: Duration) -> Duration {
+    let fraction = RandomState::new().hash_one(Instant::now()) as f64 / u64::MAX as f64;
+    delay.mul_f64(0.5 + fraction / 2.0)
+}
+
+/// The wait a `Retry-After` header asks for, given as seconds or an HTTP date.
+pub fn retry_after(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+    Some((at - now).to_std().unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn throttled(retry_after: Option<Duration>) -> Error {
+        Error::Octopus { status: Some(429), retry_after, message: "error status 429 Too Many Requests".to_string() }
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_failures() {
+        let metrics = Metrics::new(false, &[]).unwrap();
+        let retrier = Retrier::new(RetrySettings { retries: 2, max_concurrent: 1, per_second: 1000.0 })
+            .with_delays(Duration::from_millis(1), Duration::from_millis(50))
+            .with_metrics(&metrics);
+
+        // Throttled, then a server error, then through
+        let attempts = AtomicU32::new(0);
+        let result = retrier
+            .run(Api::Octopus, || async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(throttled(Some(Duration::from_millis(5)))),
+                    1 => Err(Error::Octopus { status: Some(503), retry_after: None, message: String::new() }),
+                    n => Ok(n),
+                }
+            })
+            .await;
+        assert_eq!(result, Ok(2));
+        assert_eq!(metrics.api_retries.with_label_values(&["octopus"]).get(), 2);
+        assert_eq!(metrics.api_throttled.with_label_values(&["octopus"]).get(), 1);
+
+        // Out of retries
+        let attempts = AtomicU32::new(0);
+        let result: Result<()> = retrier
+            .run(Api::CarbonIntensity, || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(Error::carbon_intensity("connection reset"))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // Errors that won't go away, and waits we won't sit through, aren't retried
+        for error in [Error::Parse("bad date".to_string()), throttled(Some(Duration::from_secs(3600)))] {
+            let attempts = AtomicU32::new(0);
+            let result: Result<()> = retrier
+                .run(Api::Octopus, || async {
+                    attempts.fetch_add(1, Ordering::SeqCst);
+                    Err(error.clone())
+                })
+                .await;
+            assert_eq!(result, Err(error));
+            assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    #[test]
+    fn test_backoff_grows_with_jitter_and_honours_retry_after() {
+        let retrier = Retrier::default();
+        let error = Error::octopus("timed out");
+        for attempt in 0..3 {
+            let full = BASE_DELAY * 2u32.pow(attempt);
+            let delay = retrier.delay(attempt, &error).unwrap();
+            assert!(delay >= full / 2 && delay <= full, "{delay:?}");
+        }
+        assert!(retrier.delay(DEFAULT_RETRIES, &error).is_none());
+        assert_eq!(retrier.delay(0, &throttled(Some(Duration::from_secs(30)))), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_retry_after_header() {
+        let now = DateTime::parse_from_rfc3339("2025-08-01T12:00:00Z").unwrap().with_timezone(&Utc);
+        let header = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, value.parse().unwrap());
+            headers
+        };
+        assert_eq!(retry_after(&header("120"), now), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(&header("Fri, 01 Aug 2025 12:00:30 GMT"), now), Some(Duration::from_secs(30)));
+        assert_eq!(retry_after(&header("Fri, 01 Aug 2025 11:00:00 GMT"), now), Some(Duration::ZERO));
+        assert_eq!(retry_after(&header("soon"), now), None);
+        assert_eq!(retry_after(&HeaderMap::new(), now), None);
+    }
+}
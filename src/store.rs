@@ -0,0 +1,365 @@
+use std::{path::Path, sync::Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{Connection, OptionalExtension, Transaction, params};
+
+use crate::cache::Coverage;
+use crate::carbon_intensity::{GenerationMix, GenerationShare, IntensitySlot, SlotIntensity};
+use crate::error::{Error, Result};
+use crate::meter::Meter;
+use crate::tariff::{Rate, TariffRates};
+use crate::usage::Reading;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS readings (
+        series TEXT NOT NULL,
+        start INTEGER NOT NULL,
+        kwh REAL NOT NULL,
+        PRIMARY KEY (series, start)
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS intensity (
+        region INTEGER NOT NULL,
+        start INTEGER NOT NULL,
+        slot_end INTEGER NOT NULL,
+        forecast REAL NOT NULL,
+        grade TEXT NOT NULL,
+        generation_mix TEXT NOT NULL,
+        PRIMARY KEY (region, start)
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS rates (
+        tariff TEXT NOT NULL,
+        charge TEXT NOT NULL,
+        valid_from INTEGER NOT NULL,
+        valid_to INTEGER,
+        pence REAL NOT NULL,
+        PRIMARY KEY (tariff, charge, valid_from)
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS coverage (
+        series TEXT PRIMARY KEY,
+        start INTEGER NOT NULL,
+        complete_to INTEGER
+    );
+";
+
+/// History of readings, regional carbon intensity and tariff rates in a
+/// SQLite database, with the span each series has been fetched over so
+/// only what's missing is fetched again.
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+impl Store {
+    /// Opens the database at `path`, creating it and its tables if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path).map_err(|e| Error::Database(format!("can't open {}: {e}", path.display())))?;
+        Self::with_connection(conn)
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        Self::with_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Store { conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The key a meter's readings are kept under, e.g. `electricity:1200000000001:S0000001`.
+    pub fn meter_series(meter: &Meter) -> String {
+        format!("{}:{}:{}", meter.fuel.as_str(), meter.meter_point, meter.serial_number)
+    }
+
+    fn intensity_series(region: u8) -> String {
+        format!("intensity:{region}")
+    }
+
+    fn rates_series(tariff_code: &str) -> String {
+        format!("rates:{tariff_code}")
+    }
+
+    /// What `series` has been fetched over, if anything.
+    pub fn coverage(&self, series: &str) -> Result<Option<Coverage>> {
+        let row = self
+            .conn()
+            .query_row("SELECT start, complete_to FROM coverage WHERE series = ?1", [series], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, Option<i64>>(1)?))
+            })
+            .optional()?;
+        Ok(row.and_then(|(from, complete_to)| {
+            Some(Coverage { from: time(from)?, complete_to: complete_to.and_then(time) })
+        }))
+    }
+
+    /// Forgets what every series covers, keeping the rows, so they're all
+    /// fetched again in full and replaced.
+    pub fn forget_coverage(&self) -> Result<()> {
+        self.conn().execute("DELETE FROM coverage", [])?;
+        Ok(())
+    }
+
+    /// Readings of `meter` from `from` up to `to`, in order.
+    pub fn readings(&self, meter: &Meter, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<Reading>> {
+        let conn = self.conn();
+        let mut statement =
+            conn.prepare("SELECT start, kwh FROM readings WHERE series = ?1 AND start >= ?2 AND start < ?3 ORDER BY start")?;
+        let rows = statement.query_map(params![Self::meter_series(meter), from.timestamp(), to.timestamp()], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, f64>(1)?))
+        })?;
+        let mut readings = Vec::new();
+        for row in rows {
+            let (start, kwh) = row?;
+            if let Some(start) = time(start) {
+                readings.push(Reading { start, kwh });
+            }
+        }
+        Ok(readings)
+    }
+
+    /// Replaces the readings of `meter` from `from` up to `to` with
+    /// `readings`, every one the API has for that span.
+    pub fn save_readings(&self, meter: &Meter, from: DateTime<Utc>, to: DateTime<Utc>, readings: &[Reading]) -> Result<()> {
+        let series = Self::meter_series(meter);
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM readings WHERE series = ?1 AND start >= ?2 AND start < ?3",
+            params![series, from.timestamp(), to.timestamp()],
+        )?;
+        {
+            let mut insert = tx.prepare("INSERT OR REPLACE INTO readings (series, start, kwh) VALUES (?1, ?2, ?3)")?;
+            for reading in readings {
+                insert.execute(params![series, reading.start.timestamp(), reading.kwh])?;
+            }
+        }
+        let complete_to = readings.iter().map(|reading| reading.start + Duration::minutes(30)).max();
+        extend_coverage(&tx, &series, from, to, complete_to)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Regional intensity slots starting from `from` up to `to`, in order.
+    pub fn intensity(&self, region: u8, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<IntensitySlot>> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(
+            "SELECT start, slot_end, forecast, grade, generation_mix FROM intensity
+             WHERE region = ?1 AND start >= ?2 AND start < ?3 ORDER BY start",
+        )?;
+        let rows = statement.query_map(params![region, from.timestamp(), to.timestamp()], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, f64>(2)?, row.get::<_, String>(3)?, row.get::<_, String>(4)?))
+        })?;
+        let mut slots = Vec::new();
+        for row in rows {
+            let (start, end, forecast, index, mix) = row?;
+            let mix: GenerationMix = serde_json::from_str(&mix).map_err(|e| Error::Database(format!("bad generation mix: {e}")))?;
+            if let (Some(from), Some(to)) = (time(start), time(end)) {
+                slots.push(IntensitySlot {
+                    from,
+                    to,
+                    intensity: SlotIntensity { forecast, index },
+                    generation_mix: mix.into_iter().map(|(fuel, percent)| GenerationShare { fuel, percent }).collect(),
+                });
+            }
+        }
+        Ok(slots)
+    }
+
+    /// Stores `slots`, every one the API has for the region from `from` up to `to`.
+    pub fn save_intensity(&self, region: u8, from: DateTime<Utc>, to: DateTime<Utc>, slots: &[IntensitySlot]) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT OR REPLACE INTO intensity (region, start, slot_end, forecast, grade, generation_mix)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for slot in slots {
+                let mix = serde_json::to_string(&slot.mix()).map_err(|e| Error::Database(e.to_string()))?;
+                insert.execute(params![region, slot.from.timestamp(), slot.to.timestamp(), slot.intensity.forecast, slot.intensity.index, mix])?;
+            }
+        }
+        let complete_to = slots.iter().map(|slot| slot.to).max();
+        extend_coverage(&tx, &Self::intensity_series(region), from, to, complete_to)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// What's held of `region`'s intensity, see `coverage`.
+    pub fn intensity_coverage(&self, region: u8) -> Result<Option<Coverage>> {
+        self.coverage(&Self::intensity_series(region))
+    }
+
+    /// Every rate of a tariff in force at any time from `from` up to `to`.
+    pub fn tariff_rates(&self, tariff_code: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<TariffRates> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(
+            "SELECT charge, valid_from, valid_to, pence FROM rates
+             WHERE tariff = ?1 AND valid_from < ?3 AND (valid_to IS NULL OR valid_to > ?2)
+             ORDER BY valid_from",
+        )?;
+        let rows = statement.query_map(params![tariff_code, from.timestamp(), to.timestamp()], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, Option<i64>>(2)?, row.get::<_, f64>(3)?))
+        })?;
+        let (mut unit_rates, mut standing_charges) = (Vec::new(), Vec::new());
+        for row in rows {
+            let (charge, valid_from, valid_to, pence) = row?;
+            let Some(valid_from) = time(valid_from) else {
+                continue;
+            };
+            let rate = Rate { valid_from, valid_to: valid_to.and_then(time), pence };
+            match charge.as_str() {
+                "unit" => unit_rates.push(rate),
+                _ => standing_charges.push(rate),
+            }
+        }
+        Ok(TariffRates::new(unit_rates, standing_charges))
+    }
+
+    /// Stores `rates`, every one the API lists for the tariff from `from` up to
+    /// `to`. A rate that was open-ended and has since been closed is updated.
+    pub fn save_tariff_rates(&self, tariff_code: &str, from: DateTime<Utc>, to: DateTime<Utc>, rates: &TariffRates) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT OR REPLACE INTO rates (tariff, charge, valid_from, valid_to, pence) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for (charge, list) in [("unit", &rates.unit_rates), ("standing", &rates.standing_charges)] {
+                for rate in list {
+                    insert.execute(params![tariff_code, charge, rate.valid_from.timestamp(), rate.valid_to.map(|to| to.timestamp()), rate.pence])?;
+                }
+            }
+        }
+        // Rates are known right up to `to`, whether or not a new one starts near it
+        extend_coverage(&tx, &Self::rates_series(tariff_code), from, to, Some(to))?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// What's held of a tariff's rates, see `coverage`.
+    pub fn rates_coverage(&self, tariff_code: &str) -> Result<Option<Coverage>> {
+        self.coverage(&Self::rates_series(tariff_code))
+    }
+}
+
+/// Records that `series` was fetched from `from` up to `to`. A span that
+/// overlaps or touches what's held joins it; one that doesn't replaces it
+/// if it's more recent, as coverage can't have gaps.
+fn extend_coverage(
+    tx: &Transaction<'_>,
+    series: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    complete_to: Option<DateTime<Utc>>,
+) -> Result<()> {
+    let existing = tx
+        .query_row("SELECT start, complete_to FROM coverage WHERE series = ?1", [series], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, Option<i64>>(1)?))
+        })
+        .optional()?;
+    let (from, to, complete_to) = (from.timestamp(), to.timestamp(), complete_to.map(|to| to.timestamp()));
+    let (start, complete_to) = match existing {
+        Some((start, held_to)) if from <= held_to.unwrap_or(start) && to >= start => {
+            (start.min(from), held_to.max(complete_to))
+        }
+        Some((start, held_to)) if held_to.unwrap_or(start) > complete_to.unwrap_or(from) => (start, held_to),
+        _ => (from, complete_to),
+    };
+    tx.execute(
+        "INSERT OR REPLACE INTO coverage (series, start, complete_to) VALUES (?1, ?2, ?3)",
+        params![series, start, complete_to],
+    )?;
+    Ok(())
+}
+
+fn time(secs: i64) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp(secs, 0)
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Database(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meter::test_meter;
+    use crate::usage::Fuel;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_readings_replaced_and_coverage_joined() {
+        let store = Store::in_memory();
+        let meter = test_meter(Fuel::Electricity, "1200000000001");
+        let series = Store::meter_series(&meter);
+        let reading = |start: &str, kwh: f64| Reading { start: at(start), kwh };
+
+        // Backfilled a month, newest first, then polled
+        store.save_readings(&meter, at("2025-07-15T00:00:00Z"), at("2025-08-01T00:00:00Z"), &[reading("2025-07-31T23:30:00Z", 0.5)]).unwrap();
+        store.save_readings(&meter, at("2025-07-01T00:00:00Z"), at("2025-07-15T00:00:00Z"), &[reading("2025-07-01T00:00:00Z", 0.25)]).unwrap();
+        store.save_readings(&meter, at("2025-07-31T00:00:00Z"), at("2025-08-02T00:00:00Z"), &[reading("2025-08-01T10:00:00Z", 1.0)]).unwrap();
+        assert_eq!(
+            store.coverage(&series).unwrap(),
+            Some(Coverage { from: at("2025-07-01T00:00:00Z"), complete_to: Some(at("2025-08-01T10:30:00Z")) }),
+        );
+        // The poll's span had no 23:30 reading any more, so it's gone
+        assert_eq!(
+            store.readings(&meter, at("2025-07-01T00:00:00Z"), at("2025-08-02T00:00:00Z")).unwrap(),
+            [reading("2025-07-01T00:00:00Z", 0.25), reading("2025-08-01T10:00:00Z", 1.0)],
+        );
+
+        // A span that leaves a gap doesn't join it
+        store.save_readings(&meter, at("2025-01-01T00:00:00Z"), at("2025-02-01T00:00:00Z"), &[]).unwrap();
+        assert_eq!(store.coverage(&series).unwrap().unwrap().from, at("2025-07-01T00:00:00Z"));
+
+        store.forget_coverage().unwrap();
+        assert_eq!(store.coverage(&series).unwrap(), None);
+    }
+
+    #[test]
+    fn test_intensity_and_rates_round_trip() {
+        let store = Store::in_memory();
+        let slot = IntensitySlot {
+            from: at("2025-08-01T12:00:00Z"),
+            to: at("2025-08-01T12:30:00Z"),
+            intensity: SlotIntensity { forecast: 120.0, index: "low".to_string() },
+            generation_mix: vec![GenerationShare { fuel: "wind".to_string(), percent: 40.0 }],
+        };
+        store.save_intensity(13, at("2025-08-01T00:00:00Z"), at("2025-08-02T00:00:00Z"), std::slice::from_ref(&slot)).unwrap();
+        assert_eq!(store.intensity(13, at("2025-08-01T00:00:00Z"), at("2025-08-02T00:00:00Z")).unwrap(), [slot]);
+        assert_eq!(store.intensity_coverage(13).unwrap().unwrap().complete_to, Some(at("2025-08-01T12:30:00Z")));
+
+        let rates = TariffRates::new(
+            vec![Rate { valid_from: at("2025-04-01T00:00:00Z"), valid_to: None, pence: 25.0 }],
+            vec![Rate { valid_from: at("2025-01-01T00:00:00Z"), valid_to: None, pence: 50.0 }],
+        );
+        let code = "E-1R-VAR-22-11-01-C";
+        store.save_tariff_rates(code, at("2025-07-01T00:00:00Z"), at("2025-08-02T00:00:00Z"), &rates).unwrap();
+        // The unit rate has since closed and a new one opened
+        let changed = TariffRates::new(
+            vec![
+                Rate { valid_from: at("2025-08-01T00:00:00Z"), valid_to: None, pence: 26.0 },
+                Rate { valid_from: at("2025-04-01T00:00:00Z"), valid_to: Some(at("2025-08-01T00:00:00Z")), pence: 25.0 },
+            ],
+            rates.standing_charges.clone(),
+        );
+        store.save_tariff_rates(code, at("2025-07-31T00:00:00Z"), at("2025-08-03T00:00:00Z"), &changed).unwrap();
+
+        let held = store.tariff_rates(code, at("2025-07-01T00:00:00Z"), at("2025-08-03T00:00:00Z")).unwrap();
+        assert_eq!(held.unit_rates, changed.unit_rates);
+        assert_eq!(held.standing_charges, rates.standing_charges);
+        assert_eq!(
+            store.rates_coverage(code).unwrap(),
+            Some(Coverage { from: at("2025-07-01T00:00:00Z"), complete_to: Some(at("2025-08-03T00:00:00Z")) }),
+        );
+    }
+}
@@ -0,0 +1,437 @@
+use std::{collections::BTreeSet, str::FromStr};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use octopust::models::TariffChargesResponse;
+
+use crate::error::{self, Error};
+use crate::octopus::OctopusApi;
+use crate::usage::{Fuel, Reading};
+
+/// A tariff code such as `E-1R-VAR-22-11-01-C`, split into the parts the
+/// rate endpoints need.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tariff {
+    pub fuel: Fuel,
+    pub product_code: String,
+    pub tariff_code: String,
+}
+
+impl FromStr for Tariff {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tariff_code = s.trim().to_ascii_uppercase();
+        let parts: Vec<&str> = tariff_code.split('-').collect();
+        if parts.len() < 4 {
+            return Err(format!("invalid tariff code '{s}', expected e.g. E-1R-VAR-22-11-01-C"));
+        }
+
+        let fuel = match parts[0] {
+            "E" => Fuel::Electricity,
+            "G" => Fuel::Gas,
+            _ => return Err(format!("tariff code '{s}' must start with E- or G-")),
+        };
+        if parts[1] != "1R" {
+            return Err(format!("tariff code '{s}' is not a single register tariff, which is the only kind priced"));
+        }
+
+        // Drop the fuel/register prefix and the region suffix
+        let product_code = parts[2..parts.len() - 1].join("-");
+        Ok(Tariff { fuel, product_code, tariff_code })
+    }
+}
+
+impl Tariff {
+    /// Parses a tariff code that must be for `fuel`.
+    pub fn parse_for(s: &str, fuel: Fuel) -> Result<Self, String> {
+        let tariff: Tariff = s.parse()?;
+        if tariff.fuel != fuel {
+            return Err(format!("'{s}' is not {} tariff code", fuel.as_str()));
+        }
+        Ok(tariff)
+    }
+}
+
+/// Tariff codes set by hand. They win over the agreement active on the
+/// account, which is used otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct TariffSettings {
+    pub electricity: Option<Tariff>,
+    pub gas: Option<Tariff>,
+}
+
+impl TariffSettings {
+    pub fn configured(&self, fuel: Fuel) -> Option<&Tariff> {
+        match fuel {
+            Fuel::Electricity => self.electricity.as_ref(),
+            Fuel::Gas => self.gas.as_ref(),
+        }
+    }
+}
+
+/// A charge in pence (inc. VAT) valid over a period, open-ended when `valid_to` is `None`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rate {
+    pub valid_from: DateTime<Utc>,
+    pub valid_to: Option<DateTime<Utc>>,
+    pub pence: f64,
+}
+
+/// Pence per unit in force at `at`, from `rates` sorted by `valid_from`.
+pub fn rate_at(rates: &[Rate], at: DateTime<Utc>) -> Option<f64> {
+    rate_in_force(rates, at).map(|rate| rate.pence)
+}
+
+/// The latest rate to start by `at`, if it hasn't ended yet. A binary
+/// search, as a year of half-hourly rates is priced slot by slot.
+fn rate_in_force(rates: &[Rate], at: DateTime<Utc>) -> Option<&Rate> {
+    let started = rates.partition_point(|rate| rate.valid_from <= at);
+    rates[..started].last().filter(|rate| rate.valid_to.is_none_or(|to| at < to))
+}
+
+/// Spread of unit rates over a period, in pence per kWh.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateStats {
+    pub min: f64,
+    pub max: f64,
+    /// Weighted by how long each rate applied, so a flat tariff averages to its rate.
+    pub average: f64,
+}
+
+/// Unit rates (pence/kWh) and standing charges (pence/day) of one tariff,
+/// each sorted by `valid_from`.
+#[derive(Debug, Clone, Default)]
+pub struct TariffRates {
+    pub unit_rates: Vec<Rate>,
+    pub standing_charges: Vec<Rate>,
+}
+
+impl TariffRates {
+    /// Sorts the rates oldest first for `rate_at`, as the API lists them
+    /// newest first.
+    pub fn new(mut unit_rates: Vec<Rate>, mut standing_charges: Vec<Rate>) -> Self {
+        unit_rates.sort_by_key(|rate| rate.valid_from);
+        standing_charges.sort_by_key(|rate| rate.valid_from);
+        TariffRates { unit_rates, standing_charges }
+    }
+
+    /// Prices every consumption slot at the unit rate in force when it
+    /// started, or `None` if any slot has no rate.
+    pub fn energy_cost_pence(&self, readings: &[Reading]) -> Option<f64> {
+        readings
+            .iter()
+            .map(|reading| rate_at(&self.unit_rates, reading.start).map(|rate| reading.kwh * rate))
+            .sum()
+    }
+
+    /// Unit rate of the slot following the one in force at `at`. Open-ended
+    /// rates are their own next slot.
+    pub fn next_unit_rate(&self, at: DateTime<Utc>) -> Option<f64> {
+        let current = rate_in_force(&self.unit_rates, at)?;
+        match current.valid_to {
+            Some(end) => rate_at(&self.unit_rates, end),
+            None => Some(current.pence),
+        }
+    }
+
+    /// Min, max and average of the unit rates published between `from` and
+    /// `to`. Slots without a published rate yet are left out.
+    pub fn unit_rate_stats(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Option<RateStats> {
+        let mut stats: Option<RateStats> = None;
+        let mut weighted = 0.0;
+        let mut seconds = 0;
+
+        for rate in &self.unit_rates {
+            let start = rate.valid_from.max(from);
+            let end = rate.valid_to.map_or(to, |valid_to| valid_to.min(to));
+            if start >= end {
+                continue;
+            }
+
+            let duration = (end - start).num_seconds();
+            weighted += rate.pence * duration as f64;
+            seconds += duration;
+            stats = Some(match stats {
+                Some(s) => RateStats { min: s.min.min(rate.pence), max: s.max.max(rate.pence), ..s },
+                None => RateStats { min: rate.pence, max: rate.pence, average: 0.0 },
+            });
+        }
+
+        stats.map(|s| RateStats { average: weighted / seconds as f64, ..s })
+    }
+
+    /// Standing charges between `from` and `to`, pro rata for part days.
+    pub fn standing_charge_pence(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> f64 {
+        let mut total = 0.0;
+        let mut at = from;
+        while at < to {
+            let next = (at + ChronoDuration::days(1)).min(to);
+            let fraction = (next - at).num_seconds() as f64 / 86_400.0;
+            total += rate_at(&self.standing_charges, at).unwrap_or(0.0) * fraction;
+            at = next;
+        }
+        total
+    }
+}
+
+pub async fn fetch_tariff_rates(
+    api: &OctopusApi,
+    tariff: &Tariff,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> error::Result<TariffRates> {
+    let fuel_path = match tariff.fuel {
+        Fuel::Electricity => "electricity-tariffs",
+        Fuel::Gas => "gas-tariffs",
+    };
+    let base = format!("products/{}/{}/{}", tariff.product_code, fuel_path, tariff.tariff_code);
+
+    Ok(TariffRates::new(
+        fetch_charges(api, &format!("{base}/standard-unit-rates/"), from, to).await?,
+        fetch_charges(api, &format!("{base}/standing-charges/"), from, to).await?,
+    ))
+}
+
+/// Fetches every page of a charges endpoint, following each `next` link,
+/// and keeps direct debit prices where the tariff lists both payment
+/// methods. The charges have to add up to the count the API gave.
+async fn fetch_charges(
+    api: &OctopusApi,
+    path: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> error::Result<Vec<Rate>> {
+    let params = &[
+        ("period_from", from.format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+        ("period_to", to.format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+        ("page_size", "1500".to_string()),
+    ];
+    let mut rates = Vec::new();
+    let mut pages = 0;
+    let mut received = 0;
+    let mut count = None;
+    let mut seen = BTreeSet::new();
+    let mut next: Option<String> = None;
+    loop {
+        let response: TariffChargesResponse = match next.take() {
+            Some(url) => api.get_next(&url).await?,
+            None => api.get(path, params).await?,
+        };
+        pages += 1;
+        let count = *count.get_or_insert(response.count as usize);
+        if response.results.is_empty() && response.next.is_some() {
+            return Err(Error::Inconsistent(format!("page {pages} of {path} is empty but isn't the last")));
+        }
+        received += response.results.len();
+        if received > count {
+            return Err(Error::Inconsistent(format!("the API counted {count} charges of {path} but page {pages} brings it to {received}")));
+        }
+
+        for charge in response.results {
+            if charge.payment_method.as_deref() == Some("NON_DIRECT_DEBIT") {
+                continue;
+            }
+            rates.push(Rate {
+                valid_from: parse_time(&charge.valid_from)?,
+                valid_to: charge.valid_to.as_deref().map(parse_time).transpose()?,
+                pence: charge.value_inc_vat,
+            });
+        }
+
+        match response.next {
+            Some(url) if !seen.insert(url.clone()) => {
+                return Err(Error::Inconsistent(format!("page {pages} of {path} links back to {url}")));
+            }
+            Some(url) => next = Some(url),
+            None => break,
+        }
+    }
+
+    let count = count.unwrap_or_default();
+    if received != count {
+        return Err(Error::Inconsistent(format!("the API counted {count} charges of {path} but {received} came back in {pages} pages")));
+    }
+    Ok(rates)
+}
+
+fn parse_time(s: &str) -> error::Result<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| Error::Parse(format!("invalid time '{s}': {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octopus::mock_server;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn rates() -> TariffRates {
+        TariffRates::new(
+            vec![
+                Rate { valid_from: at("2025-04-01T00:00:00+01:00"), valid_to: None, pence: 25.0 },
+                Rate { valid_from: at("2025-01-01T00:00:00Z"), valid_to: Some(at("2025-04-01T00:00:00+01:00")), pence: 24.0 },
+            ],
+            vec![Rate { valid_from: at("2025-01-01T00:00:00Z"), valid_to: None, pence: 50.0 }],
+        )
+    }
+
+    #[test]
+    fn test_parse_tariff_code() {
+        let tariff: Tariff = "E-1R-AGILE-24-10-01-C".parse().unwrap();
+        assert_eq!(tariff.fuel, Fuel::Electricity);
+        assert_eq!(tariff.product_code, "AGILE-24-10-01");
+        assert_eq!(tariff.tariff_code, "E-1R-AGILE-24-10-01-C");
+
+        let tariff: Tariff = "g-1r-var-22-11-01-a".parse().unwrap();
+        assert_eq!(tariff.fuel, Fuel::Gas);
+        assert_eq!(tariff.product_code, "VAR-22-11-01");
+
+        for invalid in ["", "E-1R-C", "X-1R-VAR-22-11-01-C", "E-2R-VAR-22-11-01-C"] {
+            assert!(invalid.parse::<Tariff>().is_err(), "{invalid} should not parse");
+        }
+
+        assert!(Tariff::parse_for("G-1R-VAR-22-11-01-C", Fuel::Gas).is_ok());
+        assert!(Tariff::parse_for("G-1R-VAR-22-11-01-C", Fuel::Electricity).is_err());
+    }
+
+    #[test]
+    fn test_energy_cost_uses_rate_per_slot() {
+        let readings = vec![
+            Reading { start: at("2025-03-31T22:00:00Z"), kwh: 1.0 },
+            // Midnight BST, when the new rate starts
+            Reading { start: at("2025-03-31T23:00:00Z"), kwh: 2.0 },
+        ];
+        assert_eq!(rates().energy_cost_pence(&readings), Some(24.0 + 50.0));
+
+        // A slot before any known rate leaves the whole cost unknown
+        let readings = [readings, vec![Reading { start: at("2024-12-31T23:00:00Z"), kwh: 5.0 }]].concat();
+        assert_eq!(rates().energy_cost_pence(&readings), None);
+    }
+
+    #[test]
+    fn test_standing_charge_pro_rata() {
+        let rates = rates();
+        assert_eq!(rates.standing_charge_pence(at("2025-02-01T00:00:00Z"), at("2025-02-03T00:00:00Z")), 100.0);
+        assert_eq!(rates.standing_charge_pence(at("2025-02-01T00:00:00Z"), at("2025-02-01T12:00:00Z")), 25.0);
+        assert_eq!(rates.standing_charge_pence(at("2025-02-01T00:00:00Z"), at("2025-02-01T00:00:00Z")), 0.0);
+    }
+
+    fn agile_rates() -> TariffRates {
+        let slot = |from: &str, pence: f64| {
+            let valid_from = at(from);
+            Rate { valid_from, valid_to: Some(valid_from + ChronoDuration::minutes(30)), pence }
+        };
+        TariffRates::new(
+            vec![
+                slot("2025-06-01T16:30:00+01:00", 35.0),
+                slot("2025-06-01T16:00:00+01:00", 30.0),
+                slot("2025-06-01T17:00:00+01:00", 10.0),
+                slot("2025-06-01T17:30:00+01:00", -2.0),
+            ],
+            vec![],
+        )
+    }
+
+    #[test]
+    fn test_current_and_next_unit_rate() {
+        let agile = agile_rates();
+        assert_eq!(rate_at(&agile.unit_rates, at("2025-06-01T16:10:00+01:00")), Some(30.0));
+        assert_eq!(agile.next_unit_rate(at("2025-06-01T16:10:00+01:00")), Some(35.0));
+        assert_eq!(agile.next_unit_rate(at("2025-06-01T17:29:59+01:00")), Some(-2.0));
+        // The slot after the last published one isn't known yet
+        assert_eq!(agile.next_unit_rate(at("2025-06-01T17:30:00+01:00")), None);
+
+        // A flat rate stays the same from one slot to the next
+        assert_eq!(rates().next_unit_rate(at("2025-05-01T12:00:00Z")), Some(25.0));
+    }
+
+    #[test]
+    fn test_unit_rate_stats_weighted_by_time() {
+        let agile = agile_rates();
+        let stats = agile.unit_rate_stats(at("2025-06-01T00:00:00+01:00"), at("2025-06-02T00:00:00+01:00")).unwrap();
+        assert_eq!(stats, RateStats { min: -2.0, max: 35.0, average: 18.25 });
+
+        // Only the overlap with the period counts
+        let stats = agile.unit_rate_stats(at("2025-06-01T16:15:00+01:00"), at("2025-06-01T17:00:00+01:00")).unwrap();
+        assert_eq!(stats, RateStats { min: 30.0, max: 35.0, average: (30.0 * 15.0 + 35.0 * 30.0) / 45.0 });
+
+        assert_eq!(agile.unit_rate_stats(at("2025-06-02T00:00:00+01:00"), at("2025-06-03T00:00:00+01:00")), None);
+
+        let stats = rates().unit_rate_stats(at("2025-03-31T11:00:00Z"), at("2025-04-01T11:00:00Z")).unwrap();
+        assert_eq!(stats, RateStats { min: 24.0, max: 25.0, average: (24.0 * 12.0 + 25.0 * 12.0) / 24.0 });
+    }
+
+    #[tokio::test]
+    async fn test_fetch_tariff_rates_from_mock_server() {
+        let base_url = mock_server(vec![
+            (
+                "/v1/products/VAR-22-11-01/electricity-tariffs/E-1R-VAR-22-11-01-C/standard-unit-rates/",
+                serde_json::json!({
+                    "count": 2, "next": null, "previous": null,
+                    "results": [
+                        {"value_exc_vat": 23.8, "value_inc_vat": 24.99, "valid_from": "2025-04-01T00:00:00+01:00", "valid_to": null, "payment_method": "DIRECT_DEBIT"},
+                        {"value_exc_vat": 25.0, "value_inc_vat": 26.25, "valid_from": "2025-04-01T00:00:00+01:00", "valid_to": null, "payment_method": "NON_DIRECT_DEBIT"}
+                    ]
+                }),
+            ),
+            (
+                "/v1/products/VAR-22-11-01/electricity-tariffs/E-1R-VAR-22-11-01-C/standing-charges/",
+                serde_json::json!({
+                    "count": 1, "next": null, "previous": null,
+                    "results": [
+                        {"value_exc_vat": 50.0, "value_inc_vat": 52.5, "valid_from": "2025-01-01T00:00:00Z", "valid_to": null, "payment_method": null}
+                    ]
+                }),
+            ),
+        ]);
+        let api = OctopusApi::new("key").with_base_url(base_url);
+        let tariff: Tariff = "E-1R-VAR-22-11-01-C".parse().unwrap();
+
+        let rates = fetch_tariff_rates(&api, &tariff, at("2025-05-01T00:00:00Z"), at("2025-05-02T00:00:00Z")).await.unwrap();
+        assert_eq!(rates.unit_rates.len(), 1);
+        assert_eq!(rate_at(&rates.unit_rates, at("2025-05-01T12:00:00Z")), Some(24.99));
+        assert_eq!(rates.standing_charge_pence(at("2025-05-01T00:00:00Z"), at("2025-05-02T00:00:00Z")), 52.5);
+
+        let readings = vec![Reading { start: at("2025-05-01T12:00:00Z"), kwh: 2.0 }];
+        assert!((rates.energy_cost_pence(&readings).unwrap() - 49.98).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_charges_follows_next_links() {
+        use std::collections::HashMap;
+        use warp::Filter;
+
+        // Rates come back a charge a page, and the looped endpoint links to itself
+        let routes = warp::path::full()
+            .and(warp::header::<String>("host"))
+            .and(warp::query::<HashMap<String, String>>())
+            .map(|path: warp::path::FullPath, host: String, query: HashMap<String, String>| {
+                let here = format!("http://{host}{}", path.as_str());
+                let (valid_from, pence, next) = match (path.as_str(), query.get("cursor")) {
+                    ("/v1/looped/", _) => ("2025-01-01T00:00:00Z", 50.0, Some(here)),
+                    (_, None) => ("2025-06-01T00:00:00Z", 25.0, Some(format!("{here}?cursor=b"))),
+                    (_, Some(_)) => ("2025-01-01T00:00:00Z", 24.0, None),
+                };
+                warp::reply::json(&serde_json::json!({
+                    "count": 2,
+                    "next": next,
+                    "previous": null,
+                    "results": [{"value_exc_vat": pence, "value_inc_vat": pence, "valid_from": valid_from, "valid_to": null, "payment_method": null}],
+                }))
+            });
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let api = OctopusApi::new("key").with_base_url(format!("http://{addr}/v1/"));
+        let (from, to) = (at("2025-01-01T00:00:00Z"), at("2025-07-01T00:00:00Z"));
+
+        let rates = fetch_charges(&api, "rates/", from, to).await.unwrap();
+        assert_eq!(rates.iter().map(|rate| rate.pence).collect::<Vec<_>>(), [25.0, 24.0]);
+
+        let looped = fetch_charges(&api, "looped/", from, to).await;
+        assert!(matches!(looped, Err(Error::Inconsistent(_))), "{looped:?}");
+    }
+}
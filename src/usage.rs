@@ -1,408 +1,617 @@
-use std::{collections::HashMap, env};
+use std::{collections::{BTreeMap, BTreeSet}, future::Future, str::FromStr};
 use chrono::{DateTime, Utc};
-use octopust::{models::{ListElectrictyConsumptionQuery, ListGasConsumptionQuery}, Client};
-use carbonintensity::Region;
-use log::warn;
+use octopust::models::{ConsumptionReading, ConsumptionResponse};
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::cache::ReadingCache;
+use crate::carbon_intensity::{self, GenerationMix, GenerationMixes, Intensities, IntensitySlot};
+use crate::error::{Error, Result};
+use crate::meter::Meter;
+use crate::octopus::OctopusApi;
+use crate::tariff::TariffRates;
+
+/// A year of half-hourly slots is ~17,500 readings, so expect a couple of pages.
+const CONSUMPTION_PAGE_SIZE: u32 = 10000;
+
+
+/// Fuel a reading belongs to, used as the `fuel` metric label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fuel {
+    Electricity,
+    Gas,
+}
+
+impl Fuel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Fuel::Electricity => "electricity",
+            Fuel::Gas => "gas",
+        }
+    }
+}
 
-use crate::carbon_intensity;
+impl FromStr for Fuel {
+    type Err = String;
 
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "electricity" => Ok(Fuel::Electricity),
+            "gas" => Ok(Fuel::Gas),
+            _ => Err(format!("invalid fuel '{s}', expected electricity or gas")),
+        }
+    }
+}
 
+/// Consumption of one meter over a single slot starting at `start`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reading {
+    pub start: DateTime<Utc>,
+    pub kwh: f64,
+}
+
+/// Usage, cost and carbon figures of one meter over a poll, keyed by reporting
+/// window. For an export meter usage is the energy exported, cost what it
+/// earned and carbon the emissions it avoided. Figures that couldn't be
+/// read are missing, with the error in `failures`.
+#[derive(Debug, Clone)]
 pub struct Summary {
-    pub e_usage_kwh_two_days: f64,
-    pub e_usage_kwh_week: f64,
-    pub e_usage_kwh_two_weeks: f64,
-    pub e_usage_kwh_four_weeks: f64,
-    pub e_usage_kwh_month: f64,
-    pub e_usage_kwh_two_months: f64,
-    pub e_usage_kwh_three_months: f64,
-    pub e_usage_kwh_six_months: f64,
-    pub e_usage_kwh_year: f64,
-    pub g_usage_kwh_two_days: f64,
-    pub g_usage_kwh_week: f64,
-    pub g_usage_kwh_two_weeks: f64,
-    pub g_usage_kwh_four_weeks: f64,
-    pub g_usage_kwh_month: f64,
-    pub g_usage_kwh_two_months: f64,
-    pub g_usage_kwh_three_months: f64,
-    pub g_usage_kwh_six_months: f64,
-    pub g_usage_kwh_year: f64,
-    pub carbon_intensity_two_days: f64,
-    pub carbon_intensity_week: f64,
-    pub carbon_intensity_two_weeks: f64,
-    pub carbon_intensity_four_weeks: f64,
-    pub carbon_intensity_month: f64,
-    pub carbon_intensity_two_months: f64,
-    pub carbon_intensity_three_months: f64,
-    pub carbon_intensity_six_months: f64,
-    pub carbon_intensity_year: f64,
+    pub meter: Meter,
+    usage_kwh: BTreeMap<String, f64>,
+    volume_m3: BTreeMap<String, f64>,
+    net_import_kwh: BTreeMap<String, f64>,
+    cost_pence: BTreeMap<String, f64>,
+    carbon_grams: BTreeMap<String, f64>,
+    carbon_average_grams: BTreeMap<String, f64>,
+    generation_kwh: BTreeMap<String, GenerationMix>,
+    standing_charge_pence: Option<f64>,
+    unit_rate_pence: BTreeMap<String, f64>,
+    failures: Vec<(Option<String>, Error)>,
+    /// Pages and readings fetched from the API for this poll.
+    fetched: Option<(u32, usize)>,
+}
+
+impl Summary {
+    pub fn new(meter: Meter) -> Self {
+        Summary {
+            meter,
+            usage_kwh: BTreeMap::new(),
+            volume_m3: BTreeMap::new(),
+            net_import_kwh: BTreeMap::new(),
+            cost_pence: BTreeMap::new(),
+            carbon_grams: BTreeMap::new(),
+            carbon_average_grams: BTreeMap::new(),
+            generation_kwh: BTreeMap::new(),
+            standing_charge_pence: None,
+            unit_rate_pence: BTreeMap::new(),
+            failures: Vec::new(),
+            fetched: None,
+        }
+    }
+
+    pub fn fuel(&self) -> Fuel {
+        self.meter.fuel
+    }
+
+    pub fn set_fetched(&mut self, pages: u32, readings: usize) {
+        self.fetched = Some((pages, readings));
+    }
+
+    pub fn set_usage(&mut self, window: &str, kwh: f64) {
+        self.usage_kwh.insert(window.to_string(), kwh);
+    }
+
+    /// Records the gas volume a meter reporting m³ measured, before conversion.
+    pub fn set_volume(&mut self, window: &str, cubic_metres: f64) {
+        self.volume_m3.insert(window.to_string(), cubic_metres);
+    }
+
+    /// Records usage less what the property's export meters exported.
+    pub fn set_net_import(&mut self, window: &str, kwh: f64) {
+        self.net_import_kwh.insert(window.to_string(), kwh);
+    }
+
+    pub fn set_cost(&mut self, window: &str, pence: f64) {
+        self.cost_pence.insert(window.to_string(), pence);
+    }
+
+    pub fn set_standing_charge(&mut self, pence_per_day: f64) {
+        self.standing_charge_pence = Some(pence_per_day);
+    }
+
+    /// Records a unit rate in pence per kWh, where `period` is e.g. `current` or `today_max`.
+    pub fn set_unit_rate(&mut self, period: &str, pence: f64) {
+        self.unit_rate_pence.insert(period.to_string(), pence);
+    }
+
+    pub fn set_carbon(&mut self, window: &str, grams: f64) {
+        self.carbon_grams.insert(window.to_string(), grams);
+    }
+
+    /// Records carbon estimated from the window's average intensity, for comparison.
+    pub fn set_carbon_average(&mut self, window: &str, grams: f64) {
+        self.carbon_average_grams.insert(window.to_string(), grams);
+    }
+
+    /// Records the window's usage in kWh by the fuel type that generated it.
+    pub fn set_generation(&mut self, window: &str, kwh: GenerationMix) {
+        self.generation_kwh.insert(window.to_string(), kwh);
+    }
+
+    /// Records an error that left out the figures of `window`, or of every window.
+    pub fn fail(&mut self, window: Option<&str>, error: Error) {
+        self.failures.push((window.map(str::to_string), error));
+    }
+
+    pub fn usage_for(&self, window: &str) -> Option<f64> {
+        self.usage_kwh.get(window).copied()
+    }
+
+    pub fn carbon_for(&self, window: &str) -> Option<f64> {
+        self.carbon_grams.get(window).copied()
+    }
+
+    pub fn cost_for(&self, window: &str) -> Option<f64> {
+        self.cost_pence.get(window).copied()
+    }
+
+    /// Every `(window, kWh)` entry, ordered by window.
+    pub fn usage(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.usage_kwh.iter().map(|(window, kwh)| (window.as_str(), *kwh))
+    }
+
+    /// How many pages and readings the poll fetched, when it got that far.
+    pub fn fetched(&self) -> Option<(u32, usize)> {
+        self.fetched
+    }
+
+    /// Every `(window, m³)` gas volume entry, ordered by window.
+    pub fn volume(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.volume_m3.iter().map(|(window, cubic_metres)| (window.as_str(), *cubic_metres))
+    }
+
+    /// Every `(window, kWh)` net import entry, ordered by window.
+    pub fn net_import(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.net_import_kwh.iter().map(|(window, kwh)| (window.as_str(), *kwh))
+    }
+
+    /// Every `(window, pence)` entry, ordered by window.
+    pub fn cost(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.cost_pence.iter().map(|(window, pence)| (window.as_str(), *pence))
+    }
+
+    /// Current standing charge in pence per day, when the meter is priced.
+    pub fn standing_charge(&self) -> Option<f64> {
+        self.standing_charge_pence
+    }
+
+    /// Every `(period, pence per kWh)` unit rate, ordered by period.
+    pub fn unit_rates(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.unit_rate_pence.iter().map(|(period, pence)| (period.as_str(), *pence))
+    }
+
+    /// Every `(window, grams)` entry, ordered by window.
+    pub fn carbon(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.carbon_grams.iter().map(|(window, grams)| (window.as_str(), *grams))
+    }
+
+    /// Every `(window, grams)` entry at the window's average intensity, ordered by window.
+    pub fn carbon_average(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.carbon_average_grams.iter().map(|(window, grams)| (window.as_str(), *grams))
+    }
+
+    /// Every error hit reading the meter, with the window it affected if only one.
+    pub fn failures(&self) -> impl Iterator<Item = (Option<&str>, &Error)> {
+        self.failures.iter().map(|(window, error)| (window.as_deref(), error))
+    }
+
+    /// Every `(window, fuel type, kWh)` entry, ordered by window then fuel type.
+    pub fn generation(&self) -> impl Iterator<Item = (&str, &str, f64)> {
+        self.generation_kwh
+            .iter()
+            .flat_map(|(window, mix)| mix.iter().map(move |(fuel, kwh)| (window.as_str(), fuel.as_str(), *kwh)))
+    }
 }
 
-pub async fn fetch_electricity_and_gas_consumption(
-    client: &Client,
-    period_to: &str,
-    periods: &HashMap<String, DateTime<Utc>>,
-    group_by_opts: &HashMap<String, &str>,
-    region: &str,
-) -> Result<Summary, Box<dyn std::error::Error>> {
-    let mpan = env::var("MPAN").expect("MPAN env variable not set");
-    let e_serial_number = env::var("E_SERIAL_NO").expect("E_SERIAL_NO env variable not set");
-    let mprn = env::var("MPRN").expect("MPRN env variable not set");
-    let g_serial_number = env::var("G_SERIAL_NO").expect("G_SERIAL_NO env variable not set");
-
-    let mut carbon_region= Region::England;
-    let mut e_usage_kwh_two_days = 0.0;
-    let mut e_usage_kwh_week = 0.0;
-    let mut e_usage_kwh_two_weeks = 0.0;
-    let mut e_usage_kwh_four_weeks = 0.0;
-    let mut e_usage_kwh_month = 0.0;
-    let mut e_usage_kwh_two_months = 0.0;
-    let mut e_usage_kwh_three_months = 0.0;
-    let mut e_usage_kwh_six_months = 0.0;
-    let mut e_usage_kwh_year = 0.0;
-
-    let mut carbon_intensity_two_days: f64 = 0.0;
-    let mut carbon_intensity_week: f64 = 0.0;
-    let mut carbon_intensity_two_weeks: f64 = 0.0;
-    let mut carbon_intensity_four_weeks: f64 = 0.0;
-    let mut carbon_intensity_month: f64 = 0.0;
-    let mut carbon_intensity_two_months: f64 = 0.0;
-    let mut carbon_intensity_three_months: f64 = 0.0;
-    let mut carbon_intensity_six_months: f64 = 0.0;
-    let mut carbon_intensity_year: f64 = 0.0;
-
-    let mut g_usage_kwh_two_days = 0.0;
-    let mut g_usage_kwh_week = 0.0;
-    let mut g_usage_kwh_two_weeks = 0.0;
-    let mut g_usage_kwh_four_weeks = 0.0;
-    let mut g_usage_kwh_month = 0.0;
-    let mut g_usage_kwh_two_months = 0.0;
-    let mut g_usage_kwh_three_months = 0.0;
-    let mut g_usage_kwh_six_months = 0.0;
-    let mut g_usage_kwh_year = 0.0;
-    
-    match region {
-      "North Scotland" => carbon_region = Region::NorthScotland,
-      "South Scotland" => carbon_region = Region::SouthScotland,
-      "North West England" => carbon_region = Region::NorthWestEngland,
-      "North East England" => carbon_region = Region::NorthEastEngland,
-      "South Yorkshire" => carbon_region = Region::SouthYorkshire,
-      "North Wales, Merseyside and Cheshire" => carbon_region = Region::NorthWalesMerseysideAndCheshire,
-      "South Wales" => carbon_region = Region::SouthWales,
-      "West Midlands" => carbon_region = Region::WestMidlands,
-      "East Midlands" => carbon_region = Region::EastMidlands,
-      "East England" => carbon_region = Region::EastEngland,
-      "South West England" => carbon_region = Region::SouthWestEngland,
-      "South England" => carbon_region = Region::SouthEngland,
-      "London" => carbon_region = Region::London,
-      "South East England" => carbon_region = Region::SouthEastEngland,
-      "England" => carbon_region = Region::England,
-      "Wales" => carbon_region = Region::Wales,
-      "Scotland" => carbon_region = Region::Scotland,
-      _ => { warn!("Warning: Unknown region '{region}' encountered.");}
-    }
-    // 1. Loop through both key and value
-    for value in group_by_opts.values() {
-        if *value == "hour" {
-            for (key, value) in periods {
-                 let e_readings = client.list_electricity_consumption(ListElectrictyConsumptionQuery { 
-                    mpan: &mpan, 
-                    group_by: Some("hour"), 
-                    serial_number: &e_serial_number, 
-                    period_from:Some(&value.format("%Y-%m-%dT%H:%M:%SZ").to_string()) , 
-                    period_to: Some(period_to), 
-                    page_size: Some(10000),
-                    ..Default::default()
-                 }).await;
-
-                 let g_readings = client.list_gas_consumption(ListGasConsumptionQuery{
-                    mprn: &mprn,
-                    serial_number: &g_serial_number,
-                    group_by: Some("hour"),
-                    period_to: Some(period_to),
-                    period_from: Some(&value.format("%Y-%m-%dT%H:%M:%SZ").to_string()),
-                    page_size: Some(10000),
-                    ..Default::default()
-                }).await;
-
-
-                 match key.as_str() {
-                     "2d" => {
-                         e_usage_kwh_two_days = e_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-
-                        let period_from = &value.format("%Y-%m-%dT%H:%M:%SZ").to_string();
-                        let ci = carbon_intensity::get_carbon_intensity(e_usage_kwh_two_days, carbon_region, period_from, Some(period_to));
-                        carbon_intensity_two_days = ci.await.unwrap();
-                        
-                        g_usage_kwh_two_days = g_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-
-                    
-                     }
-
-                     "1w" => {
-                        e_usage_kwh_week = e_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-
-                        let period_from = &value.format("%Y-%m-%dT%H:%M:%SZ").to_string();
-                        let ci = carbon_intensity::get_carbon_intensity(e_usage_kwh_week, carbon_region, period_from, Some(period_to));
-                        carbon_intensity_week = ci.await.unwrap();
-
-                        g_usage_kwh_week = g_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-                     }
-
-                     "2w" => {
-                        e_usage_kwh_two_weeks = e_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-
-                        let period_from = &value.format("%Y-%m-%dT%H:%M:%SZ").to_string();
-                        let ci = carbon_intensity::get_carbon_intensity(e_usage_kwh_two_weeks, carbon_region, period_from, Some(period_to));
-                        carbon_intensity_two_weeks = ci.await.unwrap();
-
-                        g_usage_kwh_two_weeks = g_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-                     }
-
-                     "4w" => {
-                        e_usage_kwh_four_weeks = e_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-
-                        let period_from = &value.format("%Y-%m-%dT%H:%M:%SZ").to_string();
-                        let ci = carbon_intensity::get_carbon_intensity(e_usage_kwh_four_weeks, carbon_region, period_from, Some(period_to));
-                        carbon_intensity_four_weeks = ci.await.unwrap();
-
-                        g_usage_kwh_four_weeks = g_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-                     }
-
-                     "1m" => {
-                        e_usage_kwh_month = e_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-                     
-                        let period_from = &value.format("%Y-%m-%dT%H:%M:%SZ").to_string();
-                        let ci = carbon_intensity::get_carbon_intensity(e_usage_kwh_month, carbon_region, period_from, Some(period_to));
-                        carbon_intensity_month = ci.await.unwrap();
-
-                        g_usage_kwh_month = g_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-                     }
-
-                     "2m" => {
-                        e_usage_kwh_two_months = e_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-
-                        let period_from = &value.format("%Y-%m-%dT%H:%M:%SZ").to_string();
-                        let ci = carbon_intensity::get_carbon_intensity(e_usage_kwh_two_months, carbon_region, period_from, Some(period_to));
-                        carbon_intensity_two_months = ci.await.unwrap();
-
-                        g_usage_kwh_two_months = g_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-                     }
-
-                     "3m" => {
-                        e_usage_kwh_three_months = e_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-
-                     
-                        let period_from = &value.format("%Y-%m-%dT%H:%M:%SZ").to_string();
-                        let ci = carbon_intensity::get_carbon_intensity(e_usage_kwh_three_months, carbon_region, period_from, Some(period_to));
-                        carbon_intensity_three_months = ci.await.unwrap();
-
-                        g_usage_kwh_three_months = g_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-                     }
-
-                     "6m" => {
-                        e_usage_kwh_six_months = e_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-
-                        let period_from = &value.format("%Y-%m-%dT%H:%M:%SZ").to_string();
-                        let ci = carbon_intensity::get_carbon_intensity(e_usage_kwh_six_months, carbon_region, period_from, Some(period_to));
-                        carbon_intensity_six_months = ci.await.unwrap();
-
-                        g_usage_kwh_six_months = g_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-                     }
-
-                     "1y" => {
-                        e_usage_kwh_year = e_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-
-                        let period_from = &value.format("%Y-%m-%dT%H:%M:%SZ").to_string();
-                        let ci = carbon_intensity::get_carbon_intensity(e_usage_kwh_year, carbon_region, period_from, Some(period_to));
-                        carbon_intensity_year = ci.await.unwrap();
-
-                        g_usage_kwh_year = g_readings
-                        .unwrap().results
-                        .iter()
-                        .map(|reading| reading.consumption)
-                        .sum();
-                     }
-                     _ => { warn!("Warning: Unknown period key '{key}' encountered.");}
-                 }
+/// Usage, carbon and cost of a meter over each reporting window, from one
+/// fetch of its readings across the widest span of them all, or with a
+/// `cache` only the part of it since the last poll. Carbon and the
+/// generation mix come from `history`, the regional intensity over the same
+/// span, and are only worked out for electricity. When the readings can't be
+/// fetched every window is left out and the failure recorded.
+pub async fn fetch_consumption(
+    api: &OctopusApi,
+    meter: &Meter,
+    periods: &[(String, DateTime<Utc>, DateTime<Utc>)],
+    history: Option<&[IntensitySlot]>,
+    rates: Option<&TariffRates>,
+    cache: Option<&ReadingCache>,
+) -> Summary {
+    let (Some(from), Some(to)) = (periods.iter().map(|p| p.1).min(), periods.iter().map(|p| p.2).max()) else {
+        return Summary::new(meter.clone());
+    };
+    match load_readings(api, meter, from, to, cache).await {
+        Ok((readings, pages, fetched)) => {
+            let mut summary = summarise(meter, periods, readings, history, rates);
+            summary.set_fetched(pages, fetched);
+            summary
+        }
+        Err(e) => {
+            let mut summary = Summary::new(meter.clone());
+            summary.fail(None, e);
+            summary
+        }
+    }
+}
+
+/// Readings of `meter` from `from` to `to`, with how many pages and
+/// readings it took to fetch them. With a `cache` only the slots that may
+/// have changed since they were last fetched come from the API.
+pub async fn load_readings(
+    api: &OctopusApi,
+    meter: &Meter,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    cache: Option<&ReadingCache>,
+) -> Result<(Vec<Reading>, u32, usize)> {
+    let mut cached = cache.map(|cache| (cache, cache.load(meter, from)));
+    let fetch_from = cached.as_ref().map_or(from, |(cache, readings)| cache.fetch_from(readings, from));
+
+    let (readings, pages) = fetch_readings(api, meter, fetch_from, to).await?;
+    info!(
+        "Fetched {} readings in {pages} pages for {} meter point {}",
+        readings.len(),
+        meter.fuel.as_str(),
+        meter.meter_point,
+    );
+    let fetched = readings.len();
+    let readings = match &mut cached {
+        Some((cache, cached)) => {
+            cached.merge(from, fetch_from, to, &readings);
+            cache.save(meter, cached);
+            cached.readings(from, to)
+        }
+        None => readings,
+    };
+    Ok((readings, pages, fetched))
+}
 
+/// Works out every window's figures from `readings` covering all of them,
+/// so the windows agree with each other.
+fn summarise(
+    meter: &Meter,
+    periods: &[(String, DateTime<Utc>, DateTime<Utc>)],
+    mut readings: Vec<Reading>,
+    history: Option<&[IntensitySlot]>,
+    rates: Option<&TariffRates>,
+) -> Summary {
+    let mut summary = Summary::new(meter.clone());
+    readings.sort_by_key(|reading| reading.start);
+
+    // Meters reporting m³ are converted slot by slot, so they're priced per
+    // kWh like the bill. The volumes are kept to report alongside.
+    let volumes = meter.gas_conversion.map(|conversion| {
+        let volumes: Vec<f64> = readings.iter().map(|reading| reading.kwh).collect();
+        for reading in &mut readings {
+            reading.kwh = conversion.kwh(reading.kwh);
+        }
+        volumes
+    });
+
+    // Exported energy displaces grid generation at the same intensity
+    let history = history.filter(|_| meter.fuel == Fuel::Electricity);
+    let intensities = history.map(carbon_intensity::intensities);
+    // What an export meter generated isn't drawn from the grid's mix
+    let mixes = history.filter(|_| !meter.export).map(carbon_intensity::generation_mixes);
+
+    for (key, from, to) in periods {
+        let first = readings.partition_point(|reading| reading.start < *from);
+        let last = readings.partition_point(|reading| reading.start < *to);
+        let window = &readings[first..last];
+
+        if let Some(volumes) = &volumes {
+            summary.set_volume(key, volumes[first..last].iter().sum());
+        }
+        let usage_kwh: f64 = window.iter().map(|reading| reading.kwh).sum();
+        summary.set_usage(key, usage_kwh);
+
+        if let Some(intensities) = &intensities {
+            let slots: Intensities = intensities.range(*from..*to).map(|(start, g)| (*start, *g)).collect();
+            if let Some(grams) = carbon_intensity::slot_carbon(window, &slots) {
+                summary.set_carbon(key, grams);
+            }
+            if let Some(average) = carbon_intensity::average_intensity(&slots) {
+                summary.set_carbon_average(key, usage_kwh * average);
             }
+        }
+        if let Some(mixes) = &mixes {
+            let slots: GenerationMixes = mixes.range(*from..*to).map(|(start, mix)| (*start, mix.clone())).collect();
+            if let Some(kwh) = carbon_intensity::mix_usage(window, &slots) {
+                summary.set_generation(key, kwh);
+            }
+        }
 
+        if let Some(rates) = rates {
+            match rates.energy_cost_pence(window) {
+                Some(mut pence) => {
+                    if !meter.export {
+                        pence += rates.standing_charge_pence(*from, *to);
+                    }
+                    summary.set_cost(key, pence);
+                }
+                None => warn!("No unit rate for every slot of {key} on meter {}, its cost was left out", meter.meter_point),
+            }
         }
     }
 
-    Ok(Summary {
-        e_usage_kwh_two_days,
-        e_usage_kwh_week,
-        e_usage_kwh_two_weeks,
-        e_usage_kwh_four_weeks,
-        e_usage_kwh_month,
-        e_usage_kwh_two_months,
-        e_usage_kwh_three_months,
-        e_usage_kwh_six_months,
-        e_usage_kwh_year,
-        g_usage_kwh_two_days,
-        g_usage_kwh_week,
-        g_usage_kwh_two_weeks,
-        g_usage_kwh_four_weeks,
-        g_usage_kwh_month,
-        g_usage_kwh_two_months,
-        g_usage_kwh_three_months,
-        g_usage_kwh_six_months,
-        g_usage_kwh_year,
-        carbon_intensity_two_days,
-        carbon_intensity_week,
-        carbon_intensity_two_weeks,
-        carbon_intensity_four_weeks,
-        carbon_intensity_month,
-        carbon_intensity_two_months,
-        carbon_intensity_three_months,
-        carbon_intensity_six_months,
-        carbon_intensity_year,
+    summary
+}
+
+/// Every half-hourly reading of `meter` from `from` to `to`, with the
+/// number of pages it took. Half-hourly slots, so each one can be priced at
+/// its own rate.
+pub async fn fetch_readings(api: &OctopusApi, meter: &Meter, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<(Vec<Reading>, u32)> {
+    let points = match meter.fuel {
+        Fuel::Electricity => "electricity-meter-points",
+        Fuel::Gas => "gas-meter-points",
+    };
+    let path = format!("{points}/{}/meters/{}/consumption/", meter.meter_point, meter.serial_number);
+    let path = &path;
+    let period_from = api_time(from);
+    let period_to = api_time(to);
+    let params = &[
+        ("period_from", period_from),
+        ("period_to", period_to),
+        ("page_size", CONSUMPTION_PAGE_SIZE.to_string()),
+    ];
+    all_pages(from, to, |next| async move {
+        match next {
+            Some(url) => api.get_next::<ConsumptionResponse>(&url).await,
+            None => api.get::<ConsumptionResponse>(path, params).await,
+        }
     })
+    .await
+}
+
+fn api_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// Every reading of a consumption query from `from` to `to`, with the
+/// number of pages. `fetch_page` gets the first page given `None`, then
+/// each `next` link in turn until the API has no pages left. The readings
+/// have to add up to the count the API gave, and can't outnumber the half
+/// hours in the span, or some were lost or repeated on the way.
+async fn all_pages<F, Fut>(from: DateTime<Utc>, to: DateTime<Utc>, mut fetch_page: F) -> Result<(Vec<Reading>, u32)>
+where
+    F: FnMut(Option<String>) -> Fut,
+    Fut: Future<Output = Result<ConsumptionResponse>>,
+{
+    let mut readings = Vec::new();
+    let mut pages = 0;
+    let mut count = None;
+    let mut seen = BTreeSet::new();
+    let mut next = None;
+    loop {
+        let response = fetch_page(next.take()).await?;
+        pages += 1;
+        count.get_or_insert(response.count);
+        if response.results.is_empty() && response.next.is_some() {
+            return Err(Error::Inconsistent(format!("page {pages} of consumption is empty but isn't the last")));
+        }
+        readings.extend(to_readings(&response.results)?);
+        match response.next {
+            Some(url) if !seen.insert(url.clone()) => {
+                return Err(Error::Inconsistent(format!("page {pages} of consumption links back to {url}")));
+            }
+            Some(url) => next = Some(url),
+            None => break,
+        }
+    }
+
+    let count = count.unwrap_or_default() as usize;
+    if readings.len() != count {
+        return Err(Error::Inconsistent(format!(
+            "the API counted {count} readings but {} came back in {pages} pages",
+            readings.len(),
+        )));
+    }
+    let slots = ((to - from).num_minutes().max(0) + 29) / 30;
+    if readings.len() as i64 > slots {
+        return Err(Error::Inconsistent(format!(
+            "{} readings is more than the {slots} half hours from {} to {}",
+            readings.len(),
+            api_time(from),
+            api_time(to),
+        )));
+    }
+    Ok((readings, pages))
+}
+
+fn to_readings(results: &[ConsumptionReading]) -> Result<Vec<Reading>> {
+    results
+        .iter()
+        .map(|reading| {
+            let start = DateTime::parse_from_rfc3339(&reading.interval_start)
+                .map_err(|e| Error::Parse(format!("invalid interval_start '{}': {e}", reading.interval_start)))?;
+            Ok(Reading { start: start.with_timezone(&Utc), kwh: reading.consumption })
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::gas::GasConversion;
+    use crate::meter::test_meter;
 
     #[test]
     fn test_summary_struct_defaults() {
-        let summary = Summary {
-            e_usage_kwh_two_days: 1.0,
-            e_usage_kwh_week: 2.0,
-            e_usage_kwh_two_weeks: 3.0,
-            e_usage_kwh_four_weeks: 4.0,
-            e_usage_kwh_month: 5.0,
-            e_usage_kwh_two_months: 6.0,
-            e_usage_kwh_three_months: 7.0,
-            e_usage_kwh_six_months: 8.0,
-            e_usage_kwh_year: 9.0,
-            g_usage_kwh_two_days: 10.0,
-            g_usage_kwh_week: 11.0,
-            g_usage_kwh_two_weeks: 12.0,
-            g_usage_kwh_four_weeks: 13.0,
-            g_usage_kwh_month: 14.0,
-            g_usage_kwh_two_months: 15.0,
-            g_usage_kwh_three_months: 16.0,
-            g_usage_kwh_six_months: 17.0,
-            g_usage_kwh_year: 18.0,
-            carbon_intensity_two_days: 19.0,
-            carbon_intensity_week: 20.0,
-            carbon_intensity_two_weeks: 21.0,
-            carbon_intensity_four_weeks: 22.0,
-            carbon_intensity_month: 23.0,
-            carbon_intensity_two_months: 24.0,
-            carbon_intensity_three_months: 25.0,
-            carbon_intensity_six_months: 26.0,
-            carbon_intensity_year: 27.0,
-        };
-        assert_eq!(summary.e_usage_kwh_two_days, 1.0);
-        assert_eq!(summary.g_usage_kwh_year, 18.0);
-        assert_eq!(summary.carbon_intensity_year, 27.0);
+        let mut summary = Summary::new(test_meter(Fuel::Electricity, "1200000000001"));
+        summary.set_usage("2d", 1.0);
+        summary.set_carbon("1y", 27.0);
+
+        assert_eq!(summary.fuel(), Fuel::Electricity);
+        assert_eq!(summary.usage_for("2d"), Some(1.0));
+        assert_eq!(summary.carbon_for("1y"), Some(27.0));
+        assert_eq!(summary.usage_for("1y"), None);
+        assert_eq!(summary.standing_charge(), None);
     }
 
     #[test]
-    fn test_region_mapping() {
-        // Should match known regions
-        let region_names = vec![
-            "North Scotland", "South Scotland", "North West England", "North East England",
-            "South Yorkshire", "North Wales, Merseyside and Cheshire", "South Wales", "West Midlands",
-            "East Midlands", "East England", "South West England", "South England", "London",
-            "South East England", "England", "Wales", "Scotland"
+    fn test_windows_summarised_from_one_fetch() {
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        let mut meter = test_meter(Fuel::Gas, "3000000001");
+        meter.gas_conversion = Some(GasConversion { calorific_value: 36.0, volume_correction: 1.0 });
+        // A cubic metre in each of the last three days, fetched out of order
+        let readings = vec![
+            Reading { start: at("2025-08-03T12:00:00Z"), kwh: 1.0 },
+            Reading { start: at("2025-08-01T12:00:00Z"), kwh: 1.0 },
+            Reading { start: at("2025-08-02T12:00:00Z"), kwh: 1.0 },
+        ];
+        let periods = [
+            ("2d".to_string(), at("2025-08-02T00:00:00Z"), at("2025-08-04T00:00:00Z")),
+            ("1d".to_string(), at("2025-08-03T00:00:00Z"), at("2025-08-04T00:00:00Z")),
+            ("mtd".to_string(), at("2025-08-01T00:00:00Z"), at("2025-08-04T00:00:00Z")),
+            ("old".to_string(), at("2025-07-01T00:00:00Z"), at("2025-08-01T00:00:00Z")),
         ];
-        for region in region_names {
-            let mut carbon_region = carbonintensity::Region::England;
-            match region {
-                "North Scotland" => carbon_region = carbonintensity::Region::NorthScotland,
-                "South Scotland" => carbon_region = carbonintensity::Region::SouthScotland,
-                "North West England" => carbon_region = carbonintensity::Region::NorthWestEngland,
-                "North East England" => carbon_region = carbonintensity::Region::NorthEastEngland,
-                "South Yorkshire" => carbon_region = carbonintensity::Region::SouthYorkshire,
-                "North Wales, Merseyside and Cheshire" => carbon_region = carbonintensity::Region::NorthWalesMerseysideAndCheshire,
-                "South Wales" => carbon_region = carbonintensity::Region::SouthWales,
-                "West Midlands" => carbon_region = carbonintensity::Region::WestMidlands,
-                "East Midlands" => carbon_region = carbonintensity::Region::EastMidlands,
-                "East England" => carbon_region = carbonintensity::Region::EastEngland,
-                "South West England" => carbon_region = carbonintensity::Region::SouthWestEngland,
-                "South England" => carbon_region = carbonintensity::Region::SouthEngland,
-                "London" => carbon_region = carbonintensity::Region::London,
-                "South East England" => carbon_region = carbonintensity::Region::SouthEastEngland,
-                "England" => carbon_region = carbonintensity::Region::England,
-                "Wales" => carbon_region = carbonintensity::Region::Wales,
-                "Scotland" => carbon_region = carbonintensity::Region::Scotland,
-                _ => {}
+
+        let summary = summarise(&meter, &periods, readings, None, None);
+        assert_eq!(summary.usage().collect::<Vec<_>>(), [("1d", 10.0), ("2d", 20.0), ("mtd", 30.0), ("old", 0.0)]);
+        assert_eq!(summary.volume().collect::<Vec<_>>(), [("1d", 1.0), ("2d", 2.0), ("mtd", 3.0), ("old", 0.0)]);
+        assert_eq!(summary.carbon().next(), None);
+    }
+
+    #[tokio::test]
+    async fn test_readings_fetched_from_meter_consumption() {
+        let base_url = crate::octopus::mock_server(vec![(
+            "/v1/gas-meter-points/3000000001/meters/S0000001/consumption/",
+            serde_json::json!({
+                "count": 1,
+                "next": null,
+                "previous": null,
+                "results": [{"consumption": 1.5, "interval_start": "2025-08-01T00:00:00Z", "interval_end": "2025-08-01T00:30:00Z"}],
+            }),
+        )]);
+        let api = OctopusApi::new("key").with_base_url(base_url);
+        let mut meter = test_meter(Fuel::Gas, "3000000001");
+        meter.serial_number = "S0000001".to_string();
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+
+        let (readings, pages) = fetch_readings(&api, &meter, at("2025-08-01T00:00:00Z"), at("2025-08-01T01:00:00Z")).await.unwrap();
+        assert_eq!(readings, [Reading { start: at("2025-08-01T00:00:00Z"), kwh: 1.5 }]);
+        assert_eq!(pages, 1);
+    }
+
+    #[tokio::test]
+    async fn test_all_pages_followed_and_checked() {
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        let (from, to) = (at("2025-08-01T00:00:00Z"), at("2025-08-01T02:00:00Z"));
+        let page = |count: u32, starts: &[&str], next: bool| -> ConsumptionResponse {
+            let results: Vec<_> = starts
+                .iter()
+                .map(|start| serde_json::json!({"consumption": 0.5, "interval_start": start, "interval_end": start}))
+                .collect();
+            let next = next.then_some("https://api.octopus.energy/v1/next/");
+            serde_json::from_value(serde_json::json!({"count": count, "next": next, "previous": null, "results": results})).unwrap()
+        };
+        let pages = |pages: Vec<ConsumptionResponse>| {
+            let pages = std::sync::Mutex::new(pages.into_iter());
+            move |_| {
+                let page = pages.lock().unwrap().next();
+                async move { page.ok_or_else(|| Error::octopus("no more pages")) }
             }
-            // This test just ensures we don't panic on mapping
-            assert!(matches!(carbon_region, _));
-        }
+        };
+
+        let (readings, fetched) = all_pages(from, to, pages(vec![
+            page(3, &["2025-08-01T00:00:00Z", "2025-08-01T00:30:00Z"], true),
+            page(3, &["2025-08-01T01:00:00Z"], false),
+        ])).await.unwrap();
+        assert_eq!(readings.len(), 3);
+        assert_eq!(fetched, 2);
+
+        // Readings lost between pages, a page that runs dry, and more readings than half hours
+        let lost = all_pages(from, to, pages(vec![page(3, &["2025-08-01T00:00:00Z"], true), page(3, &["2025-08-01T01:00:00Z"], false)])).await;
+        assert!(matches!(lost, Err(Error::Inconsistent(_))), "{lost:?}");
+        let dry = all_pages(from, to, pages(vec![page(1, &[], true)])).await;
+        assert!(matches!(dry, Err(Error::Inconsistent(_))), "{dry:?}");
+        let starts = ["2025-08-01T00:00:00Z"; 5];
+        let extra = all_pages(from, to, pages(vec![page(5, &starts, false)])).await;
+        assert!(matches!(extra, Err(Error::Inconsistent(_))), "{extra:?}");
+
+        // A next link that goes round in a loop
+        let looped = all_pages(from, to, pages(vec![
+            page(4, &["2025-08-01T00:00:00Z"], true),
+            page(4, &["2025-08-01T00:30:00Z"], true),
+        ])).await;
+        assert!(matches!(looped, Err(Error::Inconsistent(_))), "{looped:?}");
+    }
+
+    #[tokio::test]
+    async fn test_next_links_followed_over_http() {
+        use std::collections::HashMap;
+        use warp::Filter;
+
+        // Page 2 is only reachable through the first page's next link
+        let routes = warp::path::full()
+            .and(warp::header::<String>("host"))
+            .and(warp::query::<HashMap<String, String>>())
+            .map(|path: warp::path::FullPath, host: String, query: HashMap<String, String>| {
+                let (start, next) = match query.get("cursor") {
+                    None => ("2025-08-01T00:00:00Z", Some(format!("http://{host}{}?cursor=b", path.as_str()))),
+                    Some(_) => ("2025-08-01T00:30:00Z", None),
+                };
+                warp::reply::json(&serde_json::json!({
+                    "count": 2,
+                    "next": next,
+                    "previous": null,
+                    "results": [{"consumption": 0.5, "interval_start": start, "interval_end": start}],
+                }))
+            });
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let api = OctopusApi::new("key").with_base_url(format!("http://{addr}/v1/"));
+        let meter = test_meter(Fuel::Electricity, "1200000000001");
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        let (from, to) = (at("2025-08-01T00:00:00Z"), at("2025-08-01T01:00:00Z"));
+
+        let (readings, pages) = fetch_readings(&api, &meter, from, to).await.unwrap();
+        assert_eq!(readings.iter().map(|r| r.start).collect::<Vec<_>>(), [from, at("2025-08-01T00:30:00Z")]);
+        assert_eq!(pages, 2);
+
+        // A link off the API isn't followed, so the key isn't sent there
+        let offsite = api.get_next::<ConsumptionResponse>("https://example.com/v1/consumption/").await;
+        assert!(matches!(offsite, Err(Error::Inconsistent(_))), "{offsite:?}");
+    }
+
+    #[test]
+    fn test_summary_iterates_by_window() {
+        let mut summary = Summary::new(test_meter(Fuel::Gas, "3000000001"));
+        summary.set_usage("2w", 2.0);
+        summary.set_usage("1w", 1.0);
+        // Setting a window twice keeps the latest value
+        summary.set_usage("1w", 1.5);
+
+        let entries: Vec<_> = summary.usage().collect();
+        assert_eq!(entries, vec![("1w", 1.5), ("2w", 2.0)]);
+    }
+
+    #[test]
+    fn test_to_readings_parses_interval_start() {
+        let results: Vec<ConsumptionReading> = serde_json::from_value(serde_json::json!([
+            {"consumption": 0.25, "interval_start": "2025-04-01T00:00:00+01:00", "interval_end": "2025-04-01T00:30:00+01:00"},
+            {"consumption": 0.5, "interval_start": "2025-03-31T23:30:00Z", "interval_end": "2025-04-01T00:00:00Z"}
+        ])).unwrap();
+
+        let readings = to_readings(&results).unwrap();
+        assert_eq!(readings[0].start.to_rfc3339(), "2025-03-31T23:00:00+00:00");
+        assert_eq!(readings[1].kwh, 0.5);
+
+        let invalid: Vec<ConsumptionReading> = serde_json::from_value(serde_json::json!([
+            {"consumption": 0.25, "interval_start": "yesterday", "interval_end": "today"}
+        ])).unwrap();
+        assert!(to_readings(&invalid).is_err());
     }
 
     // Example async test for future logic (requires tokio)
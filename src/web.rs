@@ -0,0 +1,435 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt, fs,
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use prometheus::{Encoder, TextEncoder};
+use serde::Deserialize;
+use warp::{Filter, Rejection, Reply, filters::BoxedFilter, http::StatusCode, reply::Response};
+
+use crate::health::{Health, Status};
+use crate::metrics::Metrics;
+
+/// Where the metrics server listens: `host:port`, `[v6]:port`, `:port` for
+/// every IPv4 interface, or `unix:/path/to.sock`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix socket listen address needs a path, e.g. unix:/run/octopus.sock".to_string());
+            }
+            return Ok(ListenAddress::Unix(PathBuf::from(path)));
+        }
+        let addr = match s.strip_prefix(':') {
+            Some(port) => format!("0.0.0.0:{port}"),
+            None => s.to_string(),
+        };
+        addr.parse()
+            .map(ListenAddress::Tcp)
+            .map_err(|_| format!("invalid listen address '{s}', expected e.g. 0.0.0.0:9090, [::]:9090, :9090 or unix:/path"))
+    }
+}
+
+impl TryFrom<String> for ListenAddress {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(addr) => write!(f, "{addr}"),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// TLS and auth for the metrics server, in the Prometheus exporter-toolkit
+/// web config format:
+///
+/// ```yaml
+/// tls_server_config:
+///   cert_file: server.crt
+///   key_file: server.key
+/// basic_auth_users:
+///   prometheus: $2y$10$...   # bcrypt hash, e.g. from `htpasswd -nBC 10 ""`
+/// ```
+///
+/// `bearer_token` or `bearer_token_file` are accepted as well, for scrapers
+/// that send `Authorization: Bearer`. Relative paths are relative to the file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebConfig {
+    pub tls_server_config: Option<TlsConfig>,
+    #[serde(default)]
+    pub basic_auth_users: BTreeMap<String, String>,
+    pub bearer_token: Option<String>,
+    pub bearer_token_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    #[serde(default)]
+    pub client_auth_type: ClientAuthType,
+    /// CA certificates client certificates are verified against.
+    pub client_ca_file: Option<PathBuf>,
+}
+
+/// The client certificate policies of the exporter-toolkit that can be enforced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum ClientAuthType {
+    #[default]
+    NoClientCert,
+    VerifyClientCertIfGiven,
+    RequireAndVerifyClientCert,
+}
+
+impl WebConfig {
+    /// Reads and checks a web config file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("can't read web config {}: {e}", path.display()))?;
+        let mut config: WebConfig =
+            serde_yaml::from_str(&text).map_err(|e| format!("invalid web config {}: {e}", path.display()))?;
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        if let Some(tls) = &mut config.tls_server_config {
+            tls.cert_file = dir.join(&tls.cert_file);
+            tls.key_file = dir.join(&tls.key_file);
+            tls.client_ca_file = tls.client_ca_file.as_ref().map(|file| dir.join(file));
+        }
+        if let Some(file) = &config.bearer_token_file {
+            let token = fs::read_to_string(dir.join(file))
+                .map_err(|e| format!("can't read bearer token from {}: {e}", file.display()))?;
+            config.bearer_token = Some(token.trim().to_string());
+            config.bearer_token_file = None;
+        }
+
+        config.validate().map_err(|e| format!("invalid web config {}: {e}", path.display()))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(tls) = &self.tls_server_config {
+            for file in [Some(&tls.cert_file), Some(&tls.key_file), tls.client_ca_file.as_ref()].into_iter().flatten() {
+                fs::metadata(file).map_err(|e| format!("can't read {}: {e}", file.display()))?;
+            }
+            let verifies = tls.client_auth_type != ClientAuthType::NoClientCert;
+            if verifies != tls.client_ca_file.is_some() {
+                return Err("client_ca_file is needed exactly when client_auth_type verifies client certificates".to_string());
+            }
+        }
+        for (user, hash) in &self.basic_auth_users {
+            hash.parse::<bcrypt::HashParts>()
+                .map_err(|_| format!("the password of basic auth user '{user}' isn't a bcrypt hash"))?;
+        }
+        if self.bearer_token.as_ref().is_some_and(|token| token.trim().is_empty()) {
+            return Err("bearer_token is empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Checks the `Authorization` header of scrapes against the configured users
+/// and token. Verified basic credentials are remembered, as bcrypt is slow by design.
+pub struct Auth {
+    basic_auth_users: BTreeMap<String, String>,
+    bearer_token: Option<String>,
+    verified: Mutex<HashSet<String>>,
+}
+
+impl Auth {
+    pub fn new(config: &WebConfig) -> Self {
+        Auth {
+            basic_auth_users: config.basic_auth_users.clone(),
+            bearer_token: config.bearer_token.clone(),
+            verified: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        !self.basic_auth_users.is_empty() || self.bearer_token.is_some()
+    }
+
+    /// Whether a request with this `Authorization` header may read the metrics.
+    pub fn allows(&self, header: Option<&str>) -> bool {
+        if !self.enabled() {
+            return true;
+        }
+        let Some((scheme, credentials)) = header.and_then(|header| header.trim().split_once(' ')) else {
+            return false;
+        };
+        let credentials = credentials.trim();
+
+        if scheme.eq_ignore_ascii_case("bearer") {
+            return self.bearer_token.as_deref().is_some_and(|token| constant_time_eq(token.as_bytes(), credentials.as_bytes()));
+        }
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return false;
+        }
+        if self.verified.lock().is_ok_and(|verified| verified.contains(credentials)) {
+            return true;
+        }
+
+        let Some(decoded) = BASE64.decode(credentials).ok().and_then(|bytes| String::from_utf8(bytes).ok()) else {
+            return false;
+        };
+        let Some((user, password)) = decoded.split_once(':') else {
+            return false;
+        };
+        let allowed = self
+            .basic_auth_users
+            .get(user)
+            .is_some_and(|hash| bcrypt::verify(password, hash).unwrap_or(false));
+        if allowed && let Ok(mut verified) = self.verified.lock() {
+            verified.insert(credentials.to_string());
+        }
+        allowed
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// `/metrics`, behind `auth`, and the unauthenticated `/health` for probes,
+/// which fails once the figures have gone stale.
+pub fn routes(metrics: Arc<Metrics>, auth: Arc<Auth>, health: Arc<Health>) -> BoxedFilter<(Response,)> {
+    let authorized = warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let auth = Arc::clone(&auth);
+            async move {
+                if auth.allows(header.as_deref()) {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one();
+
+    let metrics_route = warp::path!("metrics").and(authorized).map(move || {
+        let encoder = TextEncoder::new();
+        let metric_families = metrics.registry.gather();
+        let mut buffer = Vec::new();
+        if let Err(e) = encoder.encode(&metric_families, &mut buffer) {
+            let reply = warp::reply::with_status(format!("can't encode metrics: {e}"), StatusCode::INTERNAL_SERVER_ERROR);
+            return reply.into_response();
+        }
+
+        let reply = warp::reply::with_header(buffer, "Content-Type", encoder.format_type());
+        reply.into_response()
+    });
+    let health_route = warp::path!("health").map(move || match health.status() {
+        Status::Ok => warp::reply::with_status("OK".to_string(), StatusCode::OK),
+        Status::Degraded(failures) => warp::reply::with_status(format!("DEGRADED\n{}\n", failures.join("\n")), StatusCode::OK),
+        Status::Unhealthy(reason) => warp::reply::with_status(format!("UNHEALTHY: {reason}\n"), StatusCode::SERVICE_UNAVAILABLE),
+    });
+
+    metrics_route
+        .or(health_route)
+        .recover(handle_rejection)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<Response, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        let reply = warp::reply::with_status("Unauthorized", StatusCode::UNAUTHORIZED);
+        return Ok(warp::reply::with_header(reply, "WWW-Authenticate", "Basic realm=\"octopus-energy-exporter\"").into_response());
+    }
+    Err(rejection)
+}
+
+/// Serves `routes` on `listen`, over TLS when it's configured, until `shutdown` completes.
+pub async fn serve(
+    routes: BoxedFilter<(Response,)>,
+    listen: &ListenAddress,
+    tls: Option<&TlsConfig>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), String> {
+    match (listen, tls) {
+        (ListenAddress::Tcp(addr), None) => {
+            let (_, server) = warp::serve(routes)
+                .try_bind_with_graceful_shutdown(*addr, shutdown)
+                .map_err(|e| format!("can't listen on {addr}: {e}"))?;
+            server.await;
+        }
+        (ListenAddress::Tcp(addr), Some(tls)) => {
+            let server = warp::serve(routes).tls().cert_path(&tls.cert_file).key_path(&tls.key_file);
+            let server = match (tls.client_auth_type, &tls.client_ca_file) {
+                (ClientAuthType::VerifyClientCertIfGiven, Some(ca)) => server.client_auth_optional_path(ca),
+                (ClientAuthType::RequireAndVerifyClientCert, Some(ca)) => server.client_auth_required_path(ca),
+                _ => server,
+            };
+            let (_, server) = server
+                .try_bind_with_graceful_shutdown(*addr, shutdown)
+                .map_err(|e| format!("can't listen with TLS on {addr}: {e}"))?;
+            server.await;
+        }
+        (ListenAddress::Unix(path), None) => serve_unix(routes, path, shutdown).await?,
+        (ListenAddress::Unix(_), Some(_)) => return Err("TLS isn't supported on unix sockets".to_string()),
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn serve_unix(
+    routes: BoxedFilter<(Response,)>,
+    path: &Path,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), String> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // A socket left behind by a previous run would stop us binding
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        fs::remove_file(path).map_err(|e| format!("can't remove stale socket {}: {e}", path.display()))?;
+    }
+    let listener = tokio::net::UnixListener::bind(path).map_err(|e| format!("can't listen on {}: {e}", path.display()))?;
+    let incoming = tokio_stream::wrappers::UnixListenerStream::new(listener);
+    warp::serve(routes).serve_incoming_with_graceful_shutdown(incoming, shutdown).await;
+    let _ = fs::remove_file(path);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(
+    _routes: BoxedFilter<(Response,)>,
+    _path: &Path,
+    _shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), String> {
+    Err("unix sockets aren't supported on this platform".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(user: &str, password: &str) -> String {
+        format!("Basic {}", BASE64.encode(format!("{user}:{password}")))
+    }
+
+    #[test]
+    fn test_parse_listen_addresses() {
+        assert_eq!("127.0.0.1:9090".parse(), Ok(ListenAddress::Tcp("127.0.0.1:9090".parse().unwrap())));
+        assert_eq!("[::]:9100".parse(), Ok(ListenAddress::Tcp("[::]:9100".parse().unwrap())));
+        assert_eq!(":9090".parse(), Ok(ListenAddress::Tcp("0.0.0.0:9090".parse().unwrap())));
+        assert_eq!("unix:/run/octopus.sock".parse(), Ok(ListenAddress::Unix(PathBuf::from("/run/octopus.sock"))));
+        assert!("localhost".parse::<ListenAddress>().is_err());
+        assert!("unix:".parse::<ListenAddress>().is_err());
+        assert_eq!(ListenAddress::Unix(PathBuf::from("/tmp/x.sock")).to_string(), "unix:/tmp/x.sock");
+    }
+
+    #[test]
+    fn test_load_web_config() {
+        let dir = std::env::temp_dir().join(format!("octopus-web-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("server.crt"), "").unwrap();
+        fs::write(dir.join("server.key"), "").unwrap();
+        fs::write(dir.join("token"), "s3cret\n").unwrap();
+        let path = dir.join("web.yml");
+
+        let hash = bcrypt::hash("hunter2", 4).unwrap();
+        fs::write(&path, format!(
+            "tls_server_config:\n  cert_file: server.crt\n  key_file: server.key\nbasic_auth_users:\n  prometheus: '{hash}'\nbearer_token_file: token\n",
+        )).unwrap();
+        let config = WebConfig::load(&path).unwrap();
+        assert_eq!(config.tls_server_config.unwrap().cert_file, dir.join("server.crt"));
+        assert_eq!(config.bearer_token.as_deref(), Some("s3cret"));
+
+        let invalid = [
+            "basic_auth_users:\n  prometheus: hunter2\n",
+            "tls_server_config:\n  cert_file: missing.crt\n  key_file: server.key\n",
+            "tls_server_config:\n  cert_file: server.crt\n  key_file: server.key\n  client_auth_type: RequireAndVerifyClientCert\n",
+            "tls_server_config:\n  cert_file: server.crt\n  key_file: server.key\n  min_version: TLS13\n",
+        ];
+        for text in invalid {
+            fs::write(&path, text).unwrap();
+            assert!(WebConfig::load(&path).is_err(), "{text:?} should be rejected");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_auth_checks_basic_and_bearer() {
+        assert!(Auth::new(&WebConfig::default()).allows(None));
+
+        let config = WebConfig {
+            basic_auth_users: BTreeMap::from([("prometheus".to_string(), bcrypt::hash("hunter2", 4).unwrap())]),
+            bearer_token: Some("s3cret".to_string()),
+            ..Default::default()
+        };
+        let auth = Auth::new(&config);
+        assert!(auth.allows(Some(&basic("prometheus", "hunter2"))));
+        // Served from the cache the second time
+        assert!(auth.allows(Some(&basic("prometheus", "hunter2"))));
+        assert!(!auth.allows(Some(&basic("prometheus", "hunter3"))));
+        assert!(!auth.allows(Some(&basic("grafana", "hunter2"))));
+        assert!(auth.allows(Some("Bearer s3cret")));
+        assert!(!auth.allows(Some("Bearer s3cre")));
+        assert!(!auth.allows(Some("Digest abc")));
+        assert!(!auth.allows(None));
+    }
+
+    fn health() -> Arc<Health> {
+        Arc::new(Health::new(Arc::new(crate::clock::SystemClock), std::time::Duration::from_secs(60)))
+    }
+
+    #[tokio::test]
+    async fn test_metrics_route_needs_auth() {
+        let metrics = Arc::new(Metrics::new(false, &[]).unwrap());
+        let auth = Arc::new(Auth::new(&WebConfig { bearer_token: Some("s3cret".to_string()), ..Default::default() }));
+        let routes = routes(metrics, auth, health());
+
+        let resp = warp::test::request().path("/metrics").reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.headers().contains_key("WWW-Authenticate"));
+
+        let resp = warp::test::request().path("/metrics").header("Authorization", "Bearer s3cret").reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = warp::test::request().path("/health").reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), "OK");
+    }
+
+    #[tokio::test]
+    async fn test_health_route_reports_failures() {
+        use crate::error::{Error, Failure};
+
+        let health = health();
+        let routes = routes(Arc::new(Metrics::new(false, &[]).unwrap()), Arc::new(Auth::new(&WebConfig::default())), Arc::clone(&health));
+        let failure = Failure { source: Some("A-1234ABCD".to_string()), window: None, error: Error::octopus("timed out") };
+        health.record(true, &[failure]);
+
+        let resp = warp::test::request().path("/health").reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), "DEGRADED\naccount A-1234ABCD: Octopus API request failed: timed out\n");
+    }
+}
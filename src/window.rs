@@ -0,0 +1,420 @@
+use std::{fmt, str::FromStr};
+
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, LocalResult, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+/// Windows reported when none are given on the command line.
+pub const DEFAULT_WINDOWS: &[&str] = &["2d", "1w", "2w", "4w", "mtd", "2m", "3m", "6m", "1y"];
+
+/// Timezone calendar windows are aligned to unless configured otherwise.
+pub const DEFAULT_TIMEZONE: &str = "Europe/London";
+
+/// Longest window accepted, well past any meter's history.
+const MAX_YEARS: i64 = 100;
+
+/// How far back a reporting window reaches from `now`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Span {
+    Hours(i64),
+    Days(i64),
+    Weeks(i64),
+    /// Since the first of the month, `n` months back
+    Months(i32),
+    /// Since the first of the current month, `n` years back
+    Years(i32),
+    /// Since the most recent occurrence of this day of the month
+    BillingCycle(u32),
+    Today,
+    Yesterday,
+    /// Since Monday of the current week
+    ThisWeek,
+    ThisMonth,
+    LastMonth,
+    ThisYear,
+    LastYear,
+}
+
+/// A named reporting window, parsed from strings such as `36h`, `90d`,
+/// `ytd`, `last_month` or `cycle:14`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Window {
+    label: String,
+    span: Span,
+}
+
+impl Window {
+    /// Label used for the `window` metric label and in logs.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Start and end of the window as of `now`, with calendar boundaries
+    /// taken at local midnight in `tz`.
+    pub fn range(&self, now: DateTime<Utc>, tz: Tz) -> (DateTime<Utc>, DateTime<Utc>) {
+        let today = now.with_timezone(&tz).date_naive();
+
+        match self.span {
+            Span::Hours(n) => (back(now, ChronoDuration::try_hours(n)), now),
+            Span::Days(n) => (back(now, ChronoDuration::try_days(n)), now),
+            Span::Weeks(n) => (back(now, ChronoDuration::try_weeks(n)), now),
+            Span::Months(n) => get_month_range(now, tz, n),
+            Span::Years(n) => get_year_range(now, tz, n),
+            Span::BillingCycle(day) => {
+                let months_back = if today.day() >= day { 0 } else { 1 };
+                let first = month_start(today, months_back);
+                // Cycle days are at most 28, so every month has one
+                (local_midnight(tz, first.with_day(day).unwrap_or(first)), now)
+            }
+            Span::Today => (local_midnight(tz, today), now),
+            Span::Yesterday => {
+                let yesterday = today.pred_opt().unwrap_or(today);
+                (local_midnight(tz, yesterday), local_midnight(tz, today))
+            }
+            Span::ThisWeek => {
+                let monday = today - ChronoDuration::days(today.weekday().num_days_from_monday() as i64);
+                (local_midnight(tz, monday), now)
+            }
+            Span::ThisMonth => get_month_range(now, tz, 0),
+            Span::LastMonth => (
+                local_midnight(tz, month_start(today, 1)),
+                local_midnight(tz, month_start(today, 0)),
+            ),
+            Span::ThisYear => get_month_range(now, tz, today.month0() as i32),
+            Span::LastYear => {
+                let this_year = month_start(today, today.month0() as i32);
+                (
+                    local_midnight(tz, this_year.with_year(this_year.year() - 1).unwrap_or(this_year)),
+                    local_midnight(tz, this_year),
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.label)
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let label = s.trim().to_ascii_lowercase();
+
+        let span = match label.as_str() {
+            "today" => Span::Today,
+            "yesterday" => Span::Yesterday,
+            "this_week" => Span::ThisWeek,
+            "mtd" | "this_month" => Span::ThisMonth,
+            "last_month" => Span::LastMonth,
+            "ytd" | "this_year" => Span::ThisYear,
+            "last_year" => Span::LastYear,
+            _ => {
+                if let Some(day) = label.strip_prefix("cycle:") {
+                    let day: u32 = day
+                        .parse()
+                        .map_err(|_| format!("invalid billing cycle day in window '{s}'"))?;
+                    // Every month has a 28th, so later days would drift
+                    if !(1..=28).contains(&day) {
+                        return Err(format!("billing cycle day must be between 1 and 28 in window '{s}'"));
+                    }
+                    Span::BillingCycle(day)
+                } else {
+                    let split = label.len().saturating_sub(1);
+                    let (count, unit) = label.split_at(split);
+                    let count: i64 = count.parse().map_err(|_| {
+                        format!("invalid window '{s}', expected e.g. 36h, 90d, 2w, 3m, 1y, mtd, ytd, today, last_month or cycle:14")
+                    })?;
+                    if count <= 0 {
+                        return Err(format!("window '{s}' must be longer than zero"));
+                    }
+                    let (span, per_year) = match unit {
+                        "h" => (Span::Hours(count), 366 * 24),
+                        "d" => (Span::Days(count), 366),
+                        "w" => (Span::Weeks(count), 53),
+                        "m" => (Span::Months(i32::try_from(count).unwrap_or(i32::MAX)), 12),
+                        "y" => (Span::Years(i32::try_from(count).unwrap_or(i32::MAX)), 1),
+                        _ => return Err(format!("unknown unit '{unit}' in window '{s}', expected h, d, w, m or y")),
+                    };
+                    if count > MAX_YEARS * per_year {
+                        return Err(format!("window '{s}' is longer than {MAX_YEARS} years"));
+                    }
+                    span
+                }
+            }
+        };
+
+        Ok(Window { label, span })
+    }
+}
+
+pub fn default_windows() -> Vec<Window> {
+    DEFAULT_WINDOWS.iter().map(|w| w.parse().expect("default windows are valid")).collect()
+}
+
+// Using years directly
+pub fn get_year_range(now: DateTime<Utc>, tz: Tz, years_back: i32) -> (DateTime<Utc>, DateTime<Utc>) {
+    get_month_range(now, tz, years_back.checked_mul(12).unwrap_or(i32::MAX))
+}
+
+pub fn get_month_range(now: DateTime<Utc>, tz: Tz, months_back: i32) -> (DateTime<Utc>, DateTime<Utc>) {
+    let today = now.with_timezone(&tz).date_naive();
+    (local_midnight(tz, month_start(today, months_back)), now)
+}
+
+/// The whole local calendar day containing `now`, which is 23 or 25 hours
+/// long when the clocks change.
+pub fn get_day_range(now: DateTime<Utc>, tz: Tz) -> (DateTime<Utc>, DateTime<Utc>) {
+    let today = now.with_timezone(&tz).date_naive();
+    (local_midnight(tz, today), local_midnight(tz, today.succ_opt().unwrap_or(today)))
+}
+
+/// First day of the month `months_back` months before `date`.
+fn month_start(date: NaiveDate, months_back: i32) -> NaiveDate {
+    // Count months from year zero so going back wraps the year for us
+    let Some(months) = (date.year() * 12 + date.month0() as i32).checked_sub(months_back) else {
+        return NaiveDate::MIN;
+    };
+    NaiveDate::from_ymd_opt(months.div_euclid(12), months.rem_euclid(12) as u32 + 1, 1).unwrap_or(NaiveDate::MIN)
+}
+
+/// `now` less `span`, or the earliest time there is when that's out of range.
+fn back(now: DateTime<Utc>, span: Option<ChronoDuration>) -> DateTime<Utc> {
+    span.and_then(|span| now.checked_sub_signed(span)).unwrap_or(DateTime::<Utc>::MIN_UTC)
+}
+
+/// The instant a calendar day starts in `tz`.
+///
+/// Midnight is ambiguous or skipped in zones that change their clocks at
+/// midnight, so take the earliest instant, or the first valid time after a gap.
+pub fn local_midnight(tz: Tz, date: NaiveDate) -> DateTime<Utc> {
+    let midnight = date.and_time(NaiveTime::MIN);
+    for minutes in (0..=180).step_by(15) {
+        match tz.from_local_datetime(&(midnight + ChronoDuration::minutes(minutes))) {
+            LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => return t.with_timezone(&Utc),
+            LocalResult::None => continue,
+        }
+    }
+    Utc.from_utc_datetime(&midnight)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Timelike;
+    use chrono_tz::Europe::London;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn range(window: &str, now: &str) -> (DateTime<Utc>, DateTime<Utc>) {
+        window.parse::<Window>().unwrap().range(at(now), London)
+    }
+
+    #[test]
+    fn test_get_year_range() {
+        let now = at("2025-01-17T10:11:12Z");
+        let years_back = 2;
+        let (start, end) = get_year_range(now, chrono_tz::UTC, years_back);
+
+        // Start should be same month, day=1, hour/min/sec=0, nanosec=0, year shifted back
+        assert_eq!(start.year(), now.year() - years_back);
+        assert_eq!(start.month(), now.month());
+        assert_eq!(start.day(), 1);
+        assert_eq!(start.hour(), 0);
+        assert_eq!(start.minute(), 0);
+        assert_eq!(start.second(), 0);
+        assert_eq!(start.nanosecond(), 0);
+
+        assert_eq!(end, now);
+    }
+
+    #[test]
+    fn test_get_year_range_leap_day() {
+        let (start, _) = get_year_range(at("2024-02-29T12:00:00Z"), London, 1);
+        assert_eq!(start, at("2023-02-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn test_get_month_range_current() {
+        let now = at("2025-01-17T10:11:12Z");
+        let (start, end) = get_month_range(now, chrono_tz::UTC, 0);
+
+        // Start should be first day of current month at midnight
+        assert_eq!(start.year(), now.year());
+        assert_eq!(start.month(), now.month());
+        assert_eq!(start.day(), 1);
+        assert_eq!(start.hour(), 0);
+        assert_eq!(start.minute(), 0);
+        assert_eq!(start.second(), 0);
+        assert_eq!(start.nanosecond(), 0);
+
+        assert_eq!(end, now);
+    }
+
+    #[test]
+    fn test_get_month_range_previous_month() {
+        let now = at("2025-01-17T10:11:12Z");
+        let (start, end) = get_month_range(now, London, 1);
+
+        // Going back from January wraps into December of the previous year
+        assert_eq!(start, at("2024-12-01T00:00:00Z"));
+        assert_eq!(end, now);
+
+        let (start, _) = get_month_range(now, London, 13);
+        assert_eq!(start, at("2023-12-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn test_month_range_uses_local_midnight_in_bst() {
+        // 00:30 on 1 April BST is still 31 March in UTC
+        let (start, _) = range("this_month", "2025-03-31T23:30:00Z");
+        assert_eq!(start, at("2025-03-31T23:00:00Z"));
+
+        let (start, _) = get_month_range(at("2025-08-17T10:00:00Z"), London, 0);
+        assert_eq!(start, at("2025-07-31T23:00:00Z"));
+    }
+
+    #[test]
+    fn test_parse_rolling_windows() {
+        let now = "2025-01-17T10:00:00Z";
+        let cases = [
+            ("36h", "2025-01-15T22:00:00Z"),
+            ("90d", "2024-10-19T10:00:00Z"),
+            ("2w", "2025-01-03T10:00:00Z"),
+            ("3m", "2024-09-30T23:00:00Z"),
+            ("1y", "2024-01-01T00:00:00Z"),
+        ];
+        for (input, expected) in cases {
+            let window: Window = input.parse().unwrap();
+            assert_eq!(window.label(), input);
+            assert_eq!(range(input, now), (at(expected), at(now)), "window {input}");
+        }
+    }
+
+    #[test]
+    fn test_parse_to_date_windows() {
+        let now = "2025-01-17T10:00:00Z";
+        let ytd: Window = "YTD".parse().unwrap();
+
+        assert_eq!(range("mtd", now).0, at("2025-01-01T00:00:00Z"));
+        assert_eq!(ytd.label(), "ytd");
+        assert_eq!(range("ytd", now).0, at("2025-01-01T00:00:00Z"));
+        // In summer the year still starts at GMT midnight
+        assert_eq!(range("this_year", "2025-08-17T10:00:00Z").0, at("2025-01-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn test_billing_cycle_window() {
+        // On or after the 14th the cycle started this month, at BST midnight
+        assert_eq!(range("cycle:14", "2025-08-13T23:00:00Z").0, at("2025-08-13T23:00:00Z"));
+        assert_eq!(range("cycle:14", "2025-08-20T09:30:00Z").0, at("2025-08-13T23:00:00Z"));
+        // Before the 14th it started last month, across the year boundary too
+        assert_eq!(range("cycle:14", "2025-01-03T09:30:00Z").0, at("2024-12-14T00:00:00Z"));
+    }
+
+    #[test]
+    fn test_calendar_windows() {
+        // Wednesday 20 August 2025, BST
+        let now = "2025-08-20T09:30:00Z";
+        assert_eq!(range("today", now), (at("2025-08-19T23:00:00Z"), at(now)));
+        assert_eq!(range("yesterday", now), (at("2025-08-18T23:00:00Z"), at("2025-08-19T23:00:00Z")));
+        assert_eq!(range("this_week", now), (at("2025-08-17T23:00:00Z"), at(now)));
+        assert_eq!(range("last_month", now), (at("2025-06-30T23:00:00Z"), at("2025-07-31T23:00:00Z")));
+        assert_eq!(range("last_year", now), (at("2024-01-01T00:00:00Z"), at("2025-01-01T00:00:00Z")));
+
+        // Early on 1 January last month is December of the previous year
+        assert_eq!(range("last_month", "2025-01-01T00:30:00Z"), (at("2024-12-01T00:00:00Z"), at("2025-01-01T00:00:00Z")));
+    }
+
+    #[test]
+    fn test_clocks_go_forward_in_march() {
+        // Clocks go forward at 01:00 GMT on Sunday 30 March 2025
+        let now = "2025-03-31T10:00:00Z";
+        let (start, end) = range("yesterday", now);
+        assert_eq!((start, end), (at("2025-03-30T00:00:00Z"), at("2025-03-30T23:00:00Z")));
+        assert_eq!(end - start, ChronoDuration::hours(23));
+
+        assert_eq!(range("today", now).0, at("2025-03-30T23:00:00Z"));
+        // The week started on Monday 24 March, still in GMT
+        assert_eq!(range("this_week", now).0, at("2025-03-30T23:00:00Z"));
+        assert_eq!(range("this_week", "2025-03-30T12:00:00Z").0, at("2025-03-24T00:00:00Z"));
+        // Last month ended at GMT midnight
+        assert_eq!(range("last_month", "2025-04-02T12:00:00Z"), (at("2025-03-01T00:00:00Z"), at("2025-03-31T23:00:00Z")));
+    }
+
+    #[test]
+    fn test_day_range_covers_whole_local_day() {
+        assert_eq!(
+            get_day_range(at("2025-03-30T12:00:00Z"), London),
+            (at("2025-03-30T00:00:00Z"), at("2025-03-30T23:00:00Z")),
+        );
+        assert_eq!(
+            get_day_range(at("2025-07-01T23:30:00Z"), London),
+            (at("2025-07-01T23:00:00Z"), at("2025-07-02T23:00:00Z")),
+        );
+    }
+
+    #[test]
+    fn test_clocks_go_back_in_october() {
+        // Clocks go back at 01:00 GMT on Sunday 26 October 2025
+        let now = "2025-10-27T10:00:00Z";
+        let (start, end) = range("yesterday", now);
+        assert_eq!((start, end), (at("2025-10-25T23:00:00Z"), at("2025-10-27T00:00:00Z")));
+        assert_eq!(end - start, ChronoDuration::hours(25));
+
+        assert_eq!(range("today", now).0, at("2025-10-27T00:00:00Z"));
+        assert_eq!(range("this_month", now).0, at("2025-09-30T23:00:00Z"));
+        assert_eq!(range("last_month", "2025-11-05T12:00:00Z"), (at("2025-09-30T23:00:00Z"), at("2025-11-01T00:00:00Z")));
+    }
+
+    #[test]
+    fn test_local_midnight_skipped_by_dst() {
+        // Santiago moves its clocks forward at midnight, so 00:00 never happens
+        let start = local_midnight(chrono_tz::America::Santiago, NaiveDate::from_ymd_opt(2025, 9, 7).unwrap());
+        assert_eq!(start, at("2025-09-07T04:00:00Z"));
+    }
+
+    #[test]
+    fn test_parse_invalid_windows() {
+        for input in ["", "h", "0d", "-2d", "3x", "cycle:0", "cycle:31", "cycle:abc", "week"] {
+            assert!(input.parse::<Window>().is_err(), "{input} should not parse");
+        }
+
+        // Counts that don't fit, or reach back further than any history
+        for input in ["999999999999h", "100000000d", "99999w", "3000000000m", "999999999y", "101y"] {
+            assert!(input.parse::<Window>().is_err(), "{input} should not parse");
+        }
+        assert!("100y".parse::<Window>().is_ok());
+    }
+
+    #[test]
+    fn test_out_of_range_spans_do_not_panic() {
+        let now = at("2025-08-01T12:00:00Z");
+        for span in [Span::Hours(i64::MAX), Span::Days(i64::MAX), Span::Weeks(i64::MAX), Span::Months(i32::MAX), Span::Years(i32::MAX)] {
+            let window = Window { label: "huge".to_string(), span };
+            let (start, end) = window.range(now, chrono_tz::Europe::London);
+            assert!(start < end, "{span:?}");
+        }
+    }
+
+    #[test]
+    fn test_default_windows_parse() {
+        let windows = default_windows();
+        let labels: Vec<_> = windows.iter().map(|w| w.label()).collect();
+        assert_eq!(labels, DEFAULT_WINDOWS);
+    }
+}